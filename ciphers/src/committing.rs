@@ -0,0 +1,134 @@
+//! Key-committing authenticated encryption
+//!
+//! ChaCha20Poly1305 is not key-committing: an attacker who can choose keys may construct
+//! a ciphertext that decrypts successfully under several of them, which enables
+//! partitioning oracle attacks when the key is attacker-influenced, e.g. derived from a
+//! password. [committing_encrypt] appends a commitment to the key, a keyed Blake2b hash
+//! of a fixed domain tag, which [committing_decrypt] checks before decrypting.
+//!
+//! The layout of a committing ciphertext is
+//!
+//! ```text
+//! nonce || ciphertext || tag || commitment
+//! ```
+//!
+//! where `nonce || ciphertext || tag` is the output of [xaead::encrypt].
+
+use anyhow::{ensure, Result};
+use rosenpass_to::To;
+
+use crate::subtle::blake2b;
+use crate::xaead;
+
+/// Size of the key commitment
+pub const COMMITMENT_LEN: usize = blake2b::OUT_MAX;
+
+/// Bytes added to the plaintext by [committing_encrypt]
+pub const OVERHEAD: usize = xaead::OVERHEAD + COMMITMENT_LEN;
+
+/// Domain separator for the key commitment
+const COMMITMENT_DOMAIN: &[u8] = b"rosenpass.eu committing aead key commitment";
+
+/// The commitment to `key`
+fn commitment(key: &[u8]) -> Result<[u8; COMMITMENT_LEN]> {
+    ensure!(key.len() == xaead::KEY_LEN, "Invalid key length");
+    let mut commitment = [0u8; COMMITMENT_LEN];
+    blake2b::hash(key, COMMITMENT_DOMAIN).to(&mut commitment)?;
+    Ok(commitment)
+}
+
+/// Encrypt like [xaead::encrypt] and append a commitment to the key
+///
+/// `ciphertext` must be exactly [OVERHEAD] bytes longer than `plaintext`.
+pub fn committing_encrypt(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> Result<()> {
+    ensure!(
+        ciphertext.len() == plaintext.len() + OVERHEAD,
+        "Ciphertext buffer size does not match the plaintext size"
+    );
+    let (ct, commit) = ciphertext.split_at_mut(ciphertext.len() - COMMITMENT_LEN);
+    xaead::encrypt(ct, key, nonce, ad, plaintext)?;
+    commit.copy_from_slice(&commitment(key)?);
+    Ok(())
+}
+
+/// Check the key commitment of a ciphertext created by [committing_encrypt] and decrypt it
+///
+/// Ciphertexts committing to a different key are rejected before the AEAD is run, even if
+/// their tag would be valid under `key`. `plaintext` must be exactly [OVERHEAD] bytes
+/// shorter than `ciphertext`.
+pub fn committing_decrypt(
+    plaintext: &mut [u8],
+    key: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext too short");
+    ensure!(
+        plaintext.len() == ciphertext.len() - OVERHEAD,
+        "Plaintext buffer size does not match the ciphertext size"
+    );
+    let (ct, commit) = ciphertext.split_at(ciphertext.len() - COMMITMENT_LEN);
+    ensure!(
+        rosenpass_constant_time::memcmp(commit, &commitment(key)?),
+        "Ciphertext does not commit to this key"
+    );
+    xaead::decrypt(plaintext, key, ad, ct)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY_A: [u8; xaead::KEY_LEN] = [0xaa; xaead::KEY_LEN];
+    const KEY_B: [u8; xaead::KEY_LEN] = [0xbb; xaead::KEY_LEN];
+    const NONCE: [u8; xaead::NONCE_LEN] = [0x42; xaead::NONCE_LEN];
+
+    #[test]
+    fn committing_roundtrip() {
+        for pt in [&b""[..], b"Hello, World!"] {
+            let mut ct = vec![0u8; pt.len() + OVERHEAD];
+            committing_encrypt(&mut ct, &KEY_A, &NONCE, b"ad", pt).unwrap();
+
+            let mut out = vec![0u8; pt.len()];
+            committing_decrypt(&mut out, &KEY_A, b"ad", &ct).unwrap();
+            assert_eq!(out, pt);
+
+            assert!(committing_decrypt(&mut out, &KEY_A, b"other", &ct).is_err());
+            assert!(committing_decrypt(&mut out, &KEY_B, b"ad", &ct).is_err());
+        }
+    }
+
+    #[test]
+    fn commitment_rejects_other_key() {
+        let pt = b"Hello, World!";
+
+        // A ciphertext with a valid tag under KEY_B, but committing to KEY_A
+        let mut ct = vec![0u8; pt.len() + OVERHEAD];
+        committing_encrypt(&mut ct, &KEY_B, &NONCE, b"", pt).unwrap();
+        let commit_pos = ct.len() - COMMITMENT_LEN;
+        ct[commit_pos..].copy_from_slice(&commitment(&KEY_A).unwrap());
+
+        let mut out = vec![0u8; pt.len()];
+        let err = committing_decrypt(&mut out, &KEY_B, b"", &ct).unwrap_err();
+        assert!(err.to_string().contains("commit"));
+        assert_eq!(out, [0u8; 13]);
+
+        // The AEAD part alone is still valid under KEY_B
+        xaead::decrypt(&mut out, &KEY_B, b"", &ct[..commit_pos]).unwrap();
+        assert_eq!(&out, pt);
+    }
+
+    #[test]
+    fn committing_rejects_short_ciphertext() {
+        let mut out = [];
+        for len in [0, OVERHEAD - 1] {
+            assert!(committing_decrypt(&mut out, &KEY_A, b"", &vec![0u8; len]).is_err());
+        }
+    }
+}
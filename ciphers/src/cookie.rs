@@ -0,0 +1,166 @@
+//! Cheap pre-filtering of packets before AEAD verification
+//!
+//! Verifying an AEAD tag requires processing the entire ciphertext. A [CookiePrefilter]
+//! prepends a short keyed Blake2b MAC, the cookie, computed over the packet length and
+//! at most [COVERED_LEN] bytes of its start, so it can be checked at a small, constant cost.
+//! Packets without a valid cookie are dropped before the AEAD is run at all; this is
+//! similar in spirit to the MACs WireGuard adds to its handshake messages.
+//!
+//! The cookie is *not* a replacement for the AEAD: it does not cover the entire packet.
+//! It only ensures that the sender knew the cookie key, which makes flooding a gateway
+//! with garbage packets cheap to defend against.
+//!
+//! The packet layout is
+//!
+//! ```text
+//! cookie (COOKIE_LEN bytes) || payload
+//! ```
+
+use anyhow::{ensure, Result};
+use blake2::digest::crypto_common::generic_array::GenericArray;
+use blake2::digest::crypto_common::typenum::U16;
+use blake2::digest::{FixedOutput, Mac};
+use blake2::Blake2bMac;
+use rosenpass_secret_memory::Secret;
+use rosenpass_util::typenum2const;
+
+type Impl = Blake2bMac<U16>;
+
+/// Size of the cookie
+pub const COOKIE_LEN: usize = typenum2const! { U16 };
+
+/// Size of the cookie key
+pub const KEY_LEN: usize = 32;
+
+/// Maximum number of payload bytes covered by the cookie
+pub const COVERED_LEN: usize = 64;
+
+/// Adds and checks cookies preceding packets
+#[derive(Debug)]
+pub struct CookiePrefilter {
+    key: Secret<KEY_LEN>,
+}
+
+impl CookiePrefilter {
+    pub fn new(key: Secret<KEY_LEN>) -> Self {
+        Self { key }
+    }
+
+    /// Compute the cookie for `payload`
+    pub fn cookie(&self, payload: &[u8]) -> [u8; COOKIE_LEN] {
+        // Keys of up to 64 bytes are accepted, so this can not fail
+        let mut mac = Impl::new_from_slice(self.key.secret()).unwrap();
+        mac.update(&(payload.len() as u64).to_le_bytes());
+        mac.update(&payload[..payload.len().min(COVERED_LEN)]);
+
+        let mut cookie = [0u8; COOKIE_LEN];
+        mac.finalize_into(GenericArray::from_mut_slice(&mut cookie));
+        cookie
+    }
+
+    /// Write the cookie followed by `payload` to `packet`
+    ///
+    /// `packet` must be exactly [COOKIE_LEN] bytes longer than `payload`.
+    pub fn seal(&self, packet: &mut [u8], payload: &[u8]) -> Result<()> {
+        ensure!(
+            packet.len() == COOKIE_LEN + payload.len(),
+            "Packet buffer size does not match the payload size"
+        );
+        let (cookie, rest) = packet.split_at_mut(COOKIE_LEN);
+        cookie.copy_from_slice(&self.cookie(payload));
+        rest.copy_from_slice(payload);
+        Ok(())
+    }
+
+    /// Check the cookie of `packet`, returning the payload
+    pub fn check<'a>(&self, packet: &'a [u8]) -> Result<&'a [u8]> {
+        ensure!(packet.len() >= COOKIE_LEN, "Packet too short for a cookie");
+        let (cookie, payload) = packet.split_at(COOKIE_LEN);
+        ensure!(
+            rosenpass_constant_time::memcmp(cookie, &self.cookie(payload)),
+            "Invalid cookie"
+        );
+        Ok(payload)
+    }
+
+    /// Run `f`, e.g. AEAD decryption, on the payload of `packet` only if its cookie is valid
+    pub fn filter<R>(&self, packet: &[u8], f: impl FnOnce(&[u8]) -> Result<R>) -> Result<R> {
+        f(self.check(packet)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::xaead;
+
+    const AEAD_KEY: [u8; xaead::KEY_LEN] = [0x42; xaead::KEY_LEN];
+
+    fn packet(prefilter: &CookiePrefilter, pt: &[u8]) -> Vec<u8> {
+        let ct = xaead::encrypt_random_nonce_vec(&AEAD_KEY, b"", pt).unwrap();
+        let mut packet = vec![0u8; COOKIE_LEN + ct.len()];
+        prefilter.seal(&mut packet, &ct).unwrap();
+        packet
+    }
+
+    /// Decrypt `packet` through the prefilter, counting AEAD invocations
+    fn open(prefilter: &CookiePrefilter, packet: &[u8], aead_runs: &mut usize) -> Result<Vec<u8>> {
+        prefilter.filter(packet, |ct| {
+            *aead_runs += 1;
+            let mut pt = vec![0u8; xaead::plaintext_len(ct.len())?];
+            xaead::decrypt(&mut pt, &AEAD_KEY, b"", ct)?;
+            Ok(pt)
+        })
+    }
+
+    #[test]
+    fn cookie_roundtrip() {
+        let prefilter = CookiePrefilter::new(Secret::random());
+        let mut aead_runs = 0;
+        for pt in [&b""[..], b"Hello, World!", &[0x55; 1000]] {
+            let packet = packet(&prefilter, pt);
+            assert_eq!(open(&prefilter, &packet, &mut aead_runs).unwrap(), pt);
+        }
+        assert_eq!(aead_runs, 3);
+    }
+
+    #[test]
+    fn forged_packets_rejected_before_aead() {
+        let prefilter = CookiePrefilter::new(Secret::random());
+        let packet = packet(&prefilter, b"Hello, World!");
+        let mut aead_runs = 0;
+
+        // Flipped cookie bit, cookie under a different key, garbage, and truncated packets
+        let mut forged = packet.clone();
+        forged[0] ^= 1;
+        assert!(open(&prefilter, &forged, &mut aead_runs).is_err());
+
+        let other = CookiePrefilter::new(Secret::random());
+        assert!(open(&other, &packet, &mut aead_runs).is_err());
+
+        assert!(open(&prefilter, &[0u8; 100], &mut aead_runs).is_err());
+        assert!(open(&prefilter, &packet[..COOKIE_LEN - 1], &mut aead_runs).is_err());
+        assert!(open(&prefilter, &packet[..packet.len() - 1], &mut aead_runs).is_err());
+
+        // Modifying covered bytes invalidates the cookie
+        let mut forged = packet.clone();
+        forged[COOKIE_LEN] ^= 1;
+        assert!(open(&prefilter, &forged, &mut aead_runs).is_err());
+
+        assert_eq!(aead_runs, 0);
+    }
+
+    #[test]
+    fn uncovered_bytes_left_to_aead() {
+        let prefilter = CookiePrefilter::new(Secret::random());
+        let mut packet = packet(&prefilter, &[0x55; 1000]);
+        let mut aead_runs = 0;
+
+        // The cookie only covers a prefix, so the AEAD has to catch this
+        *packet.last_mut().unwrap() ^= 1;
+        assert!(prefilter.check(&packet).is_ok());
+        assert!(open(&prefilter, &packet, &mut aead_runs).is_err());
+        assert_eq!(aead_runs, 1);
+    }
+}
@@ -7,19 +7,41 @@ const_assert!(KEY_LEN == aead::KEY_LEN);
 const_assert!(KEY_LEN == xaead::KEY_LEN);
 const_assert!(KEY_LEN == hash_domain::KEY_LEN);
 
+// The ChaCha20 based AEADs must agree on everything but the nonce
+const_assert!(aead::KEY_LEN == xaead::KEY_LEN);
+const_assert!(aead::TAG_LEN == xaead::TAG_LEN);
+const_assert!(aead::NONCE_LEN < xaead::NONCE_LEN);
+
 /// Authenticated encryption with associated data
 pub mod aead {
-    pub use crate::subtle::chacha20poly1305_ietf::{decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN};
+    pub use crate::subtle::chacha20poly1305_ietf::{
+        ciphertext_len, decrypt, decrypt_bind_nonce, decrypt_into, decrypt_no_ad,
+        decrypt_truncated, decrypt_with_key, encrypt, encrypt_bind_nonce, encrypt_no_ad,
+        encrypt_truncated, encrypt_with_key, plaintext_len, verify, verify_and_decrypt_to_secret,
+        KEY_LEN, MIN_TRUNCATED_TAG_LEN, NONCE_LEN, OVERHEAD, TAG_LEN,
+    };
 }
 
 /// Authenticated encryption with associated data with a constant nonce
 pub mod xaead {
     pub use crate::subtle::xchacha20poly1305_ietf::{
-        decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN,
+        ciphertext_len, decrypt, decrypt_into, decrypt_no_ad, decrypt_with_key, encrypt,
+        encrypt_no_ad, encrypt_random_nonce, encrypt_random_nonce_vec, encrypt_to_writer,
+        encrypt_with_key, plaintext_len, verify, verify_and_decrypt_to_secret, KEY_LEN, NONCE_LEN,
+        OVERHEAD, TAG_LEN,
     };
 }
 
+pub mod committing;
+pub mod cookie;
+pub mod drbg;
 pub mod hash_domain;
+pub mod message;
+pub mod replay;
+pub mod seal;
+mod self_test;
+
+pub use crate::self_test::self_test;
 
 pub mod kem {
     pub use rosenpass_oqs::ClassicMceliece460896 as StaticKem;
@@ -0,0 +1,282 @@
+//! Self-contained encrypted messages
+//!
+//! [encrypt_message] encrypts under a fresh random nonce and returns
+//! `nonce || ciphertext || tag`, so the caller never has to manage nonces; the message is
+//! decrypted with [decrypt_message]. The layout is that of [xaead::encrypt], so messages
+//! can also be decrypted with [xaead::decrypt].
+//!
+//! Both the encrypted message and the decrypted plaintext are returned in secret memory;
+//! [encrypt_message_in] and [decrypt_message_in] allocate the output with a caller
+//! provided allocator instead. [encrypt_message_to] writes the message to a
+//! [std::io::Write] without buffering it as a whole.
+//!
+//! When one key is used by several applications or protocols, [encrypt_message_with_context]
+//! additionally binds the message to a context string, so it can only be decrypted by
+//! [decrypt_message_with_context] with the same context. The associated data passed to
+//! the AEAD is then `context_len: u32 (little endian) || context || ad`; the context
+//! itself is not part of the message.
+//!
+//! Where a field of the associated data decides how a message is routed,
+//! [decrypt_message_with_header] checks that field against an expected value before
+//! running the AEAD, so misrouted messages are rejected without touching the ciphertext.
+//! This only changes when a message is rejected; it is accepted exactly when
+//! [decrypt_message] accepts it.
+
+use std::io::Write;
+use std::ops::Range;
+
+use allocator_api2::alloc::Allocator;
+use allocator_api2::vec::Vec;
+use anyhow::{ensure, Context, Result};
+use rosenpass_secret_memory::alloc::{SecretAllocator, SecretVec};
+use rosenpass_secret_memory::{Public, Secret};
+
+use crate::xaead;
+
+/// Bytes added to the plaintext by [encrypt_message]
+pub const OVERHEAD: usize = xaead::OVERHEAD;
+
+/// Allocate a zeroed buffer
+fn zeroed<A: Allocator>(len: usize, alloc: A) -> Result<Vec<u8, A>> {
+    let mut buf = Vec::new_in(alloc);
+    buf.try_reserve_exact(len)
+        .ok()
+        .context("Could not allocate memory for the message")?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// Encrypt `plaintext` under a random nonce, returning `nonce || ciphertext || tag`
+pub fn encrypt_message(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    plaintext: &[u8],
+) -> Result<SecretVec<u8>> {
+    encrypt_message_in(key, ad, plaintext, SecretAllocator::new())
+}
+
+/// Like [encrypt_message], writing the message to `writer`
+///
+/// The message is encrypted in small chunks, without a buffer the size of the whole
+/// message; see [xaead::encrypt_to_writer].
+pub fn encrypt_message_to<W: Write>(
+    writer: W,
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    plaintext: &[u8],
+) -> Result<()> {
+    let nonce = Public::<{ xaead::NONCE_LEN }>::random();
+    xaead::encrypt_to_writer(writer, key.secret(), &nonce.value, ad, plaintext)
+}
+
+/// Like [encrypt_message], allocating the message with `alloc`
+pub fn encrypt_message_in<A: Allocator>(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    plaintext: &[u8],
+    alloc: A,
+) -> Result<Vec<u8, A>> {
+    let mut message = zeroed(xaead::ciphertext_len(plaintext.len()), alloc)?;
+    xaead::encrypt_random_nonce(&mut message, key.secret(), ad, plaintext)?;
+    Ok(message)
+}
+
+/// The associated data binding `ad` to `context`
+fn context_ad(context: &[u8], ad: &[u8]) -> Result<std::vec::Vec<u8>> {
+    let context_len = u32::try_from(context.len()).context("Context too long")?;
+    let mut bound = std::vec::Vec::with_capacity(4 + context.len() + ad.len());
+    bound.extend_from_slice(&context_len.to_le_bytes());
+    bound.extend_from_slice(context);
+    bound.extend_from_slice(ad);
+    Ok(bound)
+}
+
+/// Like [encrypt_message], binding the message to an application `context`
+///
+/// Messages encrypted under one context fail to decrypt under any other, even with the
+/// same key and associated data. Decrypt with [decrypt_message_with_context].
+pub fn encrypt_message_with_context(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    context: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> Result<SecretVec<u8>> {
+    encrypt_message(key, &context_ad(context, ad)?, plaintext)
+}
+
+/// Verify and decrypt a message created by [encrypt_message_with_context]
+pub fn decrypt_message_with_context(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    context: &[u8],
+    ad: &[u8],
+    message: &[u8],
+) -> Result<SecretVec<u8>> {
+    decrypt_message(key, &context_ad(context, ad)?, message)
+}
+
+/// Verify and decrypt a message created by [encrypt_message]
+pub fn decrypt_message(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    message: &[u8],
+) -> Result<SecretVec<u8>> {
+    decrypt_message_in(key, ad, message, SecretAllocator::new())
+}
+
+/// Like [decrypt_message], first checking the header `ad[header]` against `expected`
+///
+/// The header is public and covered by the tag like the rest of `ad`; a mismatch is
+/// reported without decrypting, otherwise the message is fully verified as usual.
+pub fn decrypt_message_with_header(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    header: Range<usize>,
+    expected: &[u8],
+    message: &[u8],
+) -> Result<SecretVec<u8>> {
+    let field = ad
+        .get(header)
+        .context("Header lies outside the associated data")?;
+    ensure!(field == expected, "Unexpected message header");
+    decrypt_message(key, ad, message)
+}
+
+/// Like [decrypt_message], allocating the plaintext with `alloc`
+///
+/// Unless `alloc` provides secret memory, the plaintext is not protected any further.
+pub fn decrypt_message_in<A: Allocator>(
+    key: &Secret<{ xaead::KEY_LEN }>,
+    ad: &[u8],
+    message: &[u8],
+    alloc: A,
+) -> Result<Vec<u8, A>> {
+    let mut plaintext = zeroed(xaead::plaintext_len(message.len())?, alloc)?;
+    xaead::decrypt_with_key(&mut plaintext, key, ad, message)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(plaintext: &[u8]) {
+        let key = Secret::random();
+        let message = encrypt_message(&key, b"ad", plaintext).unwrap();
+        assert_eq!(message.len(), plaintext.len() + OVERHEAD);
+        assert_eq!(
+            &decrypt_message(&key, b"ad", &message).unwrap()[..],
+            plaintext
+        );
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        roundtrip(b"");
+        roundtrip(b"Hello, World!");
+
+        let mut large = vec![0u8; 1 << 20];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut large[..]);
+        roundtrip(&large);
+    }
+
+    #[test]
+    fn message_custom_allocator() {
+        use allocator_api2::alloc::Global;
+        use rosenpass_secret_memory::alloc::HybridAllocator;
+
+        let key = Secret::random();
+        let pt = b"Hello, World!";
+
+        let message = encrypt_message_in(&key, b"ad", pt, Global).unwrap();
+        let out = decrypt_message_in(&key, b"ad", &message, SecretAllocator::new()).unwrap();
+        assert_eq!(&out[..], pt);
+
+        let message = encrypt_message_in(&key, b"ad", pt, HybridAllocator::new()).unwrap();
+        assert_eq!(&decrypt_message(&key, b"ad", &message).unwrap()[..], pt);
+        let out = decrypt_message_in(&key, b"ad", &message, Global).unwrap();
+        assert_eq!(&out[..], pt);
+
+        assert!(decrypt_message_in(&key, b"", &message, Global).is_err());
+    }
+
+    #[test]
+    fn message_to_writer() {
+        let key = Secret::random();
+        let mut pt = vec![0u8; 10000];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut pt[..]);
+
+        let mut cursor = std::io::Cursor::new(std::vec::Vec::new());
+        encrypt_message_to(&mut cursor, &key, b"ad", &pt).unwrap();
+        let message = cursor.into_inner();
+        assert_eq!(message.len(), pt.len() + OVERHEAD);
+        assert_eq!(
+            &decrypt_message(&key, b"ad", &message).unwrap()[..],
+            &pt[..]
+        );
+        assert!(decrypt_message(&key, b"", &message).is_err());
+    }
+
+    #[test]
+    fn message_nonces_differ() {
+        let key = Secret::random();
+        let a = encrypt_message(&key, b"", b"Hello, World!").unwrap();
+        let b = encrypt_message(&key, b"", b"Hello, World!").unwrap();
+        assert_ne!(a[..xaead::NONCE_LEN], b[..xaead::NONCE_LEN]);
+        assert_ne!(a[..], b[..]);
+    }
+
+    #[test]
+    fn message_rejects_tampering() {
+        let key = Secret::random();
+        let message = encrypt_message(&key, b"ad", b"Hello, World!").unwrap();
+
+        let mut forged = message.clone();
+        forged[xaead::NONCE_LEN] ^= 1;
+        assert!(decrypt_message(&key, b"ad", &forged).is_err());
+        assert!(decrypt_message(&key, b"other ad", &message).is_err());
+        assert!(decrypt_message(&Secret::random(), b"ad", &message).is_err());
+        assert!(decrypt_message(&key, b"ad", &message[..OVERHEAD - 1]).is_err());
+    }
+
+    #[test]
+    fn message_context_binding() {
+        let key = Secret::random();
+        let pt = b"Hello, World!";
+        let message = encrypt_message_with_context(&key, b"A", b"ad", pt).unwrap();
+
+        let out = decrypt_message_with_context(&key, b"A", b"ad", &message).unwrap();
+        assert_eq!(&out[..], pt);
+        assert!(decrypt_message_with_context(&key, b"B", b"ad", &message).is_err());
+        assert!(decrypt_message_with_context(&key, b"", b"ad", &message).is_err());
+        assert!(decrypt_message(&key, b"ad", &message).is_err());
+
+        // Moving bytes between context and associated data changes the binding
+        let message = encrypt_message_with_context(&key, b"AB", b"", pt).unwrap();
+        assert!(decrypt_message_with_context(&key, b"A", b"B", &message).is_err());
+    }
+
+    #[test]
+    fn message_header_check() {
+        let key = Secret::random();
+        let pt = b"Hello, World!";
+        let message = encrypt_message(&key, b"route:A;rest", pt).unwrap();
+
+        // Wrong header fails fast, even for garbage that would not pass the AEAD
+        let err =
+            decrypt_message_with_header(&key, b"route:B;rest", 6..7, b"A", &message).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected message header");
+        let err = decrypt_message_with_header(&key, b"route:B;rest", 6..7, b"A", b"").unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected message header");
+        assert!(decrypt_message_with_header(&key, b"route", 6..7, b"A", &message).is_err());
+
+        // Correct header proceeds to full verification
+        let out = decrypt_message_with_header(&key, b"route:A;rest", 6..7, b"A", &message).unwrap();
+        assert_eq!(&out[..], pt);
+        let err =
+            decrypt_message_with_header(&key, b"route:A;other", 6..7, b"A", &message).unwrap_err();
+        assert_ne!(err.to_string(), "Unexpected message header");
+        let mut forged = message.clone();
+        forged[xaead::NONCE_LEN] ^= 1;
+        assert!(decrypt_message_with_header(&key, b"route:A;rest", 6..7, b"A", &forged).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Replay protection for messages numbered by a monotonic counter
+//!
+//! Transports such as UDP may drop, duplicate and reorder messages. When each message
+//! carries a counter, e.g. as its AEAD nonce, a [ReplayWindow] tracks which counters were
+//! seen recently, so that authentic messages are accepted exactly once while reordering
+//! within the window is tolerated. This is the sliding window used by IPsec and WireGuard
+//! (RFC 6479).
+//!
+//! Counters must only be passed to the window *after* the message was authenticated;
+//! otherwise, forged messages could advance the window and cause authentic ones to be
+//! rejected.
+
+/// Number of bits in a bitmap word
+const WORD_BITS: u64 = u64::BITS as u64;
+
+/// Number of words in the bitmap; must be a power of two
+const WORDS: usize = 32;
+
+/// Number of counters preceding the greatest counter seen that are still accepted
+///
+/// One word of the bitmap is sacrificed so that sliding the window only needs to clear
+/// whole words.
+pub const WINDOW_SIZE: u64 = (WORDS as u64 - 1) * WORD_BITS;
+
+/// Sliding window of recently seen counters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayWindow {
+    /// Greatest counter seen plus one, zero if no counter was seen yet
+    greatest: u64,
+    /// Ring buffer of bits, one for each counter in the window
+    bitmap: [u64; WORDS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            greatest: 0,
+            bitmap: [0; WORDS],
+        }
+    }
+
+    /// Check whether a message with the given counter is acceptable, marking it as seen
+    ///
+    /// Returns false for counters seen before and counters too far behind the greatest
+    /// counter seen, i.e. by more than [WINDOW_SIZE]. Counters ahead of the window are
+    /// accepted and advance it. `u64::MAX` is never accepted.
+    pub fn check_and_update(&mut self, counter: u64) -> bool {
+        // Shift by one so zero can mark the empty window
+        let Some(counter) = counter.checked_add(1) else {
+            return false;
+        };
+        if counter.saturating_add(WINDOW_SIZE) < self.greatest {
+            return false;
+        }
+
+        let index = counter / WORD_BITS;
+        if counter > self.greatest {
+            // Slide the window, clearing the words skipped over
+            let current = self.greatest / WORD_BITS;
+            let advance = (index - current).min(WORDS as u64);
+            for i in 1..=advance {
+                self.bitmap[Self::word(current + i)] = 0;
+            }
+            self.greatest = counter;
+        }
+
+        let word = &mut self.bitmap[Self::word(index)];
+        let bit = 1 << (counter % WORD_BITS);
+        let seen = *word & bit != 0;
+        *word |= bit;
+        !seen
+    }
+
+    fn word(index: u64) -> usize {
+        (index % WORDS as u64) as usize
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut w = ReplayWindow::new();
+        for c in 0..10_000 {
+            assert!(w.check_and_update(c), "Counter {c} rejected");
+        }
+    }
+
+    #[test]
+    fn reordered_within_window() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(100));
+        assert!(w.check_and_update(0));
+        assert!(w.check_and_update(99));
+        assert!(w.check_and_update(50));
+        assert!(w.check_and_update(101));
+
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(WINDOW_SIZE + 10));
+        assert!(w.check_and_update(10));
+    }
+
+    #[test]
+    fn replayed() {
+        let mut w = ReplayWindow::new();
+        for c in [0, 5, 3, 1000] {
+            assert!(w.check_and_update(c));
+        }
+        for c in [0, 5, 3, 1000] {
+            assert!(!w.check_and_update(c), "Replay of {c} accepted");
+        }
+    }
+
+    #[test]
+    fn too_old() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(WINDOW_SIZE + 10));
+        assert!(!w.check_and_update(9));
+        assert!(!w.check_and_update(0));
+
+        // The window slides along
+        assert!(w.check_and_update(3 * WINDOW_SIZE));
+        assert!(!w.check_and_update(2 * WINDOW_SIZE - 1));
+        assert!(w.check_and_update(2 * WINDOW_SIZE));
+    }
+
+    #[test]
+    fn large_jump_clears_window() {
+        let mut w = ReplayWindow::new();
+        for c in 0..WINDOW_SIZE {
+            assert!(w.check_and_update(c));
+        }
+        // Bits of the old counters must not be mistaken for the new ones sharing their words
+        let jump = 100 * WORDS as u64 * WORD_BITS;
+        assert!(w.check_and_update(jump + WINDOW_SIZE));
+        for c in jump..jump + WINDOW_SIZE {
+            assert!(w.check_and_update(c), "Counter {c} rejected");
+        }
+    }
+
+    #[test]
+    fn counter_limits() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(u64::MAX - 1));
+        assert!(!w.check_and_update(u64::MAX - 1));
+        assert!(!w.check_and_update(u64::MAX));
+        assert!(!w.check_and_update(0));
+    }
+}
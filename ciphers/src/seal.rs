@@ -0,0 +1,164 @@
+//! Envelopes consisting of an authenticated, public header and an encrypted payload
+//!
+//! The on-wire layout of a sealed envelope is
+//!
+//! ```text
+//! header_len: u32 (little endian) || header || nonce || ciphertext || tag
+//! ```
+//!
+//! where `nonce || ciphertext || tag` is the output of [xaead::encrypt] on the payload.
+//! The associated data is `header_len || header`, i.e. every byte preceding the nonce,
+//! so neither the header nor its length can be modified without [open] failing.
+
+use anyhow::{ensure, Context, Result};
+
+use crate::xaead;
+
+/// Size of the header length field
+pub const HEADER_LEN_LEN: usize = 4;
+
+/// Bytes added to the header and payload by [seal]
+pub const OVERHEAD: usize = HEADER_LEN_LEN + xaead::OVERHEAD;
+
+/// Size of the envelope produced by [seal] for the given header and payload sizes
+pub fn sealed_len(header_len: usize, payload_len: usize) -> usize {
+    OVERHEAD + header_len + payload_len
+}
+
+/// Write `header` followed by the encryption of `payload`, authenticating both
+///
+/// `out` must be exactly [sealed_len] bytes long.
+pub fn seal(out: &mut [u8], key: &[u8], nonce: &[u8], header: &[u8], payload: &[u8]) -> Result<()> {
+    ensure!(
+        out.len() == sealed_len(header.len(), payload.len()),
+        "Output buffer size does not match the sealed envelope size"
+    );
+    let header_len = u32::try_from(header.len()).context("Header too long")?;
+
+    let (ad, ct) = out.split_at_mut(HEADER_LEN_LEN + header.len());
+    let (len_field, header_field) = ad.split_at_mut(HEADER_LEN_LEN);
+    len_field.copy_from_slice(&header_len.to_le_bytes());
+    header_field.copy_from_slice(header);
+
+    xaead::encrypt(ct, key, nonce, ad, payload)
+}
+
+/// Verify an envelope created by [seal], decrypting the payload into `payload`
+///
+/// `payload` must be exactly as long as the sealed payload; see [payload_len].
+/// Returns the authenticated header.
+pub fn open<'a>(payload: &mut [u8], key: &[u8], sealed: &'a [u8]) -> Result<&'a [u8]> {
+    let (ad, ct) = split(sealed)?;
+    ensure!(
+        payload.len() == xaead::plaintext_len(ct.len())?,
+        "Payload buffer size does not match the sealed payload size"
+    );
+    xaead::decrypt(payload, key, ad, ct)?;
+    Ok(&ad[HEADER_LEN_LEN..])
+}
+
+/// Size of the payload contained in a sealed envelope
+pub fn payload_len(sealed: &[u8]) -> Result<usize> {
+    let (_, ct) = split(sealed)?;
+    xaead::plaintext_len(ct.len())
+}
+
+/// Split a sealed envelope into associated data and ciphertext
+fn split(sealed: &[u8]) -> Result<(&[u8], &[u8])> {
+    ensure!(sealed.len() >= OVERHEAD, "Sealed envelope too short");
+    let (len_field, _) = sealed.split_at(HEADER_LEN_LEN);
+    let header_len = u32::from_le_bytes(len_field.try_into().unwrap()) as usize;
+    ensure!(
+        header_len <= sealed.len() - OVERHEAD,
+        "Header length exceeds the sealed envelope"
+    );
+    Ok(sealed.split_at(HEADER_LEN_LEN + header_len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: [u8; xaead::KEY_LEN] = [0x42; xaead::KEY_LEN];
+    const NONCE: [u8; xaead::NONCE_LEN] = [0x13; xaead::NONCE_LEN];
+    const HEADER: &[u8] = b"version 1";
+    const PAYLOAD: &[u8] = b"Hello, World!";
+
+    fn sealed() -> Vec<u8> {
+        let mut out = vec![0u8; sealed_len(HEADER.len(), PAYLOAD.len())];
+        seal(&mut out, &KEY, &NONCE, HEADER, PAYLOAD).unwrap();
+        out
+    }
+
+    #[test]
+    fn seal_layout() {
+        let out = sealed();
+        assert_eq!(&out[..4], &(HEADER.len() as u32).to_le_bytes());
+        assert_eq!(&out[4..4 + HEADER.len()], HEADER);
+        assert_eq!(&out[4 + HEADER.len()..][..xaead::NONCE_LEN], &NONCE);
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let out = sealed();
+        let mut payload = vec![0u8; payload_len(&out).unwrap()];
+        let header = open(&mut payload, &KEY, &out).unwrap();
+        assert_eq!(header, HEADER);
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn open_detects_header_tampering() {
+        let mut out = sealed();
+        out[4] ^= 1;
+        let mut payload = vec![0u8; PAYLOAD.len()];
+        assert!(open(&mut payload, &KEY, &out).is_err());
+
+        // Moving the boundary between header and ciphertext
+        let mut out = sealed();
+        out[0] -= 1;
+        let mut payload = vec![0u8; PAYLOAD.len() + 1];
+        assert!(open(&mut payload, &KEY, &out).is_err());
+    }
+
+    #[test]
+    fn open_detects_payload_tampering() {
+        let mut out = sealed();
+        let ct_start = 4 + HEADER.len() + xaead::NONCE_LEN;
+        out[ct_start] ^= 1;
+        let mut payload = vec![0u8; PAYLOAD.len()];
+        assert!(open(&mut payload, &KEY, &out).is_err());
+        assert_eq!(payload, vec![0u8; PAYLOAD.len()]);
+
+        let mut out = sealed();
+        *out.last_mut().unwrap() ^= 1;
+        assert!(open(&mut payload, &KEY, &out).is_err());
+    }
+
+    #[test]
+    fn open_rejects_truncated_envelope() {
+        let out = sealed();
+        let mut payload = vec![0u8; PAYLOAD.len()];
+        assert!(open(&mut payload, &KEY, &out[..OVERHEAD - 1]).is_err());
+        assert!(open(&mut payload, &KEY, &out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn open_rejects_oversized_header_len() {
+        let mut payload = vec![0u8; PAYLOAD.len()];
+        let max_header_len = (HEADER.len() + PAYLOAD.len()) as u32;
+        for header_len in [max_header_len + 1, u32::MAX / 2, u32::MAX] {
+            let mut out = sealed();
+            out[..4].copy_from_slice(&header_len.to_le_bytes());
+            let err = open(&mut payload, &KEY, &out).unwrap_err();
+            assert_eq!(err.to_string(), "Header length exceeds the sealed envelope");
+            assert!(payload_len(&out).is_err());
+        }
+
+        // The largest header length that fits leaves an empty payload, which fails to verify
+        let mut out = sealed();
+        out[..4].copy_from_slice(&max_header_len.to_le_bytes());
+        assert_eq!(payload_len(&out).unwrap(), 0);
+        assert!(open(&mut [], &KEY, &out).is_err());
+    }
+}
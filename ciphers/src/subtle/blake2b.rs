@@ -1,13 +1,14 @@
 use zeroize::Zeroizing;
 
 use blake2::digest::crypto_common::generic_array::GenericArray;
-use blake2::digest::crypto_common::typenum::U32;
+use blake2::digest::crypto_common::typenum::{U32, U64};
 use blake2::digest::crypto_common::KeySizeUser;
 use blake2::digest::{FixedOutput, Mac, OutputSizeUser};
 use blake2::Blake2bMac;
 
 use rosenpass_to::{ops::copy_slice, with_destination, To};
 use rosenpass_util::typenum2const;
+use static_assertions::const_assert;
 
 type Impl = Blake2bMac<U32>;
 
@@ -17,11 +18,22 @@ type OutLen = <Impl as OutputSizeUser>::OutputSize;
 const KEY_LEN: usize = typenum2const! { KeyLen };
 const OUT_LEN: usize = typenum2const! { OutLen };
 
+// Blake2b accepts keys of up to 64 bytes; the output is truncated to 32 bytes
+const_assert!(KEY_LEN == 64);
+const_assert!(OUT_LEN == 32);
+
 pub const KEY_MIN: usize = KEY_LEN;
 pub const KEY_MAX: usize = KEY_LEN;
 pub const OUT_MIN: usize = OUT_LEN;
 pub const OUT_MAX: usize = OUT_LEN;
 
+/// Output length of [blake2b_512_keyed]
+pub const OUT_LEN_512: usize = 64;
+
+/// Keyed Blake2b with 32 bytes of output
+///
+/// This is the variant used by the Rosenpass protocol, through
+/// [incorrect_hmac_blake2b](crate::subtle::incorrect_hmac_blake2b).
 #[inline]
 pub fn hash<'a>(key: &'a [u8], data: &'a [u8]) -> impl To<[u8], anyhow::Result<()>> + 'a {
     with_destination(|out: &mut [u8]| {
@@ -40,3 +52,53 @@ pub fn hash<'a>(key: &'a [u8], data: &'a [u8]) -> impl To<[u8], anyhow::Result<(
         Ok(())
     })
 }
+
+/// Keyed Blake2b-512, producing the full 64 byte digest
+///
+/// For general purpose use; the Rosenpass protocol uses the truncated output of [hash].
+/// Keys may be up to 64 bytes long.
+pub fn blake2b_512_keyed(key: &[u8], data: &[u8]) -> anyhow::Result<[u8; OUT_LEN_512]> {
+    let mut h = Blake2bMac::<U64>::new_from_slice(key)?;
+    h.update(data);
+
+    let mut out = [0u8; OUT_LEN_512];
+    h.finalize_into(GenericArray::from_mut_slice(&mut out));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Test vectors from the keyed Blake2b-512 known answer tests in the BLAKE2 reference repository
+    #[test]
+    fn blake2b_512_keyed_test_vector() {
+        let key: [u8; 64] = std::array::from_fn(|i| i as u8);
+        let expected_empty: [u8; OUT_LEN_512] = [
+            0x10, 0xeb, 0xb6, 0x77, 0x00, 0xb1, 0x86, 0x8e, 0xfb, 0x44, 0x17, 0x98, 0x7a, 0xcf,
+            0x46, 0x90, 0xae, 0x9d, 0x97, 0x2f, 0xb7, 0xa5, 0x90, 0xc2, 0xf0, 0x28, 0x71, 0x79,
+            0x9a, 0xaa, 0x47, 0x86, 0xb5, 0xe9, 0x96, 0xe8, 0xf0, 0xf4, 0xeb, 0x98, 0x1f, 0xc2,
+            0x14, 0xb0, 0x05, 0xf4, 0x2d, 0x2f, 0xf4, 0x23, 0x34, 0x99, 0x39, 0x16, 0x53, 0xdf,
+            0x7a, 0xef, 0xcb, 0xc1, 0x3f, 0xc5, 0x15, 0x68,
+        ];
+        let expected_012: [u8; OUT_LEN_512] = [
+            0x33, 0xd0, 0x82, 0x5d, 0xdd, 0xf7, 0xad, 0xa9, 0x9b, 0x0e, 0x7e, 0x30, 0x71, 0x04,
+            0xad, 0x07, 0xca, 0x9c, 0xfd, 0x96, 0x92, 0x21, 0x4f, 0x15, 0x61, 0x35, 0x63, 0x15,
+            0xe7, 0x84, 0xf3, 0xe5, 0xa1, 0x7e, 0x36, 0x4a, 0xe9, 0xdb, 0xb1, 0x4c, 0xb2, 0x03,
+            0x6d, 0xf9, 0x32, 0xb7, 0x7f, 0x4b, 0x29, 0x27, 0x61, 0x36, 0x5f, 0xb3, 0x28, 0xde,
+            0x7a, 0xfd, 0xc6, 0xd8, 0x99, 0x8f, 0x5f, 0xc1,
+        ];
+        assert_eq!(blake2b_512_keyed(&key, &[]).unwrap(), expected_empty);
+        assert_eq!(blake2b_512_keyed(&key, &[0, 1, 2]).unwrap(), expected_012);
+    }
+
+    #[test]
+    fn blake2b_512_keyed_is_not_truncated_hash() {
+        let key = [0x42u8; 64];
+        let mut truncated = [0u8; OUT_LEN];
+        hash(&key, b"data").to(&mut truncated).unwrap();
+        let full = blake2b_512_keyed(&key, b"data").unwrap();
+        assert_ne!(&full[..OUT_LEN], &truncated);
+        assert!(blake2b_512_keyed(&[0u8; 65], b"data").is_err());
+    }
+}
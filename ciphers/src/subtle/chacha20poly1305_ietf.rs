@@ -1,15 +1,43 @@
+use anyhow::{anyhow, bail, ensure};
+use rosenpass_constant_time::memcmp;
+use rosenpass_secret_memory::Secret;
 use rosenpass_to::ops::copy_slice;
 use rosenpass_to::To;
 use rosenpass_util::typenum2const;
+use static_assertions::const_assert;
+use zeroize::{Zeroize, Zeroizing};
 
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
 use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::ChaCha20Poly1305 as AeadImpl;
 use chacha20poly1305::{AeadCore, AeadInPlace, KeyInit, KeySizeUser};
+use poly1305::universal_hash::UniversalHash;
+use poly1305::Poly1305;
 
 pub const KEY_LEN: usize = typenum2const! { <AeadImpl as KeySizeUser>::KeySize };
 pub const TAG_LEN: usize = typenum2const! { <AeadImpl as AeadCore>::TagSize };
 pub const NONCE_LEN: usize = typenum2const! { <AeadImpl as AeadCore>::NonceSize };
 
+// Guard against a backend change silently altering the buffer sizes
+const_assert!(KEY_LEN == 32);
+const_assert!(TAG_LEN == 16);
+const_assert!(NONCE_LEN == 12);
+
+/// Bytes added to the plaintext by [encrypt]; the ciphertext is `ct || tag`
+pub const OVERHEAD: usize = TAG_LEN;
+
+/// Size of the ciphertext produced by [encrypt] for a plaintext of the given size
+pub const fn ciphertext_len(plaintext_len: usize) -> usize {
+    plaintext_len + OVERHEAD
+}
+
+/// Size of the plaintext contained in a ciphertext of the given size
+pub fn plaintext_len(ciphertext_len: usize) -> anyhow::Result<usize> {
+    ensure!(ciphertext_len >= OVERHEAD, "Ciphertext too short");
+    Ok(ciphertext_len - OVERHEAD)
+}
+
 #[inline]
 pub fn encrypt(
     ciphertext: &mut [u8],
@@ -18,6 +46,7 @@ pub fn encrypt(
     ad: &[u8],
     plaintext: &[u8],
 ) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext buffer too short");
     let nonce = GenericArray::from_slice(nonce);
     let (ct, mac) = ciphertext.split_at_mut(ciphertext.len() - TAG_LEN);
     copy_slice(plaintext).to(ct);
@@ -34,10 +63,517 @@ pub fn decrypt(
     ad: &[u8],
     ciphertext: &[u8],
 ) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext too short");
     let nonce = GenericArray::from_slice(nonce);
     let (ct, mac) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
     let tag = GenericArray::from_slice(mac);
+    let aead = AeadImpl::new_from_slice(key)?;
+    copy_slice(ct).to(plaintext);
+    if let Err(e) = aead.decrypt_in_place_detached(nonce, ad, plaintext, tag) {
+        // Never leave unauthenticated data in the output buffer
+        plaintext.zeroize();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Like [encrypt], with empty associated data
+#[inline]
+pub fn encrypt_no_ad(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    encrypt(ciphertext, key, nonce, &[], plaintext)
+}
+
+/// Like [decrypt], with empty associated data
+#[inline]
+pub fn decrypt_no_ad(
+    plaintext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    decrypt(plaintext, key, nonce, &[], ciphertext)
+}
+
+/// Like [encrypt], taking the key as a [Secret] of exactly [KEY_LEN] bytes
+#[inline]
+pub fn encrypt_with_key(
+    ciphertext: &mut [u8],
+    key: &Secret<KEY_LEN>,
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    encrypt(ciphertext, key.secret(), nonce, ad, plaintext)
+}
+
+/// Like [decrypt], taking the key as a [Secret] of exactly [KEY_LEN] bytes
+#[inline]
+pub fn decrypt_with_key(
+    plaintext: &mut [u8],
+    key: &Secret<KEY_LEN>,
+    nonce: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    decrypt(plaintext, key.secret(), nonce, ad, ciphertext)
+}
+
+/// Decrypt into the start of an output buffer that may be larger than the plaintext
+///
+/// Returns the size of the plaintext written to `out`; the bytes following it are not modified.
+#[inline]
+pub fn decrypt_into(
+    out: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<usize> {
+    let len = plaintext_len(ciphertext.len())?;
+    ensure!(
+        out.len() >= len,
+        "Output buffer too small for the plaintext"
+    );
+    decrypt(&mut out[..len], key, nonce, ad, ciphertext)?;
+    Ok(len)
+}
+
+/// Decrypt key material into secret memory
+///
+/// This is the recommended way to decrypt PSKs, KEM secrets and other keys: the tag is
+/// verified before any plaintext is produced, the plaintext is only ever written to
+/// secret memory, and `secret` is zeroized if decryption fails for any reason.
+///
+/// The ciphertext must contain exactly `N` bytes of plaintext.
+pub fn verify_and_decrypt_to_secret<const N: usize>(
+    secret: &mut Secret<N>,
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    let res = match plaintext_len(ciphertext.len()) {
+        Ok(len) if len == N => decrypt(secret.secret_mut(), key, nonce, ad, ciphertext),
+        Ok(_) => Err(anyhow!("Ciphertext size does not match the secret size")),
+        Err(e) => Err(e),
+    };
+    if res.is_err() {
+        secret.zeroize();
+    }
+    res
+}
+
+/// Check that a ciphertext is authentic without exposing the plaintext
+///
+/// Only the tag is recomputed; nothing is decrypted and no memory is allocated.
+#[inline]
+pub fn verify(key: &[u8], nonce: &[u8], ad: &[u8], ciphertext: &[u8]) -> anyhow::Result<()> {
+    ensure!(key.len() == KEY_LEN, "Invalid key length");
+    ensure!(nonce.len() == NONCE_LEN, "Invalid nonce length");
+    let (ct, tag) = ciphertext.split_at(plaintext_len(ciphertext.len())?);
+    let cipher = ChaCha20::new(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+    ensure!(memcmp(&poly1305_tag(cipher, ad, ct), tag), "Invalid tag");
+    Ok(())
+}
+
+/// The Poly1305 tag of `ad` and `ct`, as computed by ChaCha20Poly1305 (RFC 8439, section 2.8)
+///
+/// `cipher` must be positioned at the start of its keystream; its first block provides
+/// the Poly1305 key.
+pub(crate) fn poly1305_tag<C: StreamCipher>(mut cipher: C, ad: &[u8], ct: &[u8]) -> [u8; TAG_LEN] {
+    let mut block = Zeroizing::new([0u8; 64]);
+    cipher.apply_keystream(block.as_mut());
+    let mut mac = Poly1305::new(GenericArray::from_slice(&block[..32]));
+    mac.update_padded(ad);
+    mac.update_padded(ct);
+
+    let mut lengths = GenericArray::default();
+    lengths[..8].copy_from_slice(&(ad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ct.len() as u64).to_le_bytes());
+    mac.update(&[lengths]);
+    mac.finalize().into()
+}
+
+/// Encrypt with the nonce bound into the associated data
+///
+/// The associated data passed to the AEAD is `nonce || extra_ad`. Unlike [encrypt]
+/// followed by transmitting the nonce, this makes the tag depend on the nonce
+/// explicitly, so a ciphertext can not be accepted under a different nonce, even
+/// if the other nonce yields a valid tag for a different associated data.
+///
+/// Must be decrypted with [decrypt_bind_nonce].
+#[inline]
+pub fn encrypt_bind_nonce(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    extra_ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    encrypt(
+        ciphertext,
+        key,
+        nonce,
+        &bind_nonce(nonce, extra_ad),
+        plaintext,
+    )
+}
+
+/// Decrypt a ciphertext produced by [encrypt_bind_nonce]
+///
+/// The associated data passed to the AEAD is `nonce || extra_ad`.
+#[inline]
+pub fn decrypt_bind_nonce(
+    plaintext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    extra_ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    decrypt(
+        plaintext,
+        key,
+        nonce,
+        &bind_nonce(nonce, extra_ad),
+        ciphertext,
+    )
+}
+
+/// Shortest tag accepted by [encrypt_truncated] and [decrypt_truncated]
+pub const MIN_TRUNCATED_TAG_LEN: usize = 8;
+
+fn check_tag_len(tag_len: usize) -> anyhow::Result<()> {
+    ensure!(
+        (MIN_TRUNCATED_TAG_LEN..=TAG_LEN).contains(&tag_len),
+        "Tag length {tag_len} is out of range [{MIN_TRUNCATED_TAG_LEN}, {TAG_LEN}]"
+    );
+    Ok(())
+}
+
+/// Like [encrypt], but only keep the first `tag_len` bytes of the tag
+///
+/// The ciphertext is `ct || tag[..tag_len]`, so `ciphertext` must be `tag_len` bytes
+/// longer than `plaintext`. `tag_len` must be in `[MIN_TRUNCATED_TAG_LEN, TAG_LEN]`;
+/// with `tag_len == TAG_LEN`, this is the same as [encrypt].
+///
+/// # Security
+///
+/// Every forgery attempt succeeds with a probability of about `2^(-8 * tag_len)`, so an
+/// 8 byte tag only resists about 2^64 attempts. Only use truncated tags when bandwidth
+/// really matters and the number of decryption attempts an attacker can make is limited.
+#[inline]
+pub fn encrypt_truncated(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+    tag_len: usize,
+) -> anyhow::Result<()> {
+    check_tag_len(tag_len)?;
+    ensure!(
+        ciphertext.len() == plaintext.len() + tag_len,
+        "Ciphertext buffer size does not match the plaintext and tag size"
+    );
+    let nonce = GenericArray::from_slice(nonce);
+    let (ct, mac) = ciphertext.split_at_mut(plaintext.len());
+    copy_slice(plaintext).to(ct);
+    let mac_value = AeadImpl::new_from_slice(key)?.encrypt_in_place_detached(nonce, ad, ct)?;
+    copy_slice(&mac_value[..tag_len]).to(mac);
+    Ok(())
+}
+
+/// Decrypt a ciphertext produced by [encrypt_truncated] with the same `tag_len`
+///
+/// The full tag is computed and its first `tag_len` bytes are compared with the
+/// received tag in constant time. `plaintext` is only decrypted into once the tag is
+/// verified; on failure, it is zeroized. See
+/// [encrypt_truncated] for the security implications of truncated tags.
+#[inline]
+pub fn decrypt_truncated(
+    plaintext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+    tag_len: usize,
+) -> anyhow::Result<()> {
+    check_tag_len(tag_len)?;
+    ensure!(ciphertext.len() >= tag_len, "Ciphertext too short");
+    let (ct, mac) = ciphertext.split_at(ciphertext.len() - tag_len);
+    ensure!(
+        plaintext.len() == ct.len(),
+        "Plaintext buffer size does not match the ciphertext size"
+    );
+    ensure!(key.len() == KEY_LEN, "Invalid key length");
+    ensure!(nonce.len() == NONCE_LEN, "Invalid nonce length");
+    let cipher = || {
+        ChaCha20::new(
+            GenericArray::from_slice(key),
+            GenericArray::from_slice(nonce),
+        )
+    };
+
+    // The AEAD only verifies complete tags, so compute the full tag like [verify] does
+    let expected = poly1305_tag(cipher(), ad, ct);
+    if !memcmp(&expected[..tag_len], mac) {
+        plaintext.zeroize();
+        bail!("Authentication failed");
+    }
+
+    // The first key stream block provided the Poly1305 key
+    let mut cipher = cipher();
+    cipher.seek(64u64);
     copy_slice(ct).to(plaintext);
-    AeadImpl::new_from_slice(key)?.decrypt_in_place_detached(nonce, ad, plaintext, tag)?;
+    cipher.apply_keystream(plaintext);
     Ok(())
 }
+
+fn bind_nonce(nonce: &[u8], extra_ad: &[u8]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(nonce.len() + extra_ad.len());
+    ad.extend_from_slice(nonce);
+    ad.extend_from_slice(extra_ad);
+    ad
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [0x42; KEY_LEN];
+    const NONCE_A: [u8; NONCE_LEN] = [0xaa; NONCE_LEN];
+    const NONCE_B: [u8; NONCE_LEN] = [0xbb; NONCE_LEN];
+
+    #[test]
+    fn aead_properties() {
+        crate::subtle::aead_properties::check(&crate::subtle::aead_properties::Aead {
+            key_len: KEY_LEN,
+            nonce_len: NONCE_LEN,
+            overhead: OVERHEAD,
+            encrypt,
+            decrypt,
+        });
+    }
+
+    #[test]
+    fn overhead_lengths() {
+        assert_eq!(OVERHEAD, 16);
+        assert_eq!(ciphertext_len(0), 16);
+        assert_eq!(ciphertext_len(13), 29);
+        assert_eq!(plaintext_len(29).unwrap(), 13);
+        assert_eq!(plaintext_len(16).unwrap(), 0);
+        assert!(plaintext_len(15).is_err());
+        for len in [0, 1, 64, 1000] {
+            assert_eq!(plaintext_len(ciphertext_len(len)).unwrap(), len);
+        }
+
+        let pt = b"Hello, World!";
+        let mut ct = vec![0u8; ciphertext_len(pt.len())];
+        encrypt(&mut ct, &KEY, &NONCE_A, b"", pt).unwrap();
+        let mut out = vec![0u8; plaintext_len(ct.len()).unwrap()];
+        decrypt(&mut out, &KEY, &NONCE_A, b"", &ct).unwrap();
+        assert_eq!(&out, pt);
+    }
+
+    #[test]
+    fn bind_nonce_roundtrip() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt_bind_nonce(&mut ct, &KEY, &NONCE_A, b"extra", pt).unwrap();
+
+        let mut out = [0u8; 13];
+        decrypt_bind_nonce(&mut out, &KEY, &NONCE_A, b"extra", &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        assert!(decrypt_bind_nonce(&mut out, &KEY, &NONCE_A, b"other", &ct).is_err());
+    }
+
+    #[test]
+    fn bind_nonce_rejects_swapped_nonce() {
+        let pt = b"Hello, World!";
+
+        // A ciphertext with a perfectly valid tag under NONCE_B, created with the
+        // associated data the bound variant would use for NONCE_A
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE_B, &bind_nonce(&NONCE_A, b"extra"), pt).unwrap();
+
+        let mut out = [0u8; 13];
+        assert!(decrypt_bind_nonce(&mut out, &KEY, &NONCE_A, b"extra", &ct).is_err());
+        assert!(decrypt_bind_nonce(&mut out, &KEY, &NONCE_B, b"extra", &ct).is_err());
+
+        // Sanity check: the binding is exactly nonce || extra_ad
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt_bind_nonce(&mut ct, &KEY, &NONCE_A, b"extra", pt).unwrap();
+        decrypt(
+            &mut out,
+            &KEY,
+            &NONCE_A,
+            &bind_nonce(&NONCE_A, b"extra"),
+            &ct,
+        )
+        .unwrap();
+        assert_eq!(&out, pt);
+    }
+
+    #[test]
+    fn decrypt_into_oversized_buffer() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE_A, b"", pt).unwrap();
+
+        let mut out = [0xffu8; 32];
+        let len = decrypt_into(&mut out, &KEY, &NONCE_A, b"", &ct).unwrap();
+        assert_eq!(len, pt.len());
+        assert_eq!(&out[..len], pt);
+        assert_eq!(&out[len..], &[0xffu8; 32 - 13]);
+        assert!(decrypt_into(&mut out[..12], &KEY, &NONCE_A, b"", &ct).is_err());
+    }
+
+    #[test]
+    fn no_ad_matches_empty_ad() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; ciphertext_len(13)];
+        encrypt_no_ad(&mut ct, &KEY, &NONCE_A, pt).unwrap();
+
+        let mut expected = [0u8; ciphertext_len(13)];
+        encrypt(&mut expected, &KEY, &NONCE_A, &[], pt).unwrap();
+        assert_eq!(ct, expected);
+
+        let mut out = [0u8; 13];
+        decrypt_no_ad(&mut out, &KEY, &NONCE_A, &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        // Ciphertexts with associated data are rejected
+        encrypt(&mut ct, &KEY, &NONCE_A, b"ad", pt).unwrap();
+        assert!(decrypt_no_ad(&mut out, &KEY, &NONCE_A, &ct).is_err());
+    }
+
+    #[test]
+    fn secret_key_roundtrip() {
+        let key = Secret::<32>::from_slice(&KEY);
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; ciphertext_len(13)];
+        encrypt_with_key(&mut ct, &key, &NONCE_A, b"ad", pt).unwrap();
+
+        // Interoperates with the slice based API
+        let mut expected = [0u8; ciphertext_len(13)];
+        encrypt(&mut expected, &KEY, &NONCE_A, b"ad", pt).unwrap();
+        assert_eq!(ct, expected);
+
+        let mut out = [0u8; 13];
+        decrypt_with_key(&mut out, &key, &NONCE_A, b"ad", &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        let other = Secret::<32>::random();
+        assert!(decrypt_with_key(&mut out, &other, &NONCE_A, b"ad", &ct).is_err());
+        assert_eq!(out, [0u8; 13]);
+    }
+
+    #[test]
+    fn verify_and_decrypt_to_secret_roundtrip() {
+        let psk = Secret::<32>::random();
+        let mut ct = [0u8; ciphertext_len(32)];
+        encrypt(&mut ct, &KEY, &NONCE_A, b"psk", psk.secret()).unwrap();
+
+        let mut out = Secret::<32>::random();
+        verify_and_decrypt_to_secret(&mut out, &KEY, &NONCE_A, b"psk", &ct).unwrap();
+        assert_eq!(out.secret(), psk.secret());
+
+        let mut out = Secret::<32>::random();
+        assert!(verify_and_decrypt_to_secret(&mut out, &KEY, &NONCE_B, b"psk", &ct).is_err());
+        assert_eq!(out.secret(), &[0u8; 32]);
+    }
+
+    #[test]
+    fn decrypt_forgery_zeroizes_plaintext() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE_A, b"", pt).unwrap();
+        ct[0] ^= 1;
+
+        let mut out = [0xffu8; 13];
+        assert!(decrypt(&mut out, &KEY, &NONCE_A, b"", &ct).is_err());
+        assert_eq!(out, [0u8; 13]);
+    }
+
+    #[test]
+    fn verify_detects_forgery() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE_A, b"ad", pt).unwrap();
+        verify(&KEY, &NONCE_A, b"ad", &ct).unwrap();
+
+        for i in 0..ct.len() {
+            let mut forged = ct;
+            forged[i] ^= 0x80;
+            assert!(verify(&KEY, &NONCE_A, b"ad", &forged).is_err());
+        }
+        assert!(verify(&KEY, &NONCE_B, b"ad", &ct).is_err());
+        assert!(verify(&KEY, &NONCE_A, b"ad", &ct[..TAG_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn verify_accepts_encrypted() {
+        use crate::subtle::aead_properties::{bytes, config};
+        use proptest::prelude::*;
+
+        proptest!(config(), |(
+            key in bytes(KEY_LEN),
+            nonce in bytes(NONCE_LEN),
+            ad in bytes(0..=64),
+            pt in bytes(0..=1000),
+        )| {
+            let mut ct = vec![0u8; ciphertext_len(pt.len())];
+            encrypt(&mut ct, &key, &nonce, &ad, &pt).unwrap();
+            prop_assert!(verify(&key, &nonce, &ad, &ct).is_ok());
+        });
+    }
+
+    #[test]
+    fn truncated_tag_roundtrip() {
+        let pt = b"Hello, World!";
+        let mut full = [0u8; ciphertext_len(13)];
+        encrypt(&mut full, &KEY, &NONCE_A, b"ad", pt).unwrap();
+
+        for tag_len in [MIN_TRUNCATED_TAG_LEN, TAG_LEN] {
+            let mut ct = vec![0u8; pt.len() + tag_len];
+            encrypt_truncated(&mut ct, &KEY, &NONCE_A, b"ad", pt, tag_len).unwrap();
+            // The tag is a prefix of the full tag
+            assert_eq!(&ct[..], &full[..pt.len() + tag_len]);
+
+            let mut out = [0u8; 13];
+            decrypt_truncated(&mut out, &KEY, &NONCE_A, b"ad", &ct, tag_len).unwrap();
+            assert_eq!(&out, pt);
+
+            for i in 0..ct.len() {
+                let mut forged = ct.clone();
+                forged[i] ^= 0x80;
+                let mut out = [0xffu8; 13];
+                assert!(
+                    decrypt_truncated(&mut out, &KEY, &NONCE_A, b"ad", &forged, tag_len).is_err()
+                );
+                assert_eq!(out, [0u8; 13]);
+            }
+            assert!(decrypt_truncated(&mut out, &KEY, &NONCE_B, b"ad", &ct, tag_len).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_tag_length_out_of_range() {
+        let pt = b"Hello, World!";
+        for tag_len in [0, MIN_TRUNCATED_TAG_LEN - 1, TAG_LEN + 1] {
+            let mut ct = vec![0u8; pt.len() + tag_len];
+            assert!(encrypt_truncated(&mut ct, &KEY, &NONCE_A, b"", pt, tag_len).is_err());
+            let mut out = [0u8; 13];
+            assert!(decrypt_truncated(&mut out, &KEY, &NONCE_A, b"", &ct, tag_len).is_err());
+        }
+    }
+}
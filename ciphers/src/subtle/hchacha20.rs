@@ -0,0 +1,46 @@
+use zeroize::Zeroize;
+
+use chacha20::cipher::consts::U10;
+use chacha20::cipher::generic_array::GenericArray;
+use chacha20::hchacha;
+
+use rosenpass_secret_memory::Secret;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 16;
+pub const OUT_LEN: usize = 32;
+
+/// HChaCha20 subkey derivation as used by XChaCha20
+///
+/// Derives a subkey from a key and the first 16 bytes of a nonce.
+/// See <https://datatracker.ietf.org/doc/html/draft-irtf-cfrg-xchacha-03#section-2.2>
+pub fn hchacha20(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN]) -> Secret<OUT_LEN> {
+    let mut tmp = hchacha::<U10>(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+    let out = Secret::from_slice(&tmp);
+    tmp.as_mut_slice().zeroize();
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Test vector from draft-irtf-cfrg-xchacha-03, section 2.2.1
+    #[test]
+    fn hchacha20_test_vector() {
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        let nonce: [u8; NONCE_LEN] = [
+            0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x4a, 0x00, 0x00, 0x00, 0x00, 0x31, 0x41,
+            0x59, 0x27,
+        ];
+        let expected: [u8; OUT_LEN] = [
+            0x82, 0x41, 0x3b, 0x42, 0x27, 0xb2, 0x7b, 0xfe, 0xd3, 0x0e, 0x42, 0x50, 0x8a, 0x87,
+            0x7d, 0x73, 0xa0, 0xf9, 0xe4, 0xd5, 0x8a, 0x74, 0xa8, 0x53, 0xc1, 0x2e, 0xc4, 0x13,
+            0x26, 0xd3, 0xec, 0xdc,
+        ];
+        assert_eq!(hchacha20(&key, &nonce).secret(), &expected);
+    }
+}
@@ -3,6 +3,7 @@ use zeroize::Zeroizing;
 
 use rosenpass_constant_time::xor;
 use rosenpass_to::{ops::copy_slice, with_destination, To};
+use static_assertions::const_assert;
 
 use crate::subtle::blake2b;
 
@@ -12,6 +13,10 @@ pub const KEY_MAX: usize = KEY_LEN;
 pub const OUT_MIN: usize = blake2b::OUT_MIN;
 pub const OUT_MAX: usize = blake2b::OUT_MAX;
 
+// Keys are passed to blake2b unpadded and the inner hash output is reused as key-sized data
+const_assert!(KEY_LEN <= blake2b::KEY_MAX);
+const_assert!(KEY_LEN == OUT_MIN);
+
 /// This is a woefully incorrect implementation of hmac_blake2b.
 /// See <https://github.com/rosenpass/rosenpass/issues/68#issuecomment-1563612222>
 ///
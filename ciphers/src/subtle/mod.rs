@@ -1,4 +1,8 @@
+#[cfg(test)]
+mod aead_properties;
 pub mod blake2b;
 pub mod chacha20poly1305_ietf;
+pub mod hchacha20;
 pub mod incorrect_hmac_blake2b;
 pub mod xchacha20poly1305_ietf;
+pub mod xchacha20poly1305_ietf_hchacha;
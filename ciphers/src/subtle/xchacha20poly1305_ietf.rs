@@ -1,15 +1,51 @@
+use std::io::Write;
+
+use anyhow::{anyhow, ensure};
+use rosenpass_constant_time::memcmp;
+use rosenpass_secret_memory::{Public, Secret};
 use rosenpass_to::ops::copy_slice;
 use rosenpass_to::To;
 use rosenpass_util::typenum2const;
+use static_assertions::const_assert;
+use zeroize::{Zeroize, Zeroizing};
 
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
 use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::XChaCha20Poly1305 as AeadImpl;
 use chacha20poly1305::{AeadCore, AeadInPlace, KeyInit, KeySizeUser};
+use poly1305::universal_hash::UniversalHash;
+use poly1305::Poly1305;
+
+use crate::subtle::chacha20poly1305_ietf;
 
 pub const KEY_LEN: usize = typenum2const! { <AeadImpl as KeySizeUser>::KeySize };
 pub const TAG_LEN: usize = typenum2const! { <AeadImpl as AeadCore>::TagSize };
 pub const NONCE_LEN: usize = typenum2const! { <AeadImpl as AeadCore>::NonceSize };
 
+// Guard against a backend change silently altering the buffer sizes
+const_assert!(KEY_LEN == 32);
+const_assert!(TAG_LEN == 16);
+const_assert!(NONCE_LEN == 24);
+
+/// Bytes added to the plaintext by [encrypt]; the ciphertext is `nonce || ct || tag`
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// Size of the chunks [encrypt_to_writer] encrypts the plaintext in; a multiple of the
+/// ChaCha20 block size
+const WRITE_CHUNK_LEN: usize = 4096;
+
+/// Size of the ciphertext produced by [encrypt] for a plaintext of the given size
+pub const fn ciphertext_len(plaintext_len: usize) -> usize {
+    plaintext_len + OVERHEAD
+}
+
+/// Size of the plaintext contained in a ciphertext of the given size
+pub fn plaintext_len(ciphertext_len: usize) -> anyhow::Result<usize> {
+    ensure!(ciphertext_len >= OVERHEAD, "Ciphertext too short");
+    Ok(ciphertext_len - OVERHEAD)
+}
+
 #[inline]
 pub fn encrypt(
     ciphertext: &mut [u8],
@@ -18,6 +54,7 @@ pub fn encrypt(
     ad: &[u8],
     plaintext: &[u8],
 ) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext buffer too short");
     let nonce = GenericArray::from_slice(nonce);
     let (n, ct_mac) = ciphertext.split_at_mut(NONCE_LEN);
     let (ct, mac) = ct_mac.split_at_mut(ct_mac.len() - TAG_LEN);
@@ -28,6 +65,33 @@ pub fn encrypt(
     Ok(())
 }
 
+/// Encrypt under a fresh random nonce
+///
+/// The nonce is written to the start of `ciphertext`, just like with [encrypt]. At 24 bytes,
+/// it is large enough to be chosen at random for every message without practical risk of
+/// reuse, so no nonce counter has to be maintained. Decrypt with [decrypt] as usual.
+#[inline]
+pub fn encrypt_random_nonce(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    let nonce = Public::<NONCE_LEN>::random();
+    encrypt(ciphertext, key, &nonce.value, ad, plaintext)
+}
+
+/// Like [encrypt_random_nonce], returning the ciphertext in a new buffer
+pub fn encrypt_random_nonce_vec(
+    key: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut ciphertext = vec![0u8; ciphertext_len(plaintext.len())];
+    encrypt_random_nonce(&mut ciphertext, key, ad, plaintext)?;
+    Ok(ciphertext)
+}
+
 #[inline]
 pub fn decrypt(
     plaintext: &mut [u8],
@@ -35,11 +99,382 @@ pub fn decrypt(
     ad: &[u8],
     ciphertext: &[u8],
 ) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext too short");
     let (n, ct_mac) = ciphertext.split_at(NONCE_LEN);
     let (ct, mac) = ct_mac.split_at(ct_mac.len() - TAG_LEN);
     let nonce = GenericArray::from_slice(n);
     let tag = GenericArray::from_slice(mac);
+    let aead = AeadImpl::new_from_slice(key)?;
     copy_slice(ct).to(plaintext);
-    AeadImpl::new_from_slice(key)?.decrypt_in_place_detached(nonce, ad, plaintext, tag)?;
+    if let Err(e) = aead.decrypt_in_place_detached(nonce, ad, plaintext, tag) {
+        // Never leave unauthenticated data in the output buffer
+        plaintext.zeroize();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Like [encrypt], with empty associated data
+#[inline]
+pub fn encrypt_no_ad(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    encrypt(ciphertext, key, nonce, &[], plaintext)
+}
+
+/// Like [decrypt], with empty associated data
+#[inline]
+pub fn decrypt_no_ad(plaintext: &mut [u8], key: &[u8], ciphertext: &[u8]) -> anyhow::Result<()> {
+    decrypt(plaintext, key, &[], ciphertext)
+}
+
+/// Like [encrypt], writing `nonce || ct || tag` to `writer` instead of a buffer
+///
+/// The plaintext is encrypted in small chunks, so no buffer the size of the ciphertext is
+/// needed. The AEAD is composed from ChaCha20 and Poly1305 as in RFC 8439, section 2.8;
+/// the output is identical to that of [encrypt]. If writing fails, part of the ciphertext
+/// may already have been written.
+pub fn encrypt_to_writer<W: Write>(
+    mut writer: W,
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    ensure!(key.len() == KEY_LEN, "Invalid key length");
+    ensure!(nonce.len() == NONCE_LEN, "Invalid nonce length");
+    let mut cipher = XChaCha20::new(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+
+    // The first keystream block provides the Poly1305 key; encryption starts at the second
+    let mut block = Zeroizing::new([0u8; 64]);
+    cipher.apply_keystream(block.as_mut());
+    let mut mac = Poly1305::new(GenericArray::from_slice(&block[..32]));
+    mac.update_padded(ad);
+
+    writer.write_all(nonce)?;
+    let mut buf = Zeroizing::new([0u8; WRITE_CHUNK_LEN]);
+    for chunk in plaintext.chunks(WRITE_CHUNK_LEN) {
+        let ct = &mut buf[..chunk.len()];
+        copy_slice(chunk).to(ct);
+        cipher.apply_keystream(ct);
+        // Only the last chunk may be padded, since all others are a multiple of 16 bytes
+        mac.update_padded(ct);
+        writer.write_all(ct)?;
+    }
+
+    let mut lengths = GenericArray::default();
+    lengths[..8].copy_from_slice(&(ad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(plaintext.len() as u64).to_le_bytes());
+    mac.update(&[lengths]);
+    writer.write_all(&mac.finalize())?;
+    Ok(())
+}
+
+/// Like [encrypt], taking the key as a [Secret] of exactly [KEY_LEN] bytes
+#[inline]
+pub fn encrypt_with_key(
+    ciphertext: &mut [u8],
+    key: &Secret<KEY_LEN>,
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    encrypt(ciphertext, key.secret(), nonce, ad, plaintext)
+}
+
+/// Like [decrypt], taking the key as a [Secret] of exactly [KEY_LEN] bytes
+#[inline]
+pub fn decrypt_with_key(
+    plaintext: &mut [u8],
+    key: &Secret<KEY_LEN>,
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    decrypt(plaintext, key.secret(), ad, ciphertext)
+}
+
+/// Decrypt into the start of an output buffer that may be larger than the plaintext
+///
+/// Returns the size of the plaintext written to `out`; the bytes following it are not modified.
+#[inline]
+pub fn decrypt_into(
+    out: &mut [u8],
+    key: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<usize> {
+    let len = plaintext_len(ciphertext.len())?;
+    ensure!(
+        out.len() >= len,
+        "Output buffer too small for the plaintext"
+    );
+    decrypt(&mut out[..len], key, ad, ciphertext)?;
+    Ok(len)
+}
+
+/// Decrypt key material into secret memory
+///
+/// This is the recommended way to decrypt PSKs, KEM secrets and other keys: the tag is
+/// verified before any plaintext is produced, the plaintext is only ever written to
+/// secret memory, and `secret` is zeroized if decryption fails for any reason.
+///
+/// The ciphertext must contain exactly `N` bytes of plaintext.
+pub fn verify_and_decrypt_to_secret<const N: usize>(
+    secret: &mut Secret<N>,
+    key: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    let res = match plaintext_len(ciphertext.len()) {
+        Ok(len) if len == N => decrypt(secret.secret_mut(), key, ad, ciphertext),
+        Ok(_) => Err(anyhow!("Ciphertext size does not match the secret size")),
+        Err(e) => Err(e),
+    };
+    if res.is_err() {
+        secret.zeroize();
+    }
+    res
+}
+
+/// Check that a ciphertext is authentic without exposing the plaintext
+///
+/// Only the tag is recomputed; nothing is decrypted and no memory is allocated.
+#[inline]
+pub fn verify(key: &[u8], ad: &[u8], ciphertext: &[u8]) -> anyhow::Result<()> {
+    ensure!(key.len() == KEY_LEN, "Invalid key length");
+    let len = plaintext_len(ciphertext.len())?;
+    let (nonce, ct_mac) = ciphertext.split_at(NONCE_LEN);
+    let (ct, tag) = ct_mac.split_at(len);
+    let cipher = XChaCha20::new(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+    ensure!(
+        memcmp(&chacha20poly1305_ietf::poly1305_tag(cipher, ad, ct), tag),
+        "Invalid tag"
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [0x42; KEY_LEN];
+    const NONCE: [u8; NONCE_LEN] = [0xaa; NONCE_LEN];
+
+    #[test]
+    fn aead_properties() {
+        crate::subtle::aead_properties::check(&crate::subtle::aead_properties::Aead {
+            key_len: KEY_LEN,
+            nonce_len: NONCE_LEN,
+            overhead: OVERHEAD,
+            encrypt,
+            decrypt: |pt, key, _nonce, ad, ct| decrypt(pt, key, ad, ct),
+        });
+    }
+
+    #[test]
+    fn overhead_lengths() {
+        assert_eq!(OVERHEAD, 40);
+        assert_eq!(ciphertext_len(0), 40);
+        assert_eq!(ciphertext_len(13), 53);
+        assert_eq!(plaintext_len(53).unwrap(), 13);
+        assert_eq!(plaintext_len(40).unwrap(), 0);
+        assert!(plaintext_len(39).is_err());
+        for len in [0, 1, 64, 1000] {
+            assert_eq!(plaintext_len(ciphertext_len(len)).unwrap(), len);
+        }
+
+        let pt = b"Hello, World!";
+        let mut ct = vec![0u8; ciphertext_len(pt.len())];
+        encrypt(&mut ct, &KEY, &NONCE, b"", pt).unwrap();
+        let mut out = vec![0u8; plaintext_len(ct.len()).unwrap()];
+        decrypt(&mut out, &KEY, b"", &ct).unwrap();
+        assert_eq!(&out, pt);
+    }
+
+    #[test]
+    fn random_nonce_roundtrip() {
+        let pt = b"Hello, World!";
+        let ct1 = encrypt_random_nonce_vec(&KEY, b"ad", pt).unwrap();
+        let ct2 = encrypt_random_nonce_vec(&KEY, b"ad", pt).unwrap();
+        assert_eq!(ct1.len(), ciphertext_len(pt.len()));
+        assert_ne!(&ct1[..NONCE_LEN], &ct2[..NONCE_LEN]);
+        assert_ne!(&ct1[NONCE_LEN..], &ct2[NONCE_LEN..]);
+
+        for ct in [ct1, ct2] {
+            let mut out = [0u8; 13];
+            decrypt(&mut out, &KEY, b"ad", &ct).unwrap();
+            assert_eq!(&out, pt);
+        }
+    }
+
+    #[test]
+    fn decrypt_into_oversized_buffer() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; NONCE_LEN + 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE, b"ad", pt).unwrap();
+
+        let mut out = [0xffu8; 32];
+        let len = decrypt_into(&mut out, &KEY, b"ad", &ct).unwrap();
+        assert_eq!(len, pt.len());
+        assert_eq!(&out[..len], pt);
+        assert_eq!(&out[len..], &[0xffu8; 32 - 13]);
+
+        let mut out = [0u8; 13];
+        assert_eq!(decrypt_into(&mut out, &KEY, b"ad", &ct).unwrap(), 13);
+        assert!(decrypt_into(&mut out[..12], &KEY, b"ad", &ct).is_err());
+        assert!(decrypt_into(&mut out, &KEY, b"ad", &ct[..OVERHEAD - 1]).is_err());
+    }
+
+    #[test]
+    fn no_ad_matches_empty_ad() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; ciphertext_len(13)];
+        encrypt_no_ad(&mut ct, &KEY, &NONCE, pt).unwrap();
+
+        let mut expected = [0u8; ciphertext_len(13)];
+        encrypt(&mut expected, &KEY, &NONCE, &[], pt).unwrap();
+        assert_eq!(ct, expected);
+
+        let mut out = [0u8; 13];
+        decrypt_no_ad(&mut out, &KEY, &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        // Ciphertexts with associated data are rejected
+        encrypt(&mut ct, &KEY, &NONCE, b"ad", pt).unwrap();
+        assert!(decrypt_no_ad(&mut out, &KEY, &ct).is_err());
+    }
+
+    #[test]
+    fn secret_key_roundtrip() {
+        let key = Secret::<32>::from_slice(&KEY);
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; ciphertext_len(13)];
+        encrypt_with_key(&mut ct, &key, &NONCE, b"ad", pt).unwrap();
+
+        // Interoperates with the slice based API
+        let mut expected = [0u8; ciphertext_len(13)];
+        encrypt(&mut expected, &KEY, &NONCE, b"ad", pt).unwrap();
+        assert_eq!(ct, expected);
+
+        let mut out = [0u8; 13];
+        decrypt_with_key(&mut out, &key, b"ad", &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        let other = Secret::<32>::random();
+        assert!(decrypt_with_key(&mut out, &other, b"ad", &ct).is_err());
+        assert_eq!(out, [0u8; 13]);
+    }
+
+    #[test]
+    fn verify_and_decrypt_to_secret_roundtrip() {
+        let psk = Secret::<32>::random();
+        let mut ct = [0u8; ciphertext_len(32)];
+        encrypt(&mut ct, &KEY, &NONCE, b"psk", psk.secret()).unwrap();
+
+        let mut out = Secret::<32>::random();
+        verify_and_decrypt_to_secret(&mut out, &KEY, b"psk", &ct).unwrap();
+        assert_eq!(out.secret(), psk.secret());
+
+        // Forged ciphertext, wrong associated data and size mismatches leave the secret zeroed
+        let mut forged = ct;
+        forged[NONCE_LEN] ^= 1;
+        let mut out = Secret::<32>::random();
+        assert!(verify_and_decrypt_to_secret(&mut out, &KEY, b"psk", &forged).is_err());
+        assert_eq!(out.secret(), &[0u8; 32]);
+
+        let mut out = Secret::<32>::random();
+        assert!(verify_and_decrypt_to_secret(&mut out, &KEY, b"kem", &ct).is_err());
+        assert_eq!(out.secret(), &[0u8; 32]);
+
+        let mut out = Secret::<31>::random();
+        assert!(verify_and_decrypt_to_secret(&mut out, &KEY, b"psk", &ct).is_err());
+        assert_eq!(out.secret(), &[0u8; 31]);
+
+        let mut out = Secret::<32>::random();
+        assert!(verify_and_decrypt_to_secret(&mut out, &KEY, b"psk", &ct[..OVERHEAD - 1]).is_err());
+        assert_eq!(out.secret(), &[0u8; 32]);
+    }
+
+    #[test]
+    fn decrypt_forgery_zeroizes_plaintext() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; NONCE_LEN + 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE, b"", pt).unwrap();
+
+        let mut out = [0xffu8; 13];
+        decrypt(&mut out, &KEY, b"", &ct).unwrap();
+        assert_eq!(&out, pt);
+
+        ct[NONCE_LEN] ^= 1;
+        let mut out = [0xffu8; 13];
+        assert!(decrypt(&mut out, &KEY, b"", &ct).is_err());
+        assert_eq!(out, [0u8; 13]);
+    }
+
+    #[test]
+    fn verify_detects_forgery() {
+        let pt = b"Hello, World!";
+        let mut ct = [0u8; NONCE_LEN + 13 + TAG_LEN];
+        encrypt(&mut ct, &KEY, &NONCE, b"ad", pt).unwrap();
+        verify(&KEY, b"ad", &ct).unwrap();
+
+        for i in 0..ct.len() {
+            let mut forged = ct;
+            forged[i] ^= 0x80;
+            assert!(verify(&KEY, b"ad", &forged).is_err());
+        }
+        assert!(verify(&KEY, b"da", &ct).is_err());
+        assert!(verify(&KEY, b"ad", &ct[..NONCE_LEN + TAG_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn verify_accepts_encrypted() {
+        use crate::subtle::aead_properties::{bytes, config};
+        use proptest::prelude::*;
+
+        proptest!(config(), |(
+            key in bytes(KEY_LEN),
+            nonce in bytes(NONCE_LEN),
+            ad in bytes(0..=64),
+            pt in bytes(0..=1000),
+        )| {
+            let mut ct = vec![0u8; ciphertext_len(pt.len())];
+            encrypt(&mut ct, &key, &nonce, &ad, &pt).unwrap();
+            prop_assert!(verify(&key, &ad, &ct).is_ok());
+        });
+    }
+
+    #[test]
+    fn encrypt_to_writer_matches_encrypt() {
+        for len in [
+            0,
+            1,
+            15,
+            16,
+            64,
+            WRITE_CHUNK_LEN,
+            WRITE_CHUNK_LEN + 17,
+            3 * WRITE_CHUNK_LEN,
+        ] {
+            let pt: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut expected = vec![0u8; ciphertext_len(len)];
+            encrypt(&mut expected, &KEY, &NONCE, b"some ad", &pt).unwrap();
+
+            let mut written = Vec::new();
+            encrypt_to_writer(&mut written, &KEY, &NONCE, b"some ad", &pt).unwrap();
+            assert_eq!(written, expected);
+        }
+
+        assert!(encrypt_to_writer(Vec::new(), &KEY[1..], &NONCE, b"", b"").is_err());
+        assert!(encrypt_to_writer(Vec::new(), &KEY, &NONCE[1..], b"", b"").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! XChaCha20Poly1305 constructed from [hchacha20] and ChaCha20Poly1305
+//!
+//! This follows the construction from draft-irtf-cfrg-xchacha-03, section 2.3: the first
+//! 16 bytes of the nonce and the key are used to derive a subkey with HChaCha20; the
+//! remaining 8 bytes of the nonce, prefixed with four zero bytes, form the ChaCha20Poly1305
+//! nonce. The output is byte-for-byte identical to [xchacha20poly1305_ietf], including
+//! the embedded nonce, so either implementation can decrypt the other's ciphertexts.
+//!
+//! [xchacha20poly1305_ietf]: crate::subtle::xchacha20poly1305_ietf
+
+use anyhow::ensure;
+use rosenpass_secret_memory::Secret;
+use rosenpass_to::ops::copy_slice;
+use rosenpass_to::To;
+
+use crate::subtle::chacha20poly1305_ietf as chacha;
+use crate::subtle::hchacha20::hchacha20;
+pub use crate::subtle::xchacha20poly1305_ietf::{
+    ciphertext_len, plaintext_len, KEY_LEN, NONCE_LEN, OVERHEAD, TAG_LEN,
+};
+
+/// Derive the ChaCha20Poly1305 key and nonce from the XChaCha20Poly1305 key and nonce
+fn derive(key: &[u8], nonce: &[u8]) -> anyhow::Result<(Secret<KEY_LEN>, [u8; chacha::NONCE_LEN])> {
+    ensure!(key.len() == KEY_LEN, "Invalid key length");
+    ensure!(nonce.len() == NONCE_LEN, "Invalid nonce length");
+    let (hnonce, tail) = nonce.split_at(NONCE_LEN - 8);
+    let subkey = hchacha20(key.try_into().unwrap(), hnonce.try_into().unwrap());
+
+    let mut chacha_nonce = [0u8; chacha::NONCE_LEN];
+    copy_slice(tail).to(&mut chacha_nonce[4..]);
+    Ok((subkey, chacha_nonce))
+}
+
+#[inline]
+pub fn encrypt(
+    ciphertext: &mut [u8],
+    key: &[u8],
+    nonce: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext buffer too short");
+    let (subkey, chacha_nonce) = derive(key, nonce)?;
+    let (n, ct_mac) = ciphertext.split_at_mut(NONCE_LEN);
+    copy_slice(nonce).to(n);
+    chacha::encrypt(ct_mac, subkey.secret(), &chacha_nonce, ad, plaintext)
+}
+
+#[inline]
+pub fn decrypt(
+    plaintext: &mut [u8],
+    key: &[u8],
+    ad: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<()> {
+    ensure!(ciphertext.len() >= OVERHEAD, "Ciphertext too short");
+    let (n, ct_mac) = ciphertext.split_at(NONCE_LEN);
+    let (subkey, chacha_nonce) = derive(key, n)?;
+    chacha::decrypt(plaintext, subkey.secret(), &chacha_nonce, ad, ct_mac)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::subtle::xchacha20poly1305_ietf as rustcrypto;
+
+    /// Both implementations must produce the same ciphertexts and accept each other's output
+    #[test]
+    fn differential_xchacha20poly1305() {
+        use crate::subtle::aead_properties::{bytes, config};
+        use proptest::prelude::*;
+
+        proptest!(config(), |(
+            key in bytes(KEY_LEN),
+            nonce in bytes(NONCE_LEN),
+            ad in bytes(0..=36),
+            pt in bytes(0..=1000),
+        )| {
+            let len = pt.len();
+            let mut ct = vec![0u8; ciphertext_len(len)];
+            let mut ct_ref = vec![0u8; ciphertext_len(len)];
+            encrypt(&mut ct, &key, &nonce, &ad, &pt).unwrap();
+            rustcrypto::encrypt(&mut ct_ref, &key, &nonce, &ad, &pt).unwrap();
+            prop_assert_eq!(&ct, &ct_ref);
+
+            let mut out = vec![0u8; len];
+            decrypt(&mut out, &key, &ad, &ct_ref).unwrap();
+            prop_assert_eq!(&out, &pt);
+            let mut out = vec![0u8; len];
+            rustcrypto::decrypt(&mut out, &key, &ad, &ct).unwrap();
+            prop_assert_eq!(&out, &pt);
+
+            ct[NONCE_LEN - 1] ^= 1;
+            prop_assert!(decrypt(&mut out, &key, &ad, &ct).is_err());
+            prop_assert!(rustcrypto::decrypt(&mut out, &key, &ad, &ct).is_err());
+        });
+    }
+
+    #[test]
+    fn aead_properties() {
+        crate::subtle::aead_properties::check(&crate::subtle::aead_properties::Aead {
+            key_len: KEY_LEN,
+            nonce_len: NONCE_LEN,
+            overhead: OVERHEAD,
+            encrypt,
+            decrypt: |pt, key, _nonce, ad, ct| decrypt(pt, key, ad, ct),
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        let mut out = [0u8; OVERHEAD];
+        assert!(encrypt(&mut out, &[0u8; KEY_LEN - 1], &[0u8; NONCE_LEN], b"", b"").is_err());
+        assert!(encrypt(&mut out, &[0u8; KEY_LEN], &[0u8; NONCE_LEN - 1], b"", b"").is_err());
+        assert!(decrypt(&mut [], &[0u8; KEY_LEN], b"", &[0u8; OVERHEAD - 1]).is_err());
+    }
+}
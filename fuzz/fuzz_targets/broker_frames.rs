@@ -0,0 +1,62 @@
+#![no_main]
+extern crate arbitrary;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use rosenpass_wireguard_broker::api::connection::{
+    serve_connection, BrokerResponse, DEFAULT_MAX_QUEUED_REQUESTS,
+};
+use rosenpass_wireguard_broker::api::msgs::REQUEST_MSG_BUFFER_SIZE;
+
+/// A byte stream sent by a client, split into reads of the given sizes
+///
+/// Hand-written seeds covering valid, oversized and truncated frames are part of the
+/// connection's unit tests.
+#[derive(arbitrary::Arbitrary, Debug)]
+pub struct Input {
+    pub stream: Box<[u8]>,
+    pub chunks: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, mut broker) = mpsc::channel(DEFAULT_MAX_QUEUED_REQUESTS);
+        let conn = tokio::spawn(serve_connection(
+            queue,
+            server,
+            DEFAULT_MAX_QUEUED_REQUESTS,
+            None,
+        ));
+        let broker = tokio::spawn(async move {
+            while let Some(req) = broker.recv().await {
+                assert!(req.request.len() <= REQUEST_MSG_BUFFER_SIZE);
+                let _ = req.reply_to.send(BrokerResponse { response: vec![0] });
+            }
+        });
+
+        let mut stream = &input.stream[..];
+        let mut chunks = input.chunks.iter().cycle();
+        while !stream.is_empty() {
+            let len = chunks.next().map_or(stream.len(), |&c| c as usize + 1);
+            let (part, rest) = stream.split_at(len.min(stream.len()));
+            // The connection may have stopped reading already
+            if client.write_all(part).await.is_err() {
+                break;
+            }
+            stream = rest;
+        }
+        let _ = client.shutdown().await;
+
+        // Malformed streams must end in an error, never a panic
+        let _ = conn.await.unwrap();
+        broker.await.unwrap();
+    });
+});
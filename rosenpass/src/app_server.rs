@@ -1008,7 +1008,8 @@ impl AppServer {
         // if each socket returned WouldBlock, then we drained them all at least once indeed
         self.all_sockets_drained = would_block_count == self.sockets.len();
 
-        // Process brokers poll
+        // Process brokers poll; errors for individual PSK requests are handled by the broker,
+        // only a broken broker connection ends up here
         for (_, broker) in self.brokers.store.iter_mut() {
             broker.process_poll()?;
         }
@@ -26,6 +26,12 @@ pub fn main() {
         // error!("error dummy");
     }
 
+    // check the primitives before they are trusted with any key material
+    if let Err(e) = rosenpass_ciphers::self_test() {
+        error!("{e}");
+        exit(1);
+    }
+
     match args.command.run(None) {
         Ok(_) => {}
         Err(e) => {
@@ -8,8 +8,8 @@ use std::{
 
 use clap::{builder::Str, Parser};
 use rosenpass::{app_server::AppServerTestBuilder, cli::CliArgs};
-use rosenpass_secret_memory::{Public, Secret};
-use rosenpass_wireguard_broker::{WireguardBrokerMio, WG_KEY_LEN, WG_PEER_LEN};
+use rosenpass_secret_memory::Secret;
+use rosenpass_wireguard_broker::{PeerId, WireguardBrokerMio, WG_KEY_LEN};
 use serial_test::serial;
 use std::io::Write;
 
@@ -278,7 +278,7 @@ fn check_exchange_under_dos() {
 #[derive(Debug, Default)]
 struct MockBrokerInner {
     psk: Option<Secret<WG_KEY_LEN>>,
-    peer_id: Option<Public<WG_PEER_LEN>>,
+    peer_id: Option<PeerId>,
     interface: Option<String>,
 }
 
@@ -320,7 +320,7 @@ impl rosenpass_wireguard_broker::WireGuardBroker for MockBroker {
             if let Ok(ref mut mutex) = lock {
                 **mutex = MockBrokerInner {
                     psk: Some(config.psk.clone()),
-                    peer_id: Some(config.peer_id.clone()),
+                    peer_id: Some(*config.peer_id),
                     interface: Some(std::str::from_utf8(config.interface).unwrap().to_string()),
                 };
                 break Ok(());
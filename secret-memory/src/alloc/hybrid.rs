@@ -0,0 +1,196 @@
+use std::fmt;
+use std::ptr::NonNull;
+
+use allocator_api2::alloc::{AllocError, Allocator, Global, Layout};
+use zeroize::Zeroize;
+
+use crate::alloc::SecretAllocator;
+
+/// Allocations of at least this many bytes are considered secret by default
+///
+/// This is the size of the smallest keys used in rosenpass.
+pub const DEFAULT_SECRET_THRESHOLD: usize = 32;
+
+/// Allocator that only uses secret memory for allocations big enough to hold secrets
+///
+/// Secret memory is a limited resource; every memsec allocation occupies at least
+/// one locked memory page plus guard pages. Structures that mix secret and public
+/// fields but must use a single allocator would quickly exhaust the memory lock budget
+/// if every tiny, public allocation was placed in secret memory.
+///
+/// The heuristic applied by this allocator is purely size based:
+///
+/// - Allocations with `layout.size() >= threshold` are assumed to hold secrets and are
+///   served by the secret allocator (by default [SecretAllocator]).
+/// - All smaller allocations are assumed to be public and are served by the public
+///   allocator (by default the global allocator).
+///
+/// Deallocation uses the same rule, so the layout passed to `deallocate` always leads
+/// to the backend that produced the allocation. Memory from the public allocator
+/// is zeroized before being freed since growing a buffer can move secret data out
+/// of a small allocation.
+///
+/// Do not use this allocator for secrets smaller than the threshold; they would end
+/// up in regular memory.
+#[derive(Copy, Clone)]
+pub struct HybridAllocator<S = SecretAllocator, P = Global> {
+    threshold: usize,
+    secret: S,
+    public: P,
+}
+
+/// A box backed by the hybrid allocator
+pub type HybridBox<T> = allocator_api2::boxed::Box<T, HybridAllocator>;
+
+/// A vector backed by the hybrid allocator
+pub type HybridVec<T> = allocator_api2::vec::Vec<T, HybridAllocator>;
+
+pub fn hybrid_box<T>(x: T) -> HybridBox<T> {
+    HybridBox::<T>::new_in(x, HybridAllocator::new())
+}
+
+pub fn hybrid_vec<T>() -> HybridVec<T> {
+    HybridVec::<T>::new_in(HybridAllocator::new())
+}
+
+impl HybridAllocator {
+    /// Create a hybrid allocator using [DEFAULT_SECRET_THRESHOLD]
+    pub fn new() -> Self {
+        Self::with_threshold(DEFAULT_SECRET_THRESHOLD)
+    }
+
+    /// Create a hybrid allocator using secret memory for allocations of at least `threshold` bytes
+    pub fn with_threshold(threshold: usize) -> Self {
+        Self::with_backends(threshold, SecretAllocator::new(), Global)
+    }
+}
+
+impl Default for HybridAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Allocator, P: Allocator> HybridAllocator<S, P> {
+    /// Create a hybrid allocator with custom secret and public backends
+    pub fn with_backends(threshold: usize, secret: S, public: P) -> Self {
+        Self {
+            threshold,
+            secret,
+            public,
+        }
+    }
+
+    /// Allocations of at least this many bytes are placed in secret memory
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Whether an allocation with the given layout is served by the secret allocator
+    pub fn is_secret(&self, layout: &Layout) -> bool {
+        layout.size() >= self.threshold
+    }
+}
+
+unsafe impl<S: Allocator, P: Allocator> Allocator for HybridAllocator<S, P> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.is_secret(&layout) {
+            true => self.secret.allocate(layout),
+            false => self.public.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.is_secret(&layout) {
+            true => unsafe { self.secret.deallocate(ptr, layout) },
+            false => {
+                unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), layout.size()) }.zeroize();
+                unsafe { self.public.deallocate(ptr, layout) }
+            }
+        }
+    }
+}
+
+impl<S, P> fmt::Debug for HybridAllocator<S, P> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "<hybrid Rust allocator; secret memory from {} bytes>",
+            self.threshold
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use allocator_api2_tests::make_test;
+
+    use super::*;
+
+    make_test! { test_sizes(HybridAllocator::new()) }
+    make_test! { test_vec(HybridAllocator::new()) }
+    make_test! { test_many_boxes(HybridAllocator::new()) }
+
+    /// Global allocator that counts the live allocations it handed out
+    #[derive(Default)]
+    struct CountingAllocator {
+        live: Cell<usize>,
+    }
+
+    unsafe impl Allocator for &CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.live.set(self.live.get() + 1);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn hybrid_allocation_routing() {
+        let secret = CountingAllocator::default();
+        let public = CountingAllocator::default();
+        let alloc = HybridAllocator::with_backends(32, &secret, &public);
+
+        let small = alloc.allocate(Layout::new::<[u8; 31]>()).unwrap();
+        assert_eq!((secret.live.get(), public.live.get()), (0, 1));
+
+        let large = alloc.allocate(Layout::new::<[u8; 32]>()).unwrap();
+        assert_eq!((secret.live.get(), public.live.get()), (1, 1));
+
+        unsafe { alloc.deallocate(small.cast(), Layout::new::<[u8; 31]>()) };
+        assert_eq!((secret.live.get(), public.live.get()), (1, 0));
+
+        unsafe { alloc.deallocate(large.cast(), Layout::new::<[u8; 32]>()) };
+        assert_eq!((secret.live.get(), public.live.get()), (0, 0));
+    }
+
+    #[test]
+    fn hybrid_allocation_uses_memsec_for_large_values() {
+        let alloc = HybridAllocator::new();
+        let layout = Layout::new::<[u8; DEFAULT_SECRET_THRESHOLD]>();
+        let mem = alloc.allocate(layout).unwrap();
+
+        // Memsec initializes its allocations with the magic byte 0xD0; see the memsec allocator tests
+        assert_eq!(unsafe { mem.as_ref() }, &[0xD0u8; DEFAULT_SECRET_THRESHOLD]);
+
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+    }
+
+    #[test]
+    fn hybrid_vec_grows_into_secret_memory() {
+        let mut v = hybrid_vec::<u8>();
+        v.extend_from_slice(&[1u8; 8]);
+        v.extend_from_slice(&[2u8; 64]);
+        assert_eq!(&v[..8], &[1u8; 8]);
+        assert_eq!(&v[8..], &[2u8; 64]);
+        assert!(v
+            .allocator()
+            .is_secret(&Layout::array::<u8>(v.capacity()).unwrap()));
+    }
+}
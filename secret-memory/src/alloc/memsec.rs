@@ -1,15 +1,87 @@
 use std::fmt;
-use std::ptr::NonNull;
+use std::io;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 
 use allocator_api2::alloc::{AllocError, Allocator, Layout};
+use zeroize::Zeroize;
 
-#[derive(Copy, Clone, Default)]
-struct MemsecAllocatorContents;
+use crate::alloc::support::{secret_memory_supported, SecretMemorySupport};
+
+/// What to do when secret memory can not be locked into RAM
+///
+/// Locking can fail when the process exceeds its `RLIMIT_MEMLOCK` budget. By default,
+/// allocations fall back to unlocked memory, like memsec itself does; strict locking is
+/// opt-in through [set_default_memlock_policy] or [MemsecAllocator::with_memlock_policy].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MemlockPolicy {
+    /// Fail the allocation
+    Require,
+    /// Fall back to unlocked memory; this memory may be swapped to disk.
+    /// The memory is still excluded from core dumps and zeroized on free.
+    #[default]
+    AllowUnlocked,
+}
+
+static ALLOW_UNLOCKED: AtomicBool = AtomicBool::new(true);
+static UNLOCKED_WARNING: Once = Once::new();
+
+/// Set the [MemlockPolicy] used by [MemsecAllocator::new]
+///
+/// This is process-wide and affects all secrets allocated afterwards.
+pub fn set_default_memlock_policy(policy: MemlockPolicy) {
+    let allow = policy == MemlockPolicy::AllowUnlocked;
+    ALLOW_UNLOCKED.store(allow, Ordering::Relaxed);
+}
+
+/// The [MemlockPolicy] used by [MemsecAllocator::new]
+pub fn default_memlock_policy() -> MemlockPolicy {
+    match ALLOW_UNLOCKED.load(Ordering::Relaxed) {
+        true => MemlockPolicy::AllowUnlocked,
+        false => MemlockPolicy::Require,
+    }
+}
+
+/// Mechanism a [MemsecAllocator] obtains its memory from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AllocBackend {
+    /// Guarded heap allocations from the memsec crate, a reimplementation of libsodium's
+    /// `sodium_malloc`: every allocation sits on its own locked pages between guard pages
+    #[default]
+    Memsec,
+    /// Pages from `memfd_secret(2)`, which are removed from the kernel's direct map.
+    /// Only available on Linux, see [secret_memory_supported].
+    MemfdSecret,
+    /// Page-aligned heap memory locked with `mlock(2)`, without guard pages
+    Mlock,
+}
+
+impl AllocBackend {
+    /// Whether allocations through this backend can succeed on this system
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::Memsec | Self::Mlock => true,
+            Self::MemfdSecret => secret_memory_supported() == SecretMemorySupport::MemfdSecret,
+        }
+    }
+}
+
+type LockFn = unsafe fn(NonNull<[u8]>) -> io::Result<()>;
+
+/// How often locking is retried when interrupted by a signal
+const LOCK_RETRIES: usize = 8;
 
 /// Memory allocation using using the memsec crate
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone)]
 pub struct MemsecAllocator {
-    _dummy_private_data: MemsecAllocatorContents,
+    memlock_policy: MemlockPolicy,
+    backend: AllocBackend,
+    max_alloc_size: Option<usize>,
+    // Lets tests simulate locking failures
+    #[cfg(test)]
+    lock: LockFn,
 }
 
 /// A box backed by the memsec allocator
@@ -27,15 +99,145 @@ pub fn memsec_vec<T>() -> MemsecVec<T> {
 }
 
 impl MemsecAllocator {
+    /// Create an allocator using the process-wide [default_memlock_policy]
     pub fn new() -> Self {
+        Self::with_memlock_policy(default_memlock_policy())
+    }
+
+    pub fn with_memlock_policy(memlock_policy: MemlockPolicy) -> Self {
+        Self {
+            memlock_policy,
+            backend: AllocBackend::default(),
+            max_alloc_size: None,
+            #[cfg(test)]
+            lock: memsec_lock,
+        }
+    }
+
+    /// Create an allocator that always uses the given [AllocBackend]
+    ///
+    /// [MemsecAllocator::new] uses [AllocBackend::Memsec]; forcing a backend is mostly
+    /// useful for tests and deployments that require a specific mechanism. Allocations
+    /// fail if the backend is not available, see [AllocBackend::is_available].
+    pub fn with_backend(backend: AllocBackend) -> Self {
+        Self {
+            backend,
+            ..Self::new()
+        }
+    }
+
+    /// Fail allocations larger than `max` bytes
+    ///
+    /// Guards against size calculation bugs requesting huge amounts of locked memory,
+    /// which could exhaust the process' `RLIMIT_MEMLOCK` budget or the system's memory.
+    /// By default, allocation sizes are not limited.
+    pub fn limit_alloc_size(self, max: usize) -> Self {
         Self {
-            _dummy_private_data: MemsecAllocatorContents,
+            max_alloc_size: Some(max),
+            ..self
+        }
+    }
+
+    pub fn memlock_policy(&self) -> MemlockPolicy {
+        self.memlock_policy
+    }
+
+    pub fn backend(&self) -> AllocBackend {
+        self.backend
+    }
+
+    /// The limit set by [Self::limit_alloc_size], if any
+    pub fn max_alloc_size(&self) -> Option<usize> {
+        self.max_alloc_size
+    }
+
+    /// Verify that the allocation is locked, applying the [MemlockPolicy] if it is not
+    fn ensure_locked(&self, layout: &Layout, mem: NonNull<[u8]>) -> Result<(), AllocError> {
+        use io::ErrorKind as K;
+
+        #[cfg(test)]
+        let lock = self.lock;
+        #[cfg(not(test))]
+        let lock: LockFn = memsec_lock;
+
+        let mut attempts = 0;
+        let err = loop {
+            attempts += 1;
+            match unsafe { lock(mem) } {
+                Ok(()) => return Ok(()),
+                // EINTR; try again
+                Err(e) if e.kind() == K::Interrupted && attempts < LOCK_RETRIES => continue,
+                Err(e) => break e,
+            }
+        };
+
+        // mlock(2) fails with these when the RLIMIT_MEMLOCK budget is exhausted
+        let budget_exceeded = matches!(
+            err.kind(),
+            K::WouldBlock | K::OutOfMemory | K::PermissionDenied
+        );
+        // ENOSYS; the platform does not support locking memory at all
+        let unsupported = err.kind() == K::Unsupported;
+
+        if (budget_exceeded || unsupported) && self.memlock_policy == MemlockPolicy::AllowUnlocked {
+            UNLOCKED_WARNING.call_once(|| {
+                let reason = match unsupported {
+                    true => "locking memory is not supported on this system",
+                    false => "the memory lock limit (RLIMIT_MEMLOCK) is probably exhausted",
+                };
+                log::warn!(
+                    "WARNING: Could not lock secret memory ({err}); {reason}. Continuing with \
+                    unlocked memory as configured. SECRETS MAY BE SWAPPED TO DISK."
+                );
+            });
+            log::debug!("Allocation {layout:?} is not locked into memory: {err}");
+            return Ok(());
         }
+
+        // Name the errno so unexpected failures can be diagnosed
+        let errno = err
+            .raw_os_error()
+            .map(|n| format!("errno {n}"))
+            .unwrap_or_else(|| "no errno".to_string());
+        log::error!(
+            "Allocation {layout:?} was requested but the memory could not be locked: \
+            {err} ({:?}, {errno})",
+            err.kind()
+        );
+        Err(AllocError)
     }
 }
 
-unsafe impl Allocator for MemsecAllocator {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+impl Default for MemsecAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lock the memory; memsec already tries this during allocation but ignores failures
+pub(crate) unsafe fn memsec_lock(mem: NonNull<[u8]>) -> io::Result<()> {
+    let len = mem.len();
+    match unsafe { memsec::mlock(mem.as_ptr() as *mut u8, len) } {
+        true => Ok(()),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The whole pages backing an allocation for the [AllocBackend::MemfdSecret] and
+/// [AllocBackend::Mlock] backends
+fn page_layout(layout: &Layout) -> Result<Layout, AllocError> {
+    let page = page_size();
+    let size = layout.size().max(1).checked_next_multiple_of(page);
+    size.and_then(|size| Layout::from_size_align(size, page.max(layout.align())).ok())
+        .ok_or(AllocError)
+}
+
+impl MemsecAllocator {
+    fn allocate_memsec(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         // Call memsec allocator
         let mem: Option<NonNull<[u8]>> = unsafe { memsec::malloc_sized(layout.size()) };
 
@@ -56,16 +258,155 @@ unsafe impl Allocator for MemsecAllocator {
             return Err(AllocError);
         };
 
+        if let Err(e) = self.ensure_locked(&layout, mem) {
+            unsafe { memsec::free(mem) };
+            return Err(e);
+        }
+
         Ok(mem)
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
-        unsafe {
-            memsec::free(ptr);
+    #[cfg(target_os = "linux")]
+    fn allocate_memfd_secret(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let pages = page_layout(&layout)?;
+        if pages.align() > page_size() {
+            log::error!(
+                "Allocation {layout:?} was requested but memfd_secret memory is only page aligned"
+            );
+            return Err(AllocError);
+        }
+
+        // Secret memory is implicitly locked and never swapped; the memlock policy does not apply
+        match super::sys::mmap_secret(pages.size()) {
+            Ok(mem) => Ok(NonNull::slice_from_raw_parts(mem, layout.size())),
+            Err(e) => {
+                log::error!("Allocation {layout:?} was requested but memfd_secret failed: {e}");
+                Err(AllocError)
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn allocate_memfd_secret(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        log::error!(
+            "Allocation {layout:?} was requested but memfd_secret is only available on Linux"
+        );
+        Err(AllocError)
+    }
+
+    fn allocate_mlock(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let pages = page_layout(&layout)?;
+        let Some(ptr) = NonNull::new(unsafe { std::alloc::alloc_zeroed(pages) }) else {
+            log::error!("Allocation {layout:?} was requested but the system allocator failed");
+            return Err(AllocError);
+        };
+
+        // Lock whole pages, so unlocking on free can not unlock memory of other allocations
+        let locked = NonNull::slice_from_raw_parts(ptr, pages.size());
+        if let Err(e) = self.ensure_locked(&layout, locked) {
+            unsafe { std::alloc::dealloc(ptr.as_ptr(), pages) };
+            return Err(e);
         }
+
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 }
 
+unsafe impl Allocator for MemsecAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(max) = self.max_alloc_size.filter(|&max| layout.size() > max) {
+            log::error!("Allocation {layout:?} was requested but exceeds the limit of {max} bytes");
+            return Err(AllocError);
+        }
+
+        match self.backend {
+            AllocBackend::Memsec => self.allocate_memsec(layout),
+            AllocBackend::MemfdSecret => self.allocate_memfd_secret(layout),
+            AllocBackend::Mlock => self.allocate_mlock(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.backend {
+            AllocBackend::Memsec => unsafe { memsec::free(ptr) },
+            AllocBackend::MemfdSecret => {
+                // Allocation succeeded, so the page layout is valid
+                let pages = page_layout(&layout).unwrap();
+                unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), pages.size()) }.zeroize();
+                #[cfg(target_os = "linux")]
+                if let Err(e) = unsafe { super::sys::munmap(ptr, pages.size()) } {
+                    log::error!("Could not unmap secret memory of allocation {layout:?}: {e}");
+                }
+            }
+            AllocBackend::Mlock => {
+                let pages = page_layout(&layout).unwrap();
+                // Zeroizes the memory before unlocking it; fails harmlessly if it was never locked
+                unsafe { memsec::munlock(ptr.as_ptr(), pages.size()) };
+                unsafe { std::alloc::dealloc(ptr.as_ptr(), pages) };
+            }
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { reallocate_wiping(self, ptr, old_layout, new_layout, false) }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { reallocate_wiping(self, ptr, old_layout, new_layout, true) }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { reallocate_wiping(self, ptr, old_layout, new_layout, false) }
+    }
+}
+
+/// Move an allocation into a new one of a different size, wiping the old memory
+///
+/// The default `grow` and `shrink` implementations copy the data and free the old
+/// allocation, relying on the allocator to clean up. This zeroizes the old region
+/// right after copying, before handing it back to the allocator. With `zero_tail`,
+/// memory in the new allocation beyond the copied data is zeroed.
+///
+/// # Safety
+///
+/// Same requirements as [Allocator::grow] and [Allocator::shrink].
+unsafe fn reallocate_wiping<A: Allocator + ?Sized>(
+    alloc: &A,
+    ptr: NonNull<u8>,
+    old_layout: Layout,
+    new_layout: Layout,
+    zero_tail: bool,
+) -> Result<NonNull<[u8]>, AllocError> {
+    let new = alloc.allocate(new_layout)?;
+    let new_ptr = new.as_ptr() as *mut u8;
+    let len = old_layout.size().min(new_layout.size());
+
+    unsafe { ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr, len) };
+    if zero_tail {
+        unsafe { slice::from_raw_parts_mut(new_ptr.add(len), new.len() - len) }.zeroize();
+    }
+
+    unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), old_layout.size()) }.zeroize();
+    unsafe { alloc.deallocate(ptr, old_layout) };
+
+    Ok(new)
+}
+
 impl fmt::Debug for MemsecAllocator {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_str("<memsec based Rust allocator>")
@@ -74,6 +415,9 @@ impl fmt::Debug for MemsecAllocator {
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use std::sync::Mutex;
+
     use allocator_api2_tests::make_test;
 
     use super::*;
@@ -82,6 +426,13 @@ mod test {
     make_test! { test_vec(MemsecAllocator::new()) }
     make_test! { test_many_boxes(MemsecAllocator::new()) }
 
+    mod mlock_backend {
+        use super::*;
+
+        make_test! { test_sizes(MemsecAllocator::with_backend(AllocBackend::Mlock)) }
+        make_test! { test_vec(MemsecAllocator::with_backend(AllocBackend::Mlock)) }
+    }
+
     #[test]
     fn memsec_allocation() {
         let alloc = MemsecAllocator::new();
@@ -105,4 +456,284 @@ mod test {
         let mem = NonNull::new(mem.as_ptr() as *mut u8).unwrap();
         unsafe { alloc.deallocate(mem, layout) };
     }
+
+    unsafe fn lock_budget_exceeded(_mem: NonNull<[u8]>) -> io::Result<()> {
+        // mlock(2) returns EAGAIN if some of the memory could not be locked
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    unsafe fn lock_invalid(_mem: NonNull<[u8]>) -> io::Result<()> {
+        Err(io::ErrorKind::InvalidInput.into())
+    }
+
+    unsafe fn lock_unsupported(_mem: NonNull<[u8]>) -> io::Result<()> {
+        // ENOSYS
+        Err(io::Error::from_raw_os_error(38))
+    }
+
+    unsafe fn lock_unexpected_errno(_mem: NonNull<[u8]>) -> io::Result<()> {
+        // ENOTRECOVERABLE; not something mlock(2) is documented to return
+        Err(io::Error::from_raw_os_error(131))
+    }
+
+    thread_local! {
+        static INTERRUPTIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe fn lock_interrupted_twice(_mem: NonNull<[u8]>) -> io::Result<()> {
+        INTERRUPTIONS.with(|n| match n.get() {
+            2 => Ok(()),
+            k => {
+                n.set(k + 1);
+                Err(io::Error::from_raw_os_error(4)) // EINTR
+            }
+        })
+    }
+
+    unsafe fn lock_always_interrupted(_mem: NonNull<[u8]>) -> io::Result<()> {
+        INTERRUPTIONS.with(|n| n.set(n.get() + 1));
+        Err(io::ErrorKind::Interrupted.into())
+    }
+
+    static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOG.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+
+    fn allocator_with_lock(memlock_policy: MemlockPolicy, lock: LockFn) -> MemsecAllocator {
+        MemsecAllocator {
+            memlock_policy,
+            backend: AllocBackend::Memsec,
+            max_alloc_size: None,
+            lock,
+        }
+    }
+
+    #[test]
+    fn memlock_failure_required() {
+        let alloc = allocator_with_lock(MemlockPolicy::Require, lock_budget_exceeded);
+        assert!(alloc.allocate(Layout::new::<[u8; 32]>()).is_err());
+    }
+
+    #[test]
+    fn memlock_failure_allow_unlocked() {
+        let alloc = allocator_with_lock(MemlockPolicy::AllowUnlocked, lock_budget_exceeded);
+        let layout = Layout::new::<[u8; 32]>();
+        let mem = alloc.allocate(layout).unwrap();
+        assert_eq!(unsafe { mem.as_ref() }, &[0xD0u8; 32]);
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+
+        // Only running out of lock budget is tolerated
+        let alloc = allocator_with_lock(MemlockPolicy::AllowUnlocked, lock_invalid);
+        assert!(alloc.allocate(layout).is_err());
+    }
+
+    #[test]
+    fn memlock_retries_interrupted() {
+        let layout = Layout::new::<[u8; 32]>();
+
+        INTERRUPTIONS.with(|n| n.set(0));
+        let alloc = allocator_with_lock(MemlockPolicy::Require, lock_interrupted_twice);
+        let mem = alloc.allocate(layout).unwrap();
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+        assert_eq!(INTERRUPTIONS.with(Cell::get), 2);
+
+        INTERRUPTIONS.with(|n| n.set(0));
+        let alloc = allocator_with_lock(MemlockPolicy::Require, lock_always_interrupted);
+        assert!(alloc.allocate(layout).is_err());
+        assert_eq!(INTERRUPTIONS.with(Cell::get), LOCK_RETRIES);
+    }
+
+    #[test]
+    fn memlock_unsupported_falls_back() {
+        let layout = Layout::new::<[u8; 32]>();
+        let alloc = allocator_with_lock(MemlockPolicy::AllowUnlocked, lock_unsupported);
+        let mem = alloc.allocate(layout).unwrap();
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+
+        let alloc = allocator_with_lock(MemlockPolicy::Require, lock_unsupported);
+        assert!(alloc.allocate(layout).is_err());
+    }
+
+    #[test]
+    fn memlock_unexpected_errno_is_logged() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let alloc = allocator_with_lock(MemlockPolicy::AllowUnlocked, lock_unexpected_errno);
+        assert!(alloc.allocate(Layout::new::<[u8; 32]>()).is_err());
+        assert!(LOG
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.contains("could not be locked") && m.contains("errno 131")));
+    }
+
+    #[test]
+    fn alloc_size_limit() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let alloc = MemsecAllocator::new().limit_alloc_size(64);
+        assert_eq!(alloc.max_alloc_size(), Some(64));
+        assert_eq!(MemsecAllocator::new().max_alloc_size(), None);
+
+        let layout = Layout::new::<[u8; 64]>();
+        let mem = alloc.allocate(layout).unwrap();
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+
+        assert!(alloc.allocate(Layout::new::<[u8; 65]>()).is_err());
+        assert!(alloc
+            .allocate(Layout::array::<u8>(isize::MAX as usize).unwrap())
+            .is_err());
+        assert!(LOG
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.contains("exceeds the limit of 64 bytes")));
+
+        // Growing past the limit fails as well
+        let mut v = MemsecVec::<u8>::new_in(alloc);
+        v.extend_from_slice(&[1u8; 64]);
+        assert!(v.try_reserve_exact(1).is_err());
+    }
+
+    /// Global allocator checking that memory is wiped before being freed
+    #[derive(Default)]
+    struct WipeCheckingAllocator {
+        unwiped_frees: Cell<usize>,
+    }
+
+    unsafe impl Allocator for WipeCheckingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            allocator_api2::alloc::Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let mem = unsafe { slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+            if mem.iter().any(|&b| b != 0) {
+                self.unwiped_frees.set(self.unwiped_frees.get() + 1);
+            }
+            unsafe { allocator_api2::alloc::Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn reallocation_wipes_old_memory() {
+        let alloc = WipeCheckingAllocator::default();
+        let small = Layout::new::<[u8; 16]>();
+        let large = Layout::new::<[u8; 64]>();
+
+        let mem = alloc.allocate(small).unwrap();
+        unsafe { (mem.as_ptr() as *mut u8).write_bytes(0xab, 16) };
+
+        let mem = unsafe { reallocate_wiping(&alloc, mem.cast(), small, large, true) }.unwrap();
+        let data = unsafe { mem.as_ref() };
+        assert_eq!(&data[..16], &[0xab; 16]);
+        assert_eq!(&data[16..], &[0u8; 48]);
+
+        let mem = unsafe { reallocate_wiping(&alloc, mem.cast(), large, small, false) }.unwrap();
+        assert_eq!(unsafe { mem.as_ref() }, &[0xab; 16]);
+        assert_eq!(alloc.unwiped_frees.get(), 0);
+
+        unsafe { (mem.as_ptr() as *mut u8).write_bytes(0, 16) };
+        unsafe { alloc.deallocate(mem.cast(), small) };
+    }
+
+    #[test]
+    fn memsec_grow_and_shrink() {
+        let mut v = memsec_vec::<u8>();
+        v.extend_from_slice(&[1u8; 16]);
+        v.shrink_to_fit();
+        v.extend_from_slice(&[2u8; 1000]);
+        assert_eq!(&v[..16], &[1u8; 16]);
+        assert_eq!(&v[16..], &[2u8; 1000]);
+
+        v.truncate(8);
+        v.shrink_to_fit();
+        assert_eq!(v.capacity(), 8);
+        assert_eq!(&v[..], &[1u8; 8]);
+
+        let alloc = MemsecAllocator::new();
+        let small = Layout::new::<[u8; 8]>();
+        let large = Layout::new::<[u8; 32]>();
+        let mem = alloc.allocate(small).unwrap();
+        let mem = unsafe { alloc.grow_zeroed(mem.cast(), small, large) }.unwrap();
+        assert_eq!(&unsafe { mem.as_ref() }[8..], &[0u8; 24]);
+        unsafe { alloc.deallocate(mem.cast(), large) };
+    }
+
+    #[test]
+    fn memlock_default_policy() {
+        // Like memsec, tolerate locking failures unless strict locking was requested
+        assert_eq!(MemlockPolicy::default(), MemlockPolicy::AllowUnlocked);
+        assert_eq!(default_memlock_policy(), MemlockPolicy::AllowUnlocked);
+        assert_eq!(
+            MemsecAllocator::new().memlock_policy(),
+            default_memlock_policy()
+        );
+        assert_eq!(
+            MemsecAllocator::with_memlock_policy(MemlockPolicy::AllowUnlocked).memlock_policy(),
+            MemlockPolicy::AllowUnlocked
+        );
+    }
+
+    #[test]
+    fn allocate_through_each_backend() {
+        let backends = [
+            AllocBackend::Memsec,
+            AllocBackend::MemfdSecret,
+            AllocBackend::Mlock,
+        ];
+        // The tests may run with a small RLIMIT_MEMLOCK
+        let policy = MemlockPolicy::AllowUnlocked;
+
+        for backend in backends.into_iter().filter(|b| b.is_available()) {
+            let alloc = MemsecAllocator {
+                backend,
+                ..MemsecAllocator::with_memlock_policy(policy)
+            };
+            assert_eq!(alloc.backend(), backend);
+
+            for size in [0, 1, 32, 4096, 10000] {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                let mem = alloc.allocate(layout).unwrap();
+                assert_eq!(mem.len(), size);
+                unsafe { (mem.as_ptr() as *mut u8).write_bytes(0xab, size) };
+                unsafe { alloc.deallocate(mem.cast(), layout) };
+            }
+
+            let mut v = MemsecVec::new_in(alloc);
+            v.extend_from_slice(&[7u8; 100]);
+            v.extend_from_slice(&[8u8; 5000]);
+            assert_eq!(&v[..100], &[7u8; 100]);
+            assert_eq!(&v[100..], &[8u8; 5000]);
+        }
+    }
+
+    #[test]
+    fn backend_selection() {
+        assert_eq!(MemsecAllocator::new().backend(), AllocBackend::Memsec);
+        let alloc = MemsecAllocator::with_backend(AllocBackend::Mlock);
+        assert_eq!(alloc.backend(), AllocBackend::Mlock);
+        assert_eq!(alloc.memlock_policy(), default_memlock_policy());
+
+        if !AllocBackend::MemfdSecret.is_available() {
+            let alloc = MemsecAllocator::with_backend(AllocBackend::MemfdSecret);
+            assert!(alloc.allocate(Layout::new::<[u8; 32]>()).is_err());
+        }
+    }
 }
@@ -1,6 +1,17 @@
+pub mod hybrid;
 pub mod memsec;
+pub mod support;
+#[cfg(target_os = "linux")]
+pub mod sys;
 
+pub use crate::alloc::hybrid::{
+    hybrid_box, hybrid_vec, HybridAllocator, HybridBox, HybridVec, DEFAULT_SECRET_THRESHOLD,
+};
+pub use crate::alloc::memsec::{
+    default_memlock_policy, set_default_memlock_policy, AllocBackend, MemlockPolicy,
+};
 pub use crate::alloc::memsec::{
     memsec_box as secret_box, memsec_vec as secret_vec, MemsecAllocator as SecretAllocator,
     MemsecBox as SecretBox, MemsecVec as SecretVec,
 };
+pub use crate::alloc::support::{secret_memory_supported, SecretMemorySupport};
@@ -0,0 +1,98 @@
+//! Probing which protections for secret memory the system offers
+
+use std::sync::OnceLock;
+
+use crate::alloc::memsec::memsec_lock;
+
+/// Level of protection available to secret memory on this system
+///
+/// Levels are ordered from the weakest to the strongest protection, so callers can
+/// require a minimum level with a comparison.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SecretMemorySupport {
+    /// Memory can not be locked into RAM; secrets may be swapped to disk. Allocations
+    /// are still guarded, excluded from core dumps, and zeroized on free.
+    Unlocked,
+    /// Memory can be locked into RAM with `mlock(2)`
+    Locked,
+    /// Memory can be locked and `memfd_secret(2)` is available, which removes secret
+    /// pages from the kernel's direct map
+    MemfdSecret,
+}
+
+static SUPPORT: OnceLock<SecretMemorySupport> = OnceLock::new();
+
+/// The level of protection available to secret memory
+///
+/// The system is probed on the first call; later calls return the cached result. Note
+/// that locking may still fail later on, once the `RLIMIT_MEMLOCK` budget is exhausted.
+pub fn secret_memory_supported() -> SecretMemorySupport {
+    *SUPPORT.get_or_init(probe)
+}
+
+fn probe() -> SecretMemorySupport {
+    use SecretMemorySupport as S;
+    match (probe_mlock(), probe_memfd_secret()) {
+        (false, _) => S::Unlocked,
+        (true, false) => S::Locked,
+        (true, true) => S::MemfdSecret,
+    }
+}
+
+fn probe_mlock() -> bool {
+    let Some(mem) = (unsafe { memsec::malloc_sized(1) }) else {
+        return false;
+    };
+    // memsec allocates whole pages, so this does not affect other allocations
+    let locked = unsafe { memsec_lock(mem) };
+    unsafe { memsec::free(mem) };
+    if let Err(e) = &locked {
+        log::debug!("Secret memory can not be locked: {e}");
+    }
+    locked.is_ok()
+}
+
+#[cfg(target_os = "linux")]
+fn probe_memfd_secret() -> bool {
+    match crate::alloc::sys::memfd_secret() {
+        Ok(_fd) => true,
+        Err(e) => {
+            log::debug!("memfd_secret is not available: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_memfd_secret() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secret_memory_support_probe() {
+        let support = secret_memory_supported();
+        assert_eq!(secret_memory_supported(), support, "Probe result is cached");
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            support == SecretMemorySupport::MemfdSecret,
+            probe_mlock() && crate::alloc::sys::memfd_secret().is_ok()
+        );
+        #[cfg(not(target_os = "linux"))]
+        assert!(support < SecretMemorySupport::MemfdSecret);
+
+        // The unlocked fallback is only reported if locking actually fails
+        assert_eq!(support == SecretMemorySupport::Unlocked, !probe_mlock());
+    }
+
+    #[test]
+    fn secret_memory_support_ordering() {
+        use SecretMemorySupport as S;
+        assert!(S::Unlocked < S::Locked);
+        assert!(S::Locked < S::MemfdSecret);
+    }
+}
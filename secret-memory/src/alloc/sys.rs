@@ -0,0 +1,203 @@
+//! Raw system calls for secret memory that libc does not wrap
+//!
+//! libc only defines `SYS_memfd_secret` for some targets and versions, so the syscall
+//! numbers are kept here, per architecture. On architectures where the kernel does not
+//! implement `memfd_secret`, [memfd_secret] fails at runtime with
+//! [io::ErrorKind::Unsupported] and callers have to fall back to other mechanisms.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::{self, NonNull};
+
+/// Syscall number of `memfd_secret(2)`, if the kernel implements it on this architecture
+#[cfg(all(target_arch = "x86_64", target_pointer_width = "64"))]
+pub const SYS_MEMFD_SECRET: Option<libc::c_long> = Some(447);
+
+/// Syscall number of `memfd_secret(2)`, if the kernel implements it on this architecture
+///
+/// The x32 ABI marks its syscall numbers with `__X32_SYSCALL_BIT`.
+#[cfg(all(target_arch = "x86_64", target_pointer_width = "32"))]
+pub const SYS_MEMFD_SECRET: Option<libc::c_long> = Some(0x4000_0000 + 447);
+
+/// Syscall number of `memfd_secret(2)`, if the kernel implements it on this architecture
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "loongarch64",
+))]
+pub const SYS_MEMFD_SECRET: Option<libc::c_long> = Some(447);
+
+/// Syscall number of `memfd_secret(2)`, if the kernel implements it on this architecture
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "loongarch64",
+)))]
+pub const SYS_MEMFD_SECRET: Option<libc::c_long> = None;
+
+/// Create a file descriptor for secret memory; see `memfd_secret(2)`
+///
+/// Pages mapped from the descriptor are removed from the kernel's direct map, so they
+/// are inaccessible to other processes and, mostly, to the kernel itself. The descriptor
+/// is created with `O_CLOEXEC`.
+///
+/// Fails with [io::ErrorKind::Unsupported] if the syscall is not available on this
+/// architecture or kernel.
+pub fn memfd_secret() -> io::Result<OwnedFd> {
+    let unsupported = || {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memfd_secret is not supported on this system",
+        )
+    };
+    let nr = SYS_MEMFD_SECRET.ok_or_else(unsupported)?;
+
+    let fd = unsafe { libc::syscall(nr, libc::O_CLOEXEC) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) => Err(unsupported()),
+            _ => Err(err),
+        };
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Map `len` bytes of secret memory backed by a fresh [memfd_secret] descriptor
+///
+/// `len` must be a non-zero multiple of the page size. The mapping stays valid after
+/// the descriptor is closed; it is zero-initialized and must be released with [munmap].
+pub fn mmap_secret(len: usize) -> io::Result<NonNull<u8>> {
+    let fd = memfd_secret()?;
+    let size = libc::off_t::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "mapping too large"))?;
+    if unsafe { libc::ftruncate(fd.as_raw_fd(), size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let mem = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            prot,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if mem == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(NonNull::new(mem as *mut u8).expect("mmap(2) never returns a null mapping"))
+}
+
+/// Release a mapping created by [mmap_secret]
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a mapping returned by [mmap_secret] that is no longer used.
+pub unsafe fn munmap(ptr: NonNull<u8>, len: usize) -> io::Result<()> {
+    match unsafe { libc::munmap(ptr.as_ptr() as *mut libc::c_void, len) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Check [SYS_MEMFD_SECRET] against the definition in libc, where libc has one
+    macro_rules! syscall_number_matches_libc {
+        ($($name:ident: $arch:literal),* $(,)?) => {
+            $(
+                #[cfg(target_arch = $arch)]
+                #[test]
+                fn $name() {
+                    assert_eq!(SYS_MEMFD_SECRET, Some(libc::SYS_memfd_secret));
+                }
+            )*
+        };
+    }
+
+    syscall_number_matches_libc! {
+        syscall_number_x86_64: "x86_64",
+        syscall_number_x86: "x86",
+        syscall_number_aarch64: "aarch64",
+        syscall_number_s390x: "s390x",
+    }
+
+    #[cfg(all(target_arch = "riscv64", target_env = "gnu"))]
+    #[test]
+    fn syscall_number_riscv64() {
+        assert_eq!(SYS_MEMFD_SECRET, Some(libc::SYS_memfd_secret));
+    }
+
+    #[cfg(all(target_arch = "riscv32", target_env = "gnu"))]
+    #[test]
+    fn syscall_number_riscv32() {
+        assert_eq!(SYS_MEMFD_SECRET, Some(libc::SYS_memfd_secret));
+    }
+
+    #[cfg(target_arch = "loongarch64")]
+    #[test]
+    fn syscall_number_loongarch64() {
+        // Uses the generic syscall table, like aarch64 and riscv
+        assert_eq!(SYS_MEMFD_SECRET, Some(447));
+    }
+
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "s390x",
+        target_arch = "loongarch64",
+    )))]
+    #[test]
+    fn memfd_secret_unsupported_arch() {
+        assert_eq!(SYS_MEMFD_SECRET, None);
+        let err = memfd_secret().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn memfd_secret_available_or_unsupported() {
+        // Kernels may lack memfd_secret or have it disabled (secretmem.enable=0);
+        // sandboxes commonly refuse unknown syscalls with EPERM
+        match memfd_secret() {
+            Ok(_fd) => {}
+            Err(e) => assert!(
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied
+                ),
+                "Unexpected error from memfd_secret: {e:?}"
+            ),
+        }
+    }
+
+    #[test]
+    fn mmap_secret_round_trip() {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let Ok(mem) = mmap_secret(page) else {
+            // Covered by memfd_secret_available_or_unsupported
+            return;
+        };
+        let data = unsafe { std::slice::from_raw_parts_mut(mem.as_ptr(), page) };
+        assert!(data.iter().all(|&b| b == 0));
+        data.fill(0xab);
+        unsafe { munmap(mem, page) }.unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 pub mod debug;
 pub mod file;
 pub mod rand;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub mod alloc;
 
@@ -9,3 +11,6 @@ pub use crate::public::Public;
 
 mod secret;
 pub use crate::secret::Secret;
+
+mod string;
+pub use crate::string::SecretString;
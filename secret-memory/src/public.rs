@@ -1,5 +1,5 @@
 use crate::debug::debug_crypto_array;
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use rand::{Fill as Randomize, Rng};
 use rosenpass_to::{ops::copy_slice, To};
 use rosenpass_util::b64::{b64_decode, b64_encode};
@@ -13,6 +13,7 @@ use std::fmt;
 use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
+use std::str::FromStr;
 
 /// Contains information in the form of a byte array that may be known to the
 /// public
@@ -48,6 +49,15 @@ impl<const N: usize> Public<N> {
     pub fn randomize(&mut self) {
         self.try_fill(&mut crate::rand::rng()).unwrap()
     }
+
+    /// Compare two [Public]s in constant time
+    ///
+    /// The derived [PartialEq] may return as soon as the first differing byte is found.
+    /// Use this function instead when matching identifiers (e.g. peer ids) in lookup paths,
+    /// where the timing of the comparison could leak which entry matched.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        rosenpass_constant_time::memcmp(&self.value, &other.value)
+    }
 }
 
 impl<const N: usize> Randomize for Public<N> {
@@ -62,6 +72,42 @@ impl<const N: usize> fmt::Debug for Public<N> {
     }
 }
 
+/// Formats the value as lowercase hexadecimal
+impl<const N: usize> fmt::Display for Public<N> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.value {
+            write!(fmt, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses exactly `2 * N` hexadecimal digits
+impl<const N: usize> FromStr for Public<N> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        ensure!(
+            s.len() == 2 * N,
+            "Expected {} hexadecimal digits, got {} characters",
+            2 * N,
+            s.len()
+        );
+        ensure!(
+            s.bytes().all(|c| c.is_ascii_hexdigit()),
+            "Invalid hexadecimal digit in {s:?}"
+        );
+
+        let mut v = Self::zero();
+        for (byte, digits) in v.value.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+            // Digits were checked to be ASCII hex digits above
+            let digits = std::str::from_utf8(digits).unwrap();
+            *byte = u8::from_str_radix(digits, 16).unwrap();
+        }
+        Ok(v)
+    }
+}
+
 impl<const N: usize> Deref for Public<N> {
     type Target = [u8; N];
 
@@ -188,6 +234,44 @@ mod tests {
         use std::{fs, os::unix::fs::PermissionsExt};
         use tempfile::tempdir;
 
+        /// test formatting a public as hex and parsing it back
+        #[test]
+        fn test_public_hex_roundtrip() {
+            let public = Public::new([0x00, 0x01, 0xab, 0xff]);
+            assert_eq!(public.to_string(), "0001abff");
+            assert_eq!("0001abff".parse::<Public<4>>().unwrap(), public);
+            assert_eq!("0001ABFF".parse::<Public<4>>().unwrap(), public);
+
+            let public = Public::<32>::random();
+            assert_eq!(public.to_string().parse::<Public<32>>().unwrap(), public);
+        }
+
+        /// test constant time comparison
+        #[test]
+        fn test_public_ct_eq() {
+            let a = Public::new([0x00, 0x01, 0xab, 0xff]);
+            assert!(a.ct_eq(&a));
+            assert!(a.ct_eq(&Public::new([0x00, 0x01, 0xab, 0xff])));
+            assert!(!a.ct_eq(&Public::new([0x00, 0x01, 0xab, 0xfe])));
+            assert!(!a.ct_eq(&Public::new([0x80, 0x01, 0xab, 0xff])));
+            assert!(Public::<0>::zero().ct_eq(&Public::zero()));
+
+            let b = Public::<32>::random();
+            let c = Public::<32>::random();
+            assert_eq!(b.ct_eq(&c), b == c);
+        }
+
+        /// test that malformed hex is rejected
+        #[test]
+        fn test_public_hex_malformed() {
+            assert!("0001abf".parse::<Public<4>>().is_err());
+            assert!("0001abff00".parse::<Public<4>>().is_err());
+            assert!("0001abfg".parse::<Public<4>>().is_err());
+            assert!("+001abff".parse::<Public<4>>().is_err());
+            assert!("00ü1abf".parse::<Public<4>>().is_err());
+            assert!("".parse::<Public<4>>().is_err());
+        }
+
         /// test loading a public from an example file, and then storing it again in a different file
         #[test]
         fn test_public_load_store() {
@@ -7,8 +7,11 @@ use std::path::Path;
 
 use anyhow::Context;
 use rand::{Fill as Randomize, Rng};
+use subtle::{Choice, ConditionallySelectable};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+use rosenpass_constant_time::xor;
+use rosenpass_to::To;
 use rosenpass_util::b64::{b64_decode, b64_encode};
 use rosenpass_util::file::{
     fopen_r, LoadValue, LoadValueB64, ReadExactToEnd, ReadSliceToEnd, StoreValueB64,
@@ -49,9 +52,15 @@ where
 }
 
 // Wrapper around SecretBox that applies automatic zeroization
-#[derive(Debug)]
 struct ZeroizingSecretBox<T: Zeroize + ?Sized>(Option<SecretBox<T>>);
 
+/// Like the [Debug](fmt::Debug) implementation of [Secret], this never prints the contents
+impl<T: Zeroize + ?Sized> fmt::Debug for ZeroizingSecretBox<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("ZeroizingSecretBox([redacted])")
+    }
+}
+
 impl<T: Zeroize> ZeroizingSecretBox<T> {
     fn new(boxed: T) -> Self {
         ZeroizingSecretBox(Some(secret_box(boxed)))
@@ -103,7 +112,7 @@ impl<T: Zeroize + ?Sized> DerefMut for ZeroizingSecretBox<T> {
 /// Allocation of secret memory is expensive. Thus, this struct provides a
 /// pool of secret memory, readily available to yield protected, slices of
 /// memory.
-#[derive(Debug)] // TODO check on Debug derive, is that clever
+#[derive(Debug)] // Pooled secrets are redacted, see ZeroizingSecretBox
 struct SecretMemoryPool {
     pool: HashMap<usize, Vec<ZeroizingSecretBox<[u8]>>>,
 }
@@ -146,6 +155,16 @@ impl SecretMemoryPool {
     }
 }
 
+/// Compile time check that a secret of `N` bytes splits into parts of `A` and `B` bytes
+struct AssertSplit<const N: usize, const A: usize, const B: usize>;
+
+impl<const N: usize, const A: usize, const B: usize> AssertSplit<N, A, B> {
+    const OK: () = assert!(
+        A + B == N,
+        "Split sizes must add up to the size of the secret"
+    );
+}
+
 /// Storage for secret data
 pub struct Secret<const N: usize> {
     storage: Option<ZeroizingSecretBox<[u8; N]>>,
@@ -189,6 +208,68 @@ impl<const N: usize> Secret<N> {
     pub fn secret_mut(&mut self) -> &mut [u8; N] {
         self.storage.as_mut().unwrap()
     }
+
+    /// Runs the given closure on the mutably borrowed data
+    ///
+    /// The borrow can not outlive the closure; any scratch values the closure
+    /// creates from the secret are the closure's responsibility to zeroize.
+    pub fn with_secret_mut<R, F: FnOnce(&mut [u8; N]) -> R>(&mut self, f: F) -> R {
+        f(self.secret_mut())
+    }
+
+    /// Creates a new [Secret] from this and another secret
+    ///
+    /// The closure receives both inputs and writes its result into the new,
+    /// zero-initialized secret; e.g. to XOR two keys.
+    pub fn combine<const M: usize, F>(&self, other: &Secret<M>, f: F) -> Self
+    where
+        F: FnOnce(&[u8; N], &[u8; M], &mut [u8; N]),
+    {
+        let mut r = Self::zero();
+        f(self.secret(), other.secret(), r.secret_mut());
+        r
+    }
+
+    /// XORs another secret of the same length into this one
+    ///
+    /// Runs in constant time, see [rosenpass_constant_time::xor], and works in place
+    /// without intermediate copies. Applying the same secret twice restores the original.
+    ///
+    /// Both secrets must have the same length:
+    ///
+    /// ```compile_fail
+    /// use rosenpass_secret_memory::Secret;
+    /// let mut a = Secret::<32>::zero();
+    /// a.xor_assign(&Secret::<16>::zero());
+    /// ```
+    pub fn xor_assign(&mut self, other: &Secret<N>) {
+        xor(other.secret()).to(self.secret_mut());
+    }
+
+    /// Splits the secret into two new secrets of `A` and `B` bytes, zeroizing this one
+    ///
+    /// `A + B` must equal `N`; this is checked at compile time. Use this when a derived
+    /// secret holds two separate keys, so the combined secret does not stay around.
+    pub fn split_at<const A: usize, const B: usize>(&mut self) -> (Secret<A>, Secret<B>) {
+        let () = AssertSplit::<N, A, B>::OK;
+        let (a, b) = self.secret().split_at(A);
+        let r = (Secret::from_slice(a), Secret::from_slice(b));
+        self.zeroize();
+        r
+    }
+
+    /// Returns a copy of `a` if `choice` is 0 and a copy of `b` if `choice` is 1
+    ///
+    /// Runs in constant time: every byte is selected with [subtle]'s masking, without
+    /// branching on or indexing by `choice`, so neither timing nor memory access pattern
+    /// reveals which secret was chosen.
+    pub fn conditional_select(a: &Secret<N>, b: &Secret<N>, choice: Choice) -> Self {
+        a.combine(b, |a, b, out| {
+            for ((out, a), b) in out.iter_mut().zip(a).zip(b) {
+                *out = u8::conditional_select(a, b, choice);
+            }
+        })
+    }
 }
 
 impl<const N: usize> Randomize for Secret<N> {
@@ -231,10 +312,10 @@ impl<const N: usize> Clone for Secret<N> {
 }
 
 /// The Debug implementation of [Secret] does not reveal the secret data,
-/// instead a placeholder `<SECRET>` is used
+/// instead a placeholder `Secret<N>([redacted])` is used
 impl<const N: usize> fmt::Debug for Secret<N> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str("<SECRET>")
+        write!(fmt, "Secret<{N}>([redacted])")
     }
 }
 
@@ -325,6 +406,28 @@ mod test {
     use std::{fs, os::unix::fs::PermissionsExt};
     use tempfile::tempdir;
 
+    /// check that Debug output never contains the secret data
+    #[test]
+    fn secret_debug_redacted() {
+        let secret = Secret::<32>::from_slice(&[0xab; 32]);
+        let debug = format!("{secret:?}");
+        assert_eq!(debug, "Secret<32>([redacted])");
+        assert_eq!(format!("{secret:#?}"), debug);
+
+        let mut pool = SecretMemoryPool::new();
+        let mut pooled: ZeroizingSecretBox<[u8; 32]> = pool.take();
+        pooled.copy_from_slice(&[0xab; 32]);
+        let debug = format!("{pooled:?}");
+        pool.release(pooled);
+        let pool_debug = format!("{pool:?}");
+
+        for debug in [debug, pool_debug] {
+            assert!(debug.contains("redacted"));
+            assert!(!debug.contains("ab"));
+            assert!(!debug.contains("171"));
+        }
+    }
+
     /// check that we can alloc using the magic pool
     #[test]
     fn secret_memory_pool_take() {
@@ -363,6 +466,81 @@ mod test {
         assert_eq!(new_secret.as_ref(), &[0; N]);
     }
 
+    /// check that secrets can be combined into a new secret
+    #[test]
+    fn secret_combine_xor() {
+        const N: usize = 32;
+        let mut a = Secret::<N>::random();
+        let b = Secret::<N>::random();
+        a.with_secret_mut(|buf| buf[0] = 0x0f);
+
+        let c = a.combine(&b, |a, b, out| {
+            for ((o, a), b) in out.iter_mut().zip(a.iter()).zip(b.iter()) {
+                *o = a ^ b;
+            }
+        });
+
+        for i in 0..N {
+            assert_eq!(c.secret()[i], a.secret()[i] ^ b.secret()[i]);
+        }
+        assert_eq!(c.secret()[0], 0x0f ^ b.secret()[0]);
+    }
+
+    /// check that XOR-ing a secret in twice restores the original
+    #[test]
+    fn secret_xor_assign() {
+        let orig = Secret::<32>::random();
+        let key = Secret::<32>::random();
+
+        let mut s = orig.clone();
+        s.xor_assign(&key);
+        for i in 0..32 {
+            assert_eq!(s.secret()[i], orig.secret()[i] ^ key.secret()[i]);
+        }
+
+        s.xor_assign(&key);
+        assert_eq!(s.secret(), orig.secret());
+    }
+
+    /// check that splitting a secret copies both halves and wipes the source
+    #[test]
+    fn secret_split_at() {
+        let mut s = Secret::<64>::random();
+        let orig = *s.secret();
+
+        let (a, b): (Secret<32>, Secret<32>) = s.split_at();
+        assert_eq!(a.secret(), &orig[..32]);
+        assert_eq!(b.secret(), &orig[32..]);
+        assert_eq!(s.secret(), &[0u8; 64]);
+
+        let mut s = Secret::<64>::from_slice(&orig);
+        let (a, b) = s.split_at::<16, 48>();
+        assert_eq!(a.secret(), &orig[..16]);
+        assert_eq!(b.secret(), &orig[16..]);
+    }
+
+    /// check that conditional selection picks the right secret for both choices
+    #[test]
+    fn secret_conditional_select() {
+        let a = Secret::<32>::random();
+        let b = Secret::<32>::random();
+        let first = Secret::conditional_select(&a, &b, Choice::from(0));
+        let second = Secret::conditional_select(&a, &b, Choice::from(1));
+        assert_eq!(first.secret(), a.secret());
+        assert_eq!(second.secret(), b.secret());
+
+        // Branch freedom can not be observed from a test; what can be checked is that the
+        // selection is a pure bit mask, correct for every pair of byte values
+        let a = Secret::<256>::from_slice(&std::array::from_fn::<u8, 256, _>(|i| i as u8));
+        for x in 0..=255u8 {
+            let b = Secret::<256>::from_slice(&[x; 256]);
+            let first = Secret::conditional_select(&a, &b, Choice::from(0));
+            let second = Secret::conditional_select(&a, &b, Choice::from(1));
+            assert_eq!(first.secret(), a.secret());
+            assert_eq!(second.secret(), b.secret());
+        }
+    }
+
     /// test loading a secret from an example file, and then storing it again in a different file
     #[test]
     fn test_secret_load_store() {
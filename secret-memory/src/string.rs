@@ -0,0 +1,176 @@
+use std::fmt;
+
+use allocator_api2::alloc::Allocator;
+use zeroize::Zeroize;
+
+use crate::alloc::SecretAllocator;
+
+/// A UTF-8 string in secret memory, e.g. for passphrases
+///
+/// The contents are zeroized when the string is dropped or cleared. When the string
+/// grows, [SecretAllocator] wipes the old memory after copying, so no stray copies of
+/// the passphrase remain; a custom allocator used with [Self::new_in] must do the same.
+pub struct SecretString<A: Allocator = SecretAllocator> {
+    bytes: allocator_api2::vec::Vec<u8, A>,
+}
+
+impl SecretString {
+    pub fn new() -> Self {
+        Self::new_in(SecretAllocator::new())
+    }
+}
+
+impl<A: Allocator> SecretString<A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self {
+            bytes: allocator_api2::vec::Vec::new_in(alloc),
+        }
+    }
+
+    /// Reserve memory for at least `additional` more bytes
+    ///
+    /// Reserving the expected length up front avoids reallocations while the string is
+    /// built.
+    pub fn reserve(&mut self, additional: usize) {
+        self.bytes.reserve(additional);
+    }
+
+    pub fn push(&mut self, ch: char) {
+        self.push_str(ch.encode_utf8(&mut [0u8; 4]));
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    /// Remove the last character, zeroizing its bytes
+    pub fn pop(&mut self) -> Option<char> {
+        let ch = self.as_str().chars().next_back()?;
+        let len = self.bytes.len() - ch.len_utf8();
+        self.bytes[len..].zeroize();
+        self.bytes.truncate(len);
+        Some(ch)
+    }
+
+    /// Zeroize and remove the contents, keeping the allocation
+    pub fn clear(&mut self) {
+        self.bytes.as_mut_slice().zeroize();
+        self.bytes.clear();
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever extended by whole strings, and truncated at character boundaries
+        std::str::from_utf8(&self.bytes).unwrap()
+    }
+
+    /// The UTF-8 encoding of the string, e.g. for key derivation
+    pub fn as_secret_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl<A: Allocator> Drop for SecretString<A> {
+    fn drop(&mut self) {
+        self.bytes.as_mut_slice().zeroize();
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        let mut string = Self::new();
+        string.reserve(s.len());
+        string.push_str(s);
+        string
+    }
+}
+
+impl<A: Allocator> fmt::Debug for SecretString<A> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("SecretString([redacted])")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::ptr::NonNull;
+    use std::slice;
+
+    use allocator_api2::alloc::{AllocError, Global, Layout};
+
+    use super::*;
+
+    #[test]
+    fn secret_string_contents() {
+        let mut s = SecretString::new();
+        assert!(s.is_empty());
+        s.push_str("correct horse");
+        s.push(' ');
+        s.push('🐴');
+        assert_eq!(s.as_str(), "correct horse 🐴");
+        assert_eq!(s.as_secret_bytes(), "correct horse 🐴".as_bytes());
+        assert_eq!(s.len(), 18);
+
+        assert_eq!(s.pop(), Some('🐴'));
+        assert_eq!(s.pop(), Some(' '));
+        assert_eq!(s.as_str(), "correct horse");
+
+        s.clear();
+        assert!(s.is_empty());
+        assert_eq!(s.pop(), None);
+
+        assert_eq!(SecretString::from("battery").as_str(), "battery");
+        assert_eq!(format!("{s:?}"), "SecretString([redacted])");
+    }
+
+    /// Allocator checking that memory is wiped before being freed
+    #[derive(Default)]
+    struct WipeCheckingAllocator {
+        frees: Cell<usize>,
+        unwiped_frees: Cell<usize>,
+    }
+
+    unsafe impl Allocator for &WipeCheckingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            // Zeroed, so only bytes written by the string can be left over
+            Global.allocate_zeroed(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let mem = unsafe { slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+            self.frees.set(self.frees.get() + 1);
+            if mem.iter().any(|&b| b != 0) {
+                self.unwiped_frees.set(self.unwiped_frees.get() + 1);
+            }
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn secret_string_wiped_on_drop() {
+        let alloc = WipeCheckingAllocator::default();
+
+        let mut s = SecretString::new_in(&alloc);
+        s.reserve(64);
+        s.push_str("correct horse battery staple");
+        assert_eq!(s.pop(), Some('e'));
+        drop(s);
+
+        assert_eq!(alloc.frees.get(), 1);
+        assert_eq!(alloc.unwiped_frees.get(), 0);
+    }
+}
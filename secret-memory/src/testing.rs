@@ -0,0 +1,60 @@
+//! Assertions on secrets for use in tests
+//!
+//! Available in this crate's tests and, for other crates, through the `testing` feature.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use crate::Secret;
+
+/// Short, non-reversible identifier of a secret, for telling secrets apart in test output
+///
+/// This is not a cryptographic hash; it must only be used to identify random secrets
+/// in test failures, never in production code.
+pub fn fingerprint(secret: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(secret);
+    hasher.finish()
+}
+
+/// Assert that two secrets are equal, comparing them in constant time
+///
+/// Unlike `assert_eq!` on [Secret::secret], a mismatch only prints [fingerprint]s of the
+/// secrets, never their contents.
+#[track_caller]
+pub fn assert_secret_eq<const N: usize>(left: &Secret<N>, right: &Secret<N>) {
+    if !rosenpass_constant_time::memcmp(left.secret(), right.secret()) {
+        panic!(
+            "assertion `left == right` failed for secrets\n  left fingerprint: {:016x}\n right fingerprint: {:016x}",
+            fingerprint(left.secret()),
+            fingerprint(right.secret())
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::catch_unwind;
+
+    use super::*;
+
+    #[test]
+    fn assert_secret_eq_equal() {
+        let a = Secret::<32>::random();
+        assert_secret_eq(&a, &a.clone());
+    }
+
+    #[test]
+    fn assert_secret_eq_unequal_redacted() {
+        let a = Secret::<32>::from_slice(&[0xab; 32]);
+        let b = Secret::<32>::from_slice(&[0xcd; 32]);
+        let err = catch_unwind(|| assert_secret_eq(&a, &b)).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+
+        assert!(msg.contains(&format!("{:016x}", fingerprint(a.secret()))));
+        assert!(msg.contains(&format!("{:016x}", fingerprint(b.secret()))));
+        assert!(!msg.contains("abab"), "Secret leaked: {msg}");
+        assert!(!msg.contains("cdcd"), "Secret leaked: {msg}");
+        assert!(!msg.contains("[171"), "Secret leaked: {msg}");
+    }
+}
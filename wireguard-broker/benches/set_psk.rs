@@ -0,0 +1,48 @@
+use std::io::Read;
+use std::os::unix::net::UnixStream;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rosenpass_secret_memory::{Public, Secret};
+use rosenpass_wireguard_broker::api::framing::FRAMED_REQUEST_SIZE;
+use rosenpass_wireguard_broker::brokers::mio_client::MioBrokerClient;
+use rosenpass_wireguard_broker::{PeerId, SerializedBrokerConfig, WireGuardBroker};
+use rosenpass_wireguard_broker::{DEFAULT_PSK_SLOT, WG_KEY_LEN};
+
+/// Per-message overhead of sending `set_psk` requests through [MioBrokerClient]
+///
+/// The receiving end of the socket is drained after every request, so the measurement
+/// covers message construction, framing and the socket write.
+///
+/// Sending through a vectored write instead of framing the message in a [MessageWriter]
+/// first made no measurable difference (three alternating runs, 1.34/1.48/1.88 µs before,
+/// 1.33/1.45/1.35 µs after); the two socket calls dominate the cost of a message.
+///
+/// [MessageWriter]: rosenpass_wireguard_broker::api::framing::MessageWriter
+fn criterion_benchmark(c: &mut Criterion) {
+    let (client_socket, mut server_socket) = UnixStream::pair().unwrap();
+    client_socket.set_nonblocking(true).unwrap();
+    let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+    let psk = Secret::<WG_KEY_LEN>::random();
+    let peer_id = PeerId::from(Public::random());
+    let mut buf = [0u8; FRAMED_REQUEST_SIZE];
+
+    c.bench_function("set_psk", |bench| {
+        bench.iter(|| {
+            client
+                .set_psk(SerializedBrokerConfig {
+                    interface: black_box(b"wg0"),
+                    peer_id: &peer_id,
+                    psk: &psk,
+                    additional_params: &[],
+                    slot: DEFAULT_PSK_SLOT,
+                })
+                .unwrap();
+            server_socket.read_exact(&mut buf).unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+use anyhow::ensure;
+use postcard::{take_from_bytes, to_allocvec};
+
+use crate::BrokerConfigBuilder;
+
+/// Typed contents of [SerializedBrokerConfig::additional_params]
+///
+/// Clients encode the parameters with [Self::encode] and brokers parse them with
+/// [Self::decode], so both agree on the layout. The encoding is the postcard encoding
+/// of [Self::extra_args]; no parameters are encoded as an empty slice.
+///
+/// [SerializedBrokerConfig::additional_params]: crate::SerializedBrokerConfig::additional_params
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdditionalParams {
+    /// Arguments appended to `wg set` by the
+    /// [NativeUnixBroker](crate::brokers::native_unix::NativeUnixBroker)
+    pub extra_args: Vec<String>,
+}
+
+impl AdditionalParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extra_arg(mut self, arg: impl Into<String>) -> Self {
+        self.extra_args.push(arg.into());
+        self
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        if self.extra_args.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(to_allocvec(&self.extra_args)?)
+    }
+
+    /// Parse parameters encoded by [Self::encode], rejecting trailing bytes
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        let (extra_args, rest) = take_from_bytes(bytes)?;
+        ensure!(rest.is_empty(), "Trailing bytes after additional params");
+        Ok(Self { extra_args })
+    }
+}
+
+impl BrokerConfigBuilder {
+    /// Set [BrokerConfig::additional_params](crate::BrokerConfig::additional_params) from
+    /// typed parameters
+    pub fn typed_additional_params(
+        &mut self,
+        params: &AdditionalParams,
+    ) -> anyhow::Result<&mut Self> {
+        Ok(self.additional_params(params.encode()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rosenpass_secret_memory::Secret;
+
+    use crate::{PeerId, WG_PEER_LEN};
+
+    use super::*;
+
+    #[test]
+    fn additional_params_round_trip() {
+        let params = AdditionalParams::new()
+            .extra_arg("persistent-keepalive")
+            .extra_arg("25");
+        let encoded = params.encode().unwrap();
+        assert_eq!(AdditionalParams::decode(&encoded).unwrap(), params);
+
+        // Compatible with the untyped encoding used so far
+        let untyped = vec!["persistent-keepalive".to_string(), "25".to_string()];
+        assert_eq!(encoded, to_allocvec(&untyped).unwrap());
+
+        let empty = AdditionalParams::new();
+        assert!(empty.encode().unwrap().is_empty());
+        assert_eq!(AdditionalParams::decode(&[]).unwrap(), empty);
+        assert_eq!(AdditionalParams::decode(&[0]).unwrap(), empty);
+    }
+
+    #[test]
+    fn additional_params_malformed() {
+        let mut encoded = AdditionalParams::new().extra_arg("x").encode().unwrap();
+        assert!(AdditionalParams::decode(&encoded[..encoded.len() - 1]).is_err());
+        encoded.push(0);
+        assert!(AdditionalParams::decode(&encoded).is_err());
+        assert!(AdditionalParams::decode(&[1, 1, 0xff]).is_err());
+    }
+
+    #[test]
+    fn broker_config_typed_params() {
+        let params = AdditionalParams::new().extra_arg("allowed-ips");
+        let config = BrokerConfigBuilder::default()
+            .iface("wg0")
+            .peer_id(PeerId::new([1; WG_PEER_LEN]))
+            .psk(Secret::random())
+            .typed_additional_params(&params)
+            .unwrap()
+            .build()
+            .unwrap();
+        let serialized = config.serialized();
+        assert_eq!(
+            AdditionalParams::decode(serialized.additional_params).unwrap(),
+            params
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+use std::os::fd::{BorrowedFd, OwnedFd};
 use std::{borrow::BorrowMut, fmt::Debug};
 
 use crate::{
@@ -5,12 +7,13 @@ use crate::{
         config::NetworkBrokerConfig,
         msgs::{self, REQUEST_MSG_BUFFER_SIZE},
     },
-    SerializedBrokerConfig, WireGuardBroker,
+    SerializedBrokerConfig, WireGuardBroker, WG_KEY_LEN,
 };
 
 use super::{
     config::NetworkBrokerConfigErr,
-    msgs::{Envelope, SetPskResponse},
+    msgs::{Envelope, Goodbye, ListPsksRequest, SetPskResponse},
+    owned::PskList,
 };
 
 #[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
@@ -48,20 +51,90 @@ pub enum BrokerClientSetPskError<SendError> {
     IfaceOutOfBounds,
 }
 
+/// Outcome of [BrokerClient::poll_broker_response]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerResponse {
+    /// No complete response was received yet
+    NoneReady,
+    /// The broker set the PSK
+    Ok,
+    /// The broker failed to set the PSK
+    BrokerError(msgs::SetPskError),
+    /// The broker sent a malformed response
+    Invalid,
+}
+
 pub trait BrokerClientIo {
     type SendError;
     type RecvError;
 
     fn send_msg(&mut self, buf: &[u8]) -> Result<(), Self::SendError>;
+
+    /// Receive the next message, if a complete one is available
+    ///
+    /// The returned slice borrows the receive buffer of the io object, so it stays valid
+    /// only until the next call on `self`. Callers that need the message afterwards have
+    /// to copy it; see [Self::with_message] for processing it in place.
     fn recv_msg(&mut self) -> Result<Option<&[u8]>, Self::RecvError>;
+
+    /// Run `f` on the next message, if a complete one is available
+    ///
+    /// The message is passed to `f` directly from the receive buffer without copying;
+    /// once `f` returns, the buffer is free to be reused for the next message.
+    fn with_message<R, F>(&mut self, f: F) -> Result<Option<R>, Self::RecvError>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        Ok(self.recv_msg()?.map(f))
+    }
+
+    /// Whether [Self::send_msg_with_fds] can pass file descriptors over this transport
+    fn supports_fds(&self) -> bool {
+        false
+    }
+
+    /// Send a message along with file descriptors
+    ///
+    /// Unix socket transports pass the descriptors as `SCM_RIGHTS` ancillary data, so
+    /// the receiving process gets its own duplicates of them together with the message.
+    /// Transports that can not pass descriptors return an error if `fds` is not empty;
+    /// check [Self::supports_fds] beforehand.
+    fn send_msg_with_fds(
+        &mut self,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Result<(), Self::SendError>;
+
+    /// Take the file descriptors received along with the last message returned by [Self::recv_msg]
+    ///
+    /// Descriptors not taken before the next message is received are closed.
+    fn take_fds(&mut self) -> Vec<OwnedFd> {
+        Vec::new()
+    }
 }
 
+/// Encodes requests and decodes responses of the broker protocol over a [BrokerClientIo]
+///
+/// The client is [Send] and [Sync] whenever `Io` is; all of its state is only modified
+/// through `&mut self`.
 #[derive(Debug)]
 pub struct BrokerClient<Io>
 where
     Io: BrokerClientIo + Debug,
 {
     io: Io,
+    trace_framing: bool,
+    closed: bool,
+    in_flight: usize,
+    cancelled: usize,
+    psk_lists: VecDeque<PskList>,
+}
+
+/// A decoded response
+enum Response {
+    SetPsk(msgs::SetPskResult),
+    Goodbye,
+    ListPsks(PskList),
 }
 
 impl<Io> BrokerClient<Io>
@@ -69,7 +142,27 @@ where
     Io: BrokerClientIo + Debug,
 {
     pub fn new(io: Io) -> Self {
-        Self { io }
+        Self {
+            io,
+            trace_framing: false,
+            closed: false,
+            in_flight: 0,
+            cancelled: 0,
+            psk_lists: VecDeque::new(),
+        }
+    }
+
+    /// Whether message framing is logged at trace level
+    pub fn trace_framing(&self) -> bool {
+        self.trace_framing
+    }
+
+    /// Log the framing of sent and received messages at trace level
+    ///
+    /// Logs message types, lengths and public fields; the PSK is redacted and only its
+    /// length is logged.
+    pub fn set_trace_framing(&mut self, enabled: bool) {
+        self.trace_framing = enabled;
     }
 
     pub fn io(&self) -> &Io {
@@ -80,28 +173,210 @@ where
         &mut self.io
     }
 
+    /// Number of requests sent whose response was not yet received through [Self::poll_response]
+    ///
+    /// Callers pipelining multiple requests can use this to throttle.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Count a request sent through [Self::io_mut] towards [Self::in_flight]
+    ///
+    /// Its response is then handled like that of a request sent by this client.
+    pub fn expect_response(&mut self) {
+        self.in_flight += 1;
+    }
+
+    /// Give up on all requests awaiting a response
+    ///
+    /// Afterwards, [Self::in_flight] is zero. The responses to the cancelled requests are
+    /// still read from the connection, but [Self::poll_response] silently drops them, so
+    /// the next result returned belongs to a request sent after this call.
+    pub fn cancel_pending(&mut self) {
+        self.cancelled += self.in_flight;
+        self.in_flight = 0;
+    }
+
+    /// Whether the broker acknowledged the goodbye sent by [Self::send_goodbye]
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Announce that this client is done sending requests
+    ///
+    /// The broker acknowledges the goodbye after processing all previous requests;
+    /// once [Self::poll_response] received the acknowledgement, [Self::is_closed] returns
+    /// true and the connection can be closed.
+    pub fn send_goodbye(&mut self) -> Result<(), Io::SendError> {
+        let mut req = [0u8; msgs::ENVELOPE_OVERHEAD];
+        let mut req = zerocopy::Ref::<&mut [u8], Envelope<Goodbye>>::new(&mut req[..]).unwrap();
+        req.msg_type = msgs::MsgType::Goodbye as u8;
+        if self.trace_framing {
+            log::trace!(
+                "Broker client sending {:?} request: length {}",
+                msgs::MsgType::Goodbye,
+                req.bytes().len()
+            );
+        }
+        self.io.borrow_mut().send_msg(req.bytes())?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Ask the broker for the peers of `interface` it installed a PSK for
+    ///
+    /// The broker lists at most [msgs::MAX_LISTED_PEERS] peers per response, starting at
+    /// `offset`; request further pages until the listed peers reach [PskList::total].
+    /// Once [Self::poll_response] received the response, it can be taken through
+    /// [Self::take_psk_list].
+    pub fn send_list_psks(
+        &mut self,
+        interface: &str,
+        offset: u32,
+    ) -> Result<(), BrokerClientSetPskError<Io::SendError>> {
+        use BrokerClientSetPskError::*;
+
+        let mut req = [0u8; std::mem::size_of::<Envelope<ListPsksRequest>>()];
+        let mut req = zerocopy::Ref::<&mut [u8], Envelope<ListPsksRequest>>::new(&mut req[..])
+            .ok_or(MsgError)?;
+        req.msg_type = msgs::MsgType::ListPsks as u8;
+        req.payload.set_iface(interface).ok_or(IfaceOutOfBounds)?;
+        req.payload.offset = offset.to_le_bytes();
+        if self.trace_framing {
+            log::trace!(
+                "Broker client sending {:?} request: length {}, interface {interface:?}, \
+                offset {offset}",
+                msgs::MsgType::ListPsks,
+                req.bytes().len()
+            );
+        }
+        self.io
+            .borrow_mut()
+            .send_msg(req.bytes())
+            .map_err(IoError)?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Take the oldest response to [Self::send_list_psks] received by [Self::poll_response]
+    pub fn take_psk_list(&mut self) -> Option<PskList> {
+        self.psk_lists.pop_front()
+    }
+
+    /// Receive the result of a previous `set_psk` request, if one is available
+    ///
+    /// Goodbye acknowledgements are consumed without returning a result; see [Self::is_closed].
+    /// So are responses to [Self::send_list_psks]; see [Self::take_psk_list].
     pub fn poll_response(
         &mut self,
     ) -> Result<Option<msgs::SetPskResult>, BrokerClientPollResponseError<Io::RecvError>> {
-        let res: &[u8] = match self.io.borrow_mut().recv_msg().map_err(io_poller)? {
-            Some(r) => r,
-            None => return Ok(None),
-        };
+        let trace_framing = self.trace_framing;
+        loop {
+            let res = self
+                .io
+                .borrow_mut()
+                .with_message(|res| Self::parse_response(res, trace_framing))
+                .map_err(io_poller)?
+                .transpose()?;
+            let cancelled = res.is_some() && self.cancelled > 0;
+            if cancelled {
+                self.cancelled -= 1;
+            } else if res.is_some() {
+                self.in_flight = self.in_flight.saturating_sub(1);
+            }
+            match res {
+                // Response to a cancelled request
+                Some(Response::SetPsk(_)) if cancelled => continue,
+                Some(Response::SetPsk(res)) => return Ok(Some(res)),
+                Some(Response::Goodbye) => {
+                    self.closed = true;
+                    return Ok(None);
+                }
+                Some(Response::ListPsks(_)) if cancelled => continue,
+                Some(Response::ListPsks(list)) => {
+                    self.psk_lists.push_back(list);
+                    return Ok(None);
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like [Self::poll_response], flattening the result into a [BrokerResponse]
+    ///
+    /// Only errors receiving from the broker are returned as errors.
+    pub fn poll_broker_response(&mut self) -> Result<BrokerResponse, Io::RecvError> {
+        match self.poll_response() {
+            Ok(None) => Ok(BrokerResponse::NoneReady),
+            Ok(Some(Ok(()))) => Ok(BrokerResponse::Ok),
+            Ok(Some(Err(e))) => Ok(BrokerResponse::BrokerError(e)),
+            Err(BrokerClientPollResponseError::InvalidMessage) => Ok(BrokerResponse::Invalid),
+            Err(BrokerClientPollResponseError::IoError(e)) => Err(e),
+        }
+    }
+
+    /// Iterate over the results of all responses that can be received right now
+    ///
+    /// Equivalent to calling [Self::poll_response] until it yields no result; the iterator
+    /// ends after the first error.
+    pub fn drain_responses(
+        &mut self,
+    ) -> impl Iterator<Item = Result<msgs::SetPskResult, BrokerClientPollResponseError<Io::RecvError>>>
+           + '_ {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let res = self.poll_response().transpose();
+            failed = matches!(res, Some(Err(_)));
+            res
+        })
+    }
 
+    fn parse_response(
+        res: &[u8],
+        trace_framing: bool,
+    ) -> Result<Response, BrokerClientPollResponseError<Io::RecvError>> {
         let typ = res.get(0).ok_or(invalid_msg_poller())?;
         let typ = msgs::MsgType::try_from(*typ)?;
-        let msgs::MsgType::SetPsk = typ; // Assert type
+        if let msgs::MsgType::Goodbye = typ {
+            zerocopy::Ref::<&[u8], Envelope<Goodbye>>::new(res).ok_or(invalid_msg_poller())?;
+            if trace_framing {
+                log::trace!("Broker client received {typ:?} response");
+            }
+            return Ok(Response::Goodbye);
+        }
+        if let msgs::MsgType::ListPsks = typ {
+            let list = PskList::from_wire(res).map_err(|_| invalid_msg_poller())?;
+            if trace_framing {
+                log::trace!(
+                    "Broker client received {typ:?} response: length {}, total {}, {} peers",
+                    res.len(),
+                    list.total,
+                    list.peers.len()
+                );
+            }
+            return Ok(Response::ListPsks(list));
+        }
 
+        let len = res.len();
         let res = zerocopy::Ref::<&[u8], Envelope<SetPskResponse>>::new(res)
             .ok_or(invalid_msg_poller())?;
         let res: &msgs::SetPskResponse = &res.payload;
+        if trace_framing {
+            log::trace!(
+                "Broker client received {typ:?} response: length {len}, return code {}",
+                res.return_code
+            );
+        }
         let res: msgs::SetPskResponseReturnCode = res
             .return_code
             .try_into()
             .map_err(|_| invalid_msg_poller())?;
         let res: msgs::SetPskResult = res.into();
 
-        Ok(Some(res))
+        Ok(Response::SetPsk(res))
     }
 }
 
@@ -127,6 +402,7 @@ where
 
         // Populate envelope
         req.msg_type = msgs::MsgType::SetPsk as u8;
+        req.set_slot(config.slot);
         {
             // Derived payload
             let req = &mut req.payload;
@@ -138,12 +414,245 @@ where
                 .ok_or(IfaceOutOfBounds)?;
         }
 
+        if self.trace_framing {
+            // Never log the PSK itself
+            log::trace!(
+                "Broker client sending {:?} request: length {}, peer id {:?}, interface {:?}, \
+                slot {}, psk <redacted, {} bytes>",
+                msgs::MsgType::SetPsk,
+                req.bytes().len(),
+                config.peer_id,
+                config.iface,
+                config.slot,
+                WG_KEY_LEN,
+            );
+        }
+
         // Send message
         self.io
             .borrow_mut()
             .send_msg(req.bytes())
             .map_err(IoError)?;
+        self.in_flight += 1;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::api::in_memory::{InMemoryIo, InMemoryIoClosed};
+    use crate::test_fixtures::{config, random_psk};
+    use crate::{test_logger, PeerId};
+
+    use super::*;
+
+    #[test]
+    fn in_flight_requests() {
+        let (peer_id, psk) = random_psk();
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        for _ in 0..3 {
+            client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+        }
+        assert_eq!(client.in_flight(), 3);
+
+        for _ in 0..3 {
+            client
+                .io_mut()
+                .push_set_psk_response(msgs::SetPskResponseReturnCode::Success);
+        }
+        client.poll_response().unwrap();
+        assert_eq!(client.in_flight(), 2);
+        while client.poll_response().unwrap().is_some() {}
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn list_psks() {
+        use crate::api::owned::{ListPsks, Request};
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        client.send_list_psks("wg0", 64).unwrap();
+        let sent = Request::from_wire(&client.io_mut().pop_sent().unwrap());
+        let Ok(Request::ListPsks(req)) = sent else {
+            panic!("Expected a ListPsks request, got {sent:?}");
+        };
+        assert_eq!(
+            req,
+            ListPsks {
+                interface: "wg0".to_owned(),
+                offset: 64,
+            }
+        );
+        assert_eq!(client.in_flight(), 1);
+
+        let list = PskList {
+            total: 65,
+            peers: vec![PeerId::new([1; 32])],
+        };
+        client.io_mut().push_response(list.to_wire());
+        assert_eq!(client.poll_response(), Ok(None));
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(client.take_psk_list(), Some(list));
+        assert_eq!(client.take_psk_list(), None);
+
+        assert_eq!(
+            client.send_list_psks(&"x".repeat(256), 0),
+            Err(BrokerClientSetPskError::IfaceOutOfBounds)
+        );
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn flattened_responses() {
+        use msgs::SetPskResponseReturnCode as C;
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        assert_eq!(client.poll_broker_response(), Ok(BrokerResponse::NoneReady));
+
+        client.io_mut().push_set_psk_response(C::Success);
+        assert_eq!(client.poll_broker_response(), Ok(BrokerResponse::Ok));
+
+        client.io_mut().push_set_psk_response(C::RateLimited);
+        assert_eq!(
+            client.poll_broker_response(),
+            Ok(BrokerResponse::BrokerError(msgs::SetPskError::RateLimited))
+        );
+
+        client.io_mut().push_response(vec![0x7f]);
+        assert_eq!(client.poll_broker_response(), Ok(BrokerResponse::Invalid));
+
+        // Goodbye acknowledgements do not yield a response
+        let mut goodbye = vec![0u8; msgs::ENVELOPE_OVERHEAD];
+        goodbye[0] = msgs::MsgType::Goodbye as u8;
+        client.io_mut().push_response(goodbye);
+        assert_eq!(client.poll_broker_response(), Ok(BrokerResponse::NoneReady));
+        assert!(client.is_closed());
+
+        client.io_mut().close();
+        assert_eq!(client.poll_broker_response(), Err(InMemoryIoClosed));
+    }
+
+    #[test]
+    fn drain_pipelined_responses() {
+        let (peer_id, psk) = random_psk();
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        for _ in 0..3 {
+            client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+        }
+        for code in [
+            msgs::SetPskResponseReturnCode::Success,
+            msgs::SetPskResponseReturnCode::NoSuchPeer,
+            msgs::SetPskResponseReturnCode::Success,
+        ] {
+            client.io_mut().push_set_psk_response(code);
+        }
+
+        let results: Vec<_> = client.drain_responses().collect();
+        assert_eq!(
+            results,
+            [
+                Ok(Ok(())),
+                Ok(Err(msgs::SetPskError::NoSuchPeer)),
+                Ok(Ok(()))
+            ]
+        );
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(client.drain_responses().count(), 0);
+    }
+
+    #[test]
+    fn cancel_pending_requests() {
+        let (peer_id, psk) = random_psk();
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+        client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+        client.cancel_pending();
+        assert_eq!(client.in_flight(), 0);
+
+        // One cancelled response arrives before the new request is sent
+        client
+            .io_mut()
+            .push_set_psk_response(msgs::SetPskResponseReturnCode::NoSuchPeer);
+        assert_eq!(client.poll_response(), Ok(None));
+        assert_eq!(client.in_flight(), 0);
+
+        client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+        assert_eq!(client.in_flight(), 1);
+        client
+            .io_mut()
+            .push_set_psk_response(msgs::SetPskResponseReturnCode::NoSuchInterface);
+        client
+            .io_mut()
+            .push_set_psk_response(msgs::SetPskResponseReturnCode::Success);
+        assert_eq!(client.poll_response(), Ok(Some(Ok(()))));
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(client.poll_response(), Ok(None));
+    }
+
+    #[test]
+    fn with_message_parses_in_place() {
+        let mut io = InMemoryIo::new();
+        io.push_set_psk_response(msgs::SetPskResponseReturnCode::NoSuchPeer);
+        io.push_set_psk_response(msgs::SetPskResponseReturnCode::Success);
+
+        let parse = |msg: &[u8]| {
+            let env = zerocopy::Ref::<&[u8], Envelope<SetPskResponse>>::new(msg).unwrap();
+            (env.msg_type, env.payload.return_code)
+        };
+        let set_psk = msgs::MsgType::SetPsk as u8;
+        assert_eq!(
+            io.with_message(parse),
+            Ok(Some((
+                set_psk,
+                msgs::SetPskResponseReturnCode::NoSuchPeer as u8
+            )))
+        );
+        assert_eq!(
+            io.with_message(parse),
+            Ok(Some((
+                set_psk,
+                msgs::SetPskResponseReturnCode::Success as u8
+            )))
+        );
+        assert_eq!(io.with_message(parse), Ok(None));
+
+        let mut client = BrokerClient::new(io);
+        client
+            .io_mut()
+            .push_set_psk_response(msgs::SetPskResponseReturnCode::NoSuchPeer);
+        assert_eq!(
+            client.poll_response(),
+            Ok(Some(Err(msgs::SetPskError::NoSuchPeer)))
+        );
+        assert_eq!(client.poll_response(), Ok(None));
+    }
+
+    #[test]
+    fn trace_framing_redacts_psk() {
+        test_logger::install();
+
+        let (peer_id, psk) = random_psk();
+
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        client.set_trace_framing(true);
+        client.set_psk(config(b"wg0", &peer_id, &psk)).unwrap();
+
+        let psk_hex: String = psk.secret().iter().map(|b| format!("{b:x}")).collect();
+        let psk_debug = format!("{:?}", psk.secret());
+        let log = test_logger::captured();
+        let msg = log
+            .iter()
+            .find(|m| m.contains("Broker client sending"))
+            .unwrap();
+        assert!(msg.contains(&format!("<redacted, {WG_KEY_LEN} bytes>")));
+        assert!(msg.contains(&format!("length {}", client.io().sent()[0].len())));
+        for m in log.iter() {
+            assert!(!m.contains(&psk_hex));
+            assert!(!m.contains(&psk_debug));
+        }
+    }
+}
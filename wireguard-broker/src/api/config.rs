@@ -1,14 +1,16 @@
-use crate::{SerializedBrokerConfig, WG_KEY_LEN, WG_PEER_LEN};
+use crate::{PeerId, SerializedBrokerConfig, DEFAULT_PSK_SLOT, WG_KEY_LEN};
 use derive_builder::Builder;
-use rosenpass_secret_memory::{Public, Secret};
+use rosenpass_secret_memory::Secret;
 
 #[derive(Builder)]
 #[builder(pattern = "mutable")]
 //TODO: Use generics for iface, add additional params
 pub struct NetworkBrokerConfig<'a> {
     pub iface: &'a str,
-    pub peer_id: &'a Public<WG_PEER_LEN>,
+    pub peer_id: &'a PeerId,
     pub psk: &'a Secret<WG_KEY_LEN>,
+    #[builder(default = "DEFAULT_PSK_SLOT")]
+    pub slot: u8,
 }
 
 impl<'a> Into<SerializedBrokerConfig<'a>> for NetworkBrokerConfig<'a> {
@@ -18,6 +20,7 @@ impl<'a> Into<SerializedBrokerConfig<'a>> for NetworkBrokerConfig<'a> {
             peer_id: self.peer_id,
             psk: self.psk,
             additional_params: &[],
+            slot: self.slot,
         }
     }
 }
@@ -28,6 +31,10 @@ pub enum NetworkBrokerConfigErr {
     Interface,
 }
 
+/// Fails if the interface name is not valid UTF-8
+///
+/// The lengths of the peer id and PSK are guaranteed by their types. The broker protocol
+/// has no room for [SerializedBrokerConfig::additional_params], so they are dropped.
 impl<'a> TryFrom<SerializedBrokerConfig<'a>> for NetworkBrokerConfig<'a> {
     type Error = NetworkBrokerConfigErr;
 
@@ -38,6 +45,53 @@ impl<'a> TryFrom<SerializedBrokerConfig<'a>> for NetworkBrokerConfig<'a> {
             iface,
             peer_id: value.peer_id,
             psk: value.psk,
+            slot: value.slot,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rosenpass_secret_memory::Public;
+
+    use super::*;
+
+    #[test]
+    fn serialized_round_trip() {
+        let peer_id = PeerId::from(Public::random());
+        let psk = Secret::random();
+        let config = NetworkBrokerConfigBuilder::default()
+            .iface("wg0")
+            .peer_id(&peer_id)
+            .psk(&psk)
+            .slot(2)
+            .build()
+            .unwrap();
+
+        let serialized: SerializedBrokerConfig = config.into();
+        assert_eq!(serialized.interface, b"wg0");
+
+        let config = NetworkBrokerConfig::try_from(serialized).unwrap();
+        assert_eq!(config.iface, "wg0");
+        assert_eq!(config.peer_id, &peer_id);
+        assert_eq!(config.psk.secret(), psk.secret());
+        assert_eq!(config.slot, 2);
+    }
+
+    #[test]
+    fn serialized_invalid_interface() {
+        let peer_id = PeerId::from(Public::random());
+        let psk = Secret::random();
+        let serialized = SerializedBrokerConfig {
+            interface: b"wg\xff",
+            peer_id: &peer_id,
+            psk: &psk,
+            additional_params: &[],
+            slot: DEFAULT_PSK_SLOT,
+        };
+        assert_eq!(
+            NetworkBrokerConfig::try_from(serialized).err(),
+            Some(NetworkBrokerConfigErr::Interface)
+        );
+    }
+}
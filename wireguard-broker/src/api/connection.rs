@@ -0,0 +1,518 @@
+//! Serving a client connection of the socket handler
+//!
+//! Requests read from a connection are passed on to the underlying broker through a
+//! queue of [BrokerRequest]s; responses are written back in the order the requests were
+//! received. Clients may pipeline requests, but at most `max_queued` requests per
+//! connection are awaiting their response at any time. Once this limit is reached, no more
+//! requests are read from the connection, so the client experiences backpressure through
+//! the socket instead of the server buffering its requests.
+//!
+//! Optionally, each connection gets its own [RateLimiter]; `set_psk` requests exceeding
+//! its rate are answered with [msgs::SetPskResponseReturnCode::RateLimited] right away,
+//! without reaching the broker.
+
+use anyhow::{ensure, Context, Result};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::api::msgs::{self, Envelope, SetPskResponse};
+
+/// Default limit of requests awaiting their response per connection
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 16;
+
+/// Token bucket limiting the rate at which requests are processed
+///
+/// The bucket holds up to `capacity` tokens and gains one token every `refill_interval`.
+/// Each request consumes one token; requests arriving while the bucket is empty are rejected.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    capacity: u32,
+    refill_interval: Duration,
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a full bucket holding `capacity` tokens, gaining one token every `refill_interval`
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        Self {
+            capacity,
+            refill_interval,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Try to consume a token, returning false if the bucket is empty
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    /// Like [Self::try_acquire], using `now` as the current time
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refills = elapsed.as_nanos() / self.refill_interval.as_nanos().max(1);
+        let missing = (self.capacity - self.tokens) as u128;
+        if refills >= missing {
+            // Full buckets do not accumulate time
+            self.tokens = self.capacity;
+            self.last_refill = now;
+        } else {
+            // refills < missing <= u32::MAX
+            self.tokens += refills as u32;
+            self.last_refill += self.refill_interval * refills as u32;
+        }
+    }
+}
+
+/// A request passed to the underlying broker, along with the channel to reply on
+#[derive(Debug)]
+pub struct BrokerRequest {
+    pub reply_to: oneshot::Sender<BrokerResponse>,
+    pub request: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct BrokerResponse {
+    pub response: Vec<u8>,
+}
+
+/// A request awaiting its response
+struct Pending {
+    reply: oneshot::Receiver<BrokerResponse>,
+    goodbye: bool,
+    // Released once the response was written
+    _slot: OwnedSemaphorePermit,
+}
+
+/// Serve requests from `stream` until the client said goodbye
+///
+/// At most `max_queued` requests are awaiting their response at any time. If given,
+/// `rate_limiter` limits the rate of `set_psk` requests passed on to the broker.
+pub async fn serve_connection<S>(
+    queue: mpsc::Sender<BrokerRequest>,
+    stream: S,
+    max_queued: usize,
+    rate_limiter: Option<RateLimiter>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    ensure!(
+        max_queued > 0,
+        "At least one request must be allowed to queue"
+    );
+
+    let (rx, tx) = tokio::io::split(stream);
+    let slots = Arc::new(Semaphore::new(max_queued));
+    // Bounded by the number of slots
+    let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+    tokio::try_join!(
+        read_requests(queue, rx, slots, pending_tx, rate_limiter),
+        write_responses(tx, pending_rx)
+    )?;
+
+    Ok(())
+}
+
+async fn read_requests<R: AsyncRead>(
+    queue: mpsc::Sender<BrokerRequest>,
+    rx: R,
+    slots: Arc<Semaphore>,
+    pending: mpsc::UnboundedSender<Pending>,
+    mut rate_limiter: Option<RateLimiter>,
+) -> Result<()> {
+    tokio::pin!(rx);
+
+    loop {
+        // Stop reading while too many requests are queued
+        let slot = slots.clone().acquire_owned().await?;
+
+        // Read the message length
+        let mut len = [0u8; 8];
+        rx.read_exact(&mut len).await?;
+
+        // Parse the message length
+        let len = u64::from_le_bytes(len) as usize;
+        ensure!(
+            len <= msgs::REQUEST_MSG_BUFFER_SIZE,
+            "Oversized buffer ({len}) in unix socket input."
+        );
+
+        // Read the message itself
+        let mut request = vec![0u8; len];
+        rx.read_exact(&mut request).await?;
+
+        // Hand the message to the broker, unless it is rate limited
+        let goodbye = request.first() == Some(&(msgs::MsgType::Goodbye as u8));
+        let set_psk = request.first() == Some(&(msgs::MsgType::SetPsk as u8));
+        let (reply_to, reply) = oneshot::channel();
+        // Only set_psk requests take tokens
+        let limited = set_psk && rate_limiter.as_mut().is_some_and(|l| !l.try_acquire());
+        match limited {
+            true => {
+                // Queued like any other response, so responses stay in order
+                let response = rate_limited_response();
+                let _ = reply_to.send(BrokerResponse { response });
+            }
+            false => queue.send(BrokerRequest { reply_to, request }).await?,
+        }
+        pending
+            .send(Pending {
+                reply,
+                goodbye,
+                _slot: slot,
+            })
+            .ok()
+            .context("Connection writer terminated")?;
+
+        // The client must not send anything after its goodbye
+        if goodbye {
+            return Ok(());
+        }
+    }
+}
+
+fn rate_limited_response() -> Vec<u8> {
+    let mut response = vec![0u8; std::mem::size_of::<Envelope<SetPskResponse>>()];
+    let mut env =
+        zerocopy::Ref::<&mut [u8], Envelope<SetPskResponse>>::new(&mut response[..]).unwrap();
+    env.msg_type = msgs::MsgType::SetPsk as u8;
+    env.payload.return_code = msgs::SetPskResponseReturnCode::RateLimited as u8;
+    response
+}
+
+async fn write_responses<W: AsyncWrite>(
+    tx: W,
+    mut pending: mpsc::UnboundedReceiver<Pending>,
+) -> Result<()> {
+    tokio::pin!(tx);
+
+    while let Some(Pending { reply, goodbye, .. }) = pending.recv().await {
+        // Wait for the reply
+        let BrokerResponse { response } = reply.await.context("Broker dropped the request")?;
+
+        // Write reply back to unix socket
+        tx.write_all(&(response.len() as u64).to_le_bytes()).await?;
+        tx.write_all(&response[..]).await?;
+        tx.flush().await?;
+
+        // The client is done once its goodbye has been acknowledged
+        if goodbye {
+            tx.shutdown().await?;
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+    use tokio::io::DuplexStream;
+    use tokio::time::sleep;
+
+    use crate::api::framing::{len_prefix, LEN_SIZE};
+
+    use super::*;
+
+    async fn send(client: &mut DuplexStream, msg: &[u8]) {
+        client
+            .write_all(&(msg.len() as u64).to_le_bytes())
+            .await
+            .unwrap();
+        client.write_all(msg).await.unwrap();
+    }
+
+    async fn recv(client: &mut DuplexStream) -> Vec<u8> {
+        let mut len = [0u8; 8];
+        client.read_exact(&mut len).await.unwrap();
+        let mut msg = vec![0u8; u64::from_le_bytes(len) as usize];
+        client.read_exact(&mut msg).await.unwrap();
+        msg
+    }
+
+    /// Receive all requests the connection has passed on, without blocking
+    fn queued(broker: &mut mpsc::Receiver<BrokerRequest>) -> Vec<BrokerRequest> {
+        std::iter::from_fn(|| broker.try_recv().ok()).collect()
+    }
+
+    fn reply(req: BrokerRequest) {
+        // Echo the request id
+        let response = vec![req.request[1]];
+        req.reply_to.send(BrokerResponse { response }).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bounded_queue_under_flood() {
+        const MAX_QUEUED: usize = 4;
+        const REQUESTS: u8 = 100;
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, mut broker) = mpsc::channel(REQUESTS as usize);
+        let conn = tokio::spawn(serve_connection(queue, server, MAX_QUEUED, None));
+
+        for i in 0..REQUESTS {
+            send(&mut client, &[msgs::MsgType::SetPsk as u8, i]).await;
+        }
+        send(&mut client, &[msgs::MsgType::Goodbye as u8, REQUESTS]).await;
+
+        // The connection stops reading once the limit is reached
+        sleep(Duration::from_millis(50)).await;
+        let first = queued(&mut broker);
+        assert_eq!(first.len(), MAX_QUEUED);
+        sleep(Duration::from_millis(50)).await;
+        assert!(queued(&mut broker).is_empty());
+
+        // Answering requests resumes processing
+        first.into_iter().for_each(reply);
+        let mut answered = MAX_QUEUED;
+        while answered <= REQUESTS as usize {
+            let mut batch = vec![broker.recv().await.unwrap()];
+            batch.extend(queued(&mut broker));
+            assert!(batch.len() <= MAX_QUEUED);
+            answered += batch.len();
+            batch.into_iter().for_each(reply);
+        }
+
+        for i in 0..=REQUESTS {
+            assert_eq!(recv(&mut client).await, [i]);
+        }
+        let mut eof = [0u8; 1];
+        assert_eq!(client.read(&mut eof).await.unwrap(), 0);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn rate_limiter_refill() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(1);
+        let mut limiter = RateLimiter::new(2, interval);
+        limiter.last_refill = start;
+
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start + interval / 2));
+        assert!(limiter.try_acquire_at(start + interval));
+        assert!(!limiter.try_acquire_at(start + interval));
+
+        // Refill does not exceed the capacity
+        let later = start + interval * 100;
+        assert!(limiter.try_acquire_at(later));
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_set_psk() {
+        const REQUESTS: u8 = 5;
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, mut broker) = mpsc::channel(REQUESTS as usize);
+        let limiter = RateLimiter::new(3, Duration::from_secs(3600));
+        let conn = tokio::spawn(serve_connection(
+            queue,
+            server,
+            REQUESTS as usize + 1,
+            Some(limiter),
+        ));
+
+        for i in 0..REQUESTS {
+            send(&mut client, &[msgs::MsgType::SetPsk as u8, i]).await;
+        }
+        // Goodbyes are never rate limited
+        send(&mut client, &[msgs::MsgType::Goodbye as u8, REQUESTS]).await;
+
+        // Only the first three requests and the goodbye reach the broker
+        let mut forwarded = Vec::new();
+        for _ in 0..4 {
+            let req = broker.recv().await.unwrap();
+            forwarded.push(req.request[1]);
+            reply(req);
+        }
+        assert_eq!(forwarded, [0, 1, 2, REQUESTS]);
+
+        let limited = rate_limited_response();
+        assert_eq!(
+            limited[msgs::ENVELOPE_OVERHEAD],
+            msgs::SetPskResponseReturnCode::RateLimited as u8
+        );
+        for i in 0..3 {
+            assert_eq!(recv(&mut client).await, [i]);
+        }
+        for _ in 3..REQUESTS {
+            assert_eq!(recv(&mut client).await, limited);
+        }
+        assert_eq!(recv(&mut client).await, [REQUESTS]);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limit_ignores_other_requests() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, mut broker) = mpsc::channel(8);
+        let limiter = RateLimiter::new(2, Duration::from_secs(3600));
+        let conn = tokio::spawn(serve_connection(queue, server, 8, Some(limiter)));
+
+        let list = msgs::MsgType::ListPsks as u8;
+        let set = msgs::MsgType::SetPsk as u8;
+        let requests = [list, set, list, list, set, set];
+        for (i, &msg_type) in requests.iter().enumerate() {
+            send(&mut client, &[msg_type, i as u8]).await;
+        }
+        send(&mut client, &[msgs::MsgType::Goodbye as u8, 6]).await;
+
+        // Listing does not use up the budget of the two set_psk requests
+        let mut forwarded = Vec::new();
+        for _ in 0..6 {
+            let req = broker.recv().await.unwrap();
+            forwarded.push(req.request[1]);
+            reply(req);
+        }
+        assert_eq!(forwarded, [0, 1, 2, 3, 4, 6]);
+
+        for i in 0..5 {
+            assert_eq!(recv(&mut client).await, [i]);
+        }
+        assert_eq!(recv(&mut client).await, rate_limited_response());
+        assert_eq!(recv(&mut client).await, [6]);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_message() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, _broker) = mpsc::channel(1);
+        let conn = tokio::spawn(serve_connection(queue, server, 1, None));
+
+        send(&mut client, &[0u8; msgs::REQUEST_MSG_BUFFER_SIZE + 1]).await;
+        assert!(conn.await.unwrap().is_err());
+    }
+
+    /// Feed `stream` to a connection in chunks of `chunk` bytes, returning whether it ended
+    /// cleanly and the requests it passed on to the broker
+    async fn feed(stream: &[u8], chunk: usize) -> (bool, Vec<Vec<u8>>) {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (queue, mut broker) = mpsc::channel(DEFAULT_MAX_QUEUED_REQUESTS);
+        let conn = tokio::spawn(serve_connection(
+            queue,
+            server,
+            DEFAULT_MAX_QUEUED_REQUESTS,
+            None,
+        ));
+        let broker = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            while let Some(req) = broker.recv().await {
+                let _ = req.reply_to.send(BrokerResponse { response: vec![0] });
+                requests.push(req.request);
+            }
+            requests
+        });
+
+        for part in stream.chunks(chunk) {
+            // The connection may have stopped reading already
+            if client.write_all(part).await.is_err() {
+                break;
+            }
+        }
+        let _ = client.shutdown().await;
+
+        let ok = conn.await.unwrap().is_ok();
+        (ok, broker.await.unwrap())
+    }
+
+    /// Reference decoder: whether the stream ends with a goodbye, and the requests up to
+    /// the goodbye or the first malformed frame
+    fn decode(mut stream: &[u8]) -> (bool, Vec<Vec<u8>>) {
+        let mut requests = Vec::new();
+        while stream.len() >= LEN_SIZE {
+            let (len, rest) = stream.split_at(LEN_SIZE);
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            if len > msgs::REQUEST_MSG_BUFFER_SIZE as u64 || rest.len() < len as usize {
+                break;
+            }
+            let (msg, rest) = rest.split_at(len as usize);
+            requests.push(msg.to_vec());
+            if msg.first() == Some(&(msgs::MsgType::Goodbye as u8)) {
+                return (true, requests);
+            }
+            stream = rest;
+        }
+        (false, requests)
+    }
+
+    fn frame(msg: &[u8]) -> Vec<u8> {
+        [&len_prefix(msg)[..], msg].concat()
+    }
+
+    async fn check(stream: &[u8], chunk: usize) {
+        let (ok, requests) = feed(stream, chunk).await;
+        assert!(requests
+            .iter()
+            .all(|r| r.len() <= msgs::REQUEST_MSG_BUFFER_SIZE));
+        assert_eq!((ok, requests), decode(stream), "chunk size {chunk}");
+    }
+
+    #[tokio::test]
+    async fn decodes_split_frames() {
+        let set_psk = frame(&[msgs::MsgType::SetPsk as u8; msgs::REQUEST_MSG_BUFFER_SIZE]);
+        let goodbye = frame(&[msgs::MsgType::Goodbye as u8, 0, 0, 0]);
+        let oversized = frame(&[0u8; msgs::REQUEST_MSG_BUFFER_SIZE + 1]);
+        let huge_prefix = u64::MAX.to_le_bytes().to_vec();
+
+        let seeds: Vec<Vec<u8>> = vec![
+            // Valid frames
+            [&set_psk[..], &frame(b""), &goodbye].concat(),
+            goodbye.clone(),
+            // Oversized prefixes
+            [&set_psk[..], &oversized, &goodbye].concat(),
+            [&huge_prefix[..], &set_psk].concat(),
+            // Truncated frames
+            vec![],
+            set_psk.clone(),
+            [&set_psk[..], &goodbye[..3]].concat(),
+            [&set_psk[..], &set_psk[..LEN_SIZE + 10]].concat(),
+            goodbye[..goodbye.len() - 1].to_vec(),
+        ];
+        for seed in seeds {
+            for chunk in [1, 3, LEN_SIZE, LEN_SIZE + 1, 64, 4096] {
+                check(&seed, chunk).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_random_streams() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let mut stream = Vec::new();
+            for _ in 0..rng.gen_range(0..5) {
+                let len = rng.gen_range(0..=msgs::REQUEST_MSG_BUFFER_SIZE + 2);
+                let mut msg = vec![0u8; len];
+                rng.fill(&mut msg[..]);
+                stream.extend(frame(&msg));
+            }
+            // Random garbage or truncation at the end
+            match rng.gen_range(0..3) {
+                0 => stream.extend((0..rng.gen_range(0..32)).map(|_| rng.gen::<u8>())),
+                1 => stream.truncate(rng.gen_range(0..=stream.len())),
+                _ => {}
+            }
+            check(&stream, rng.gen_range(1..=64)).await;
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{self, Write};
+
+use rosenpass_secret_memory::Secret;
+use zeroize::Zeroize;
+
+use crate::api::msgs::REQUEST_MSG_BUFFER_SIZE;
+
+/// Size of the length prefix preceding every message on the broker socket
+pub const LEN_SIZE: usize = 8;
+
+/// Size of a framed request, including the length prefix
+pub const FRAMED_REQUEST_SIZE: usize = LEN_SIZE + REQUEST_MSG_BUFFER_SIZE;
+
+/// The length prefix for `msg`, as written by [MessageWriter::finish]
+pub fn len_prefix(msg: &[u8]) -> [u8; LEN_SIZE] {
+    (msg.len() as u64).to_le_bytes()
+}
+
+/// Builds a length-prefixed message in secret memory
+///
+/// Message contents are appended through [std::io::Write]; [Self::finish] writes the
+/// little-endian `u64` length prefix and returns the framed message. `N` is the size
+/// of the framed message, including the [LEN_SIZE] byte prefix.
+///
+/// Writes beyond the buffer size fail with [io::ErrorKind::WriteZero].
+#[derive(Debug)]
+pub struct MessageWriter<const N: usize = FRAMED_REQUEST_SIZE> {
+    buf: Secret<N>,
+    off: usize,
+}
+
+impl<const N: usize> MessageWriter<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: Secret::zero(),
+            off: LEN_SIZE,
+        }
+    }
+
+    /// The message written so far, without length prefix
+    pub fn message(&self) -> &[u8] {
+        &self.buf.secret()[LEN_SIZE..self.off]
+    }
+
+    /// Wipe the message written so far, so the writer can be reused for another one
+    pub fn clear(&mut self) {
+        self.buf.secret_mut()[..self.off].zeroize();
+        self.off = LEN_SIZE;
+    }
+
+    /// Write the length prefix and return the framed message
+    pub fn finish(&mut self) -> &[u8] {
+        let prefix = len_prefix(self.message());
+        self.buf.secret_mut()[..LEN_SIZE].copy_from_slice(&prefix);
+        &self.buf.secret()[..self.off]
+    }
+}
+
+impl<const N: usize> Default for MessageWriter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for MessageWriter<N> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let dst = &mut self.buf.secret_mut()[self.off..];
+        let len = data.len().min(dst.len());
+        dst[..len].copy_from_slice(&data[..len]);
+        self.off += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_writer_multi_field() {
+        let mut w = MessageWriter::<{ LEN_SIZE + 16 }>::new();
+        w.write_all(&[0x01, 0, 0, 0]).unwrap();
+        w.write_all(b"peer").unwrap();
+        w.write_all(&3u8.to_le_bytes()).unwrap();
+        w.write_all(b"wg0").unwrap();
+        assert_eq!(w.message().len(), 12);
+
+        let framed = w.finish().to_vec();
+        let (len, msg) = framed.split_at(LEN_SIZE);
+        let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+        assert_eq!(len, msg.len());
+
+        let (typ, rest) = msg.split_at(4);
+        assert_eq!(typ, &[0x01, 0, 0, 0]);
+        let (peer, rest) = rest.split_at(4);
+        assert_eq!(peer, b"peer");
+        let (iface_len, iface) = rest.split_at(1);
+        assert_eq!(iface_len[0] as usize, iface.len());
+        assert_eq!(iface, b"wg0");
+    }
+
+    #[test]
+    fn message_writer_reuse() {
+        let mut w = MessageWriter::<{ LEN_SIZE + 8 }>::new();
+        w.write_all(b"long msg").unwrap();
+        w.finish();
+        w.clear();
+        assert!(w.message().is_empty());
+        assert_eq!(w.buf.secret(), &[0u8; LEN_SIZE + 8]);
+
+        w.write_all(b"msg").unwrap();
+        assert_eq!(w.finish(), &[3, 0, 0, 0, 0, 0, 0, 0, b'm', b's', b'g']);
+    }
+
+    #[test]
+    fn message_writer_overflow() {
+        let mut w = MessageWriter::<{ LEN_SIZE + 4 }>::new();
+        w.write_all(b"four").unwrap();
+        let err = w.write_all(b"!").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(
+            w.finish(),
+            &[4, 0, 0, 0, 0, 0, 0, 0, b'f', b'o', b'u', b'r']
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! A [BrokerClientIo] without a socket, for testing code built on [BrokerClient]
+//!
+//! [BrokerClient]: crate::api::client::BrokerClient
+
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+
+use crate::api::client::BrokerClientIo;
+use crate::api::msgs::{self, Envelope, SetPskResponse};
+
+/// Error returned by [InMemoryIo] once it has been [closed](InMemoryIo::close)
+#[derive(thiserror::Error, Debug, Clone, Copy, Eq, PartialEq)]
+#[error("In-memory broker connection is closed")]
+pub struct InMemoryIoClosed;
+
+/// Records the messages sent by a client and hands out canned responses
+///
+/// Messages are stored without framing, exactly as passed to [BrokerClientIo::send_msg].
+/// Of file descriptors passed along, only their numbers are recorded.
+#[derive(Debug, Default)]
+pub struct InMemoryIo {
+    sent: VecDeque<Vec<u8>>,
+    sent_fds: VecDeque<Vec<RawFd>>,
+    responses: VecDeque<Vec<u8>>,
+    recv_buf: Vec<u8>,
+    closed: bool,
+}
+
+impl InMemoryIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The messages sent so far and not yet taken by [Self::pop_sent]
+    pub fn sent(&self) -> &VecDeque<Vec<u8>> {
+        &self.sent
+    }
+
+    /// Take the oldest message sent
+    pub fn pop_sent(&mut self) -> Option<Vec<u8>> {
+        self.sent_fds.pop_front();
+        self.sent.pop_front()
+    }
+
+    /// The numbers of the file descriptors sent along with the oldest message sent
+    pub fn sent_fds(&self) -> Option<&[RawFd]> {
+        self.sent_fds.front().map(Vec::as_slice)
+    }
+
+    /// Queue a raw response message
+    pub fn push_response(&mut self, msg: impl Into<Vec<u8>>) {
+        self.responses.push_back(msg.into());
+    }
+
+    /// Queue a response to a set_psk request
+    pub fn push_set_psk_response(&mut self, return_code: msgs::SetPskResponseReturnCode) {
+        let mut res = [0u8; msgs::RESPONSE_MSG_BUFFER_SIZE];
+        let mut env =
+            zerocopy::Ref::<&mut [u8], Envelope<SetPskResponse>>::new(&mut res[..]).unwrap();
+        env.msg_type = msgs::MsgType::SetPsk as u8;
+        env.payload.return_code = return_code as u8;
+        self.push_response(res);
+    }
+
+    /// Fail all further sends and receives with [InMemoryIoClosed]
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+impl BrokerClientIo for InMemoryIo {
+    type SendError = InMemoryIoClosed;
+    type RecvError = InMemoryIoClosed;
+
+    fn send_msg(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
+        self.send_msg_with_fds(buf, &[])
+    }
+
+    fn supports_fds(&self) -> bool {
+        true
+    }
+
+    fn send_msg_with_fds(
+        &mut self,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Result<(), Self::SendError> {
+        if self.closed {
+            return Err(InMemoryIoClosed);
+        }
+        self.sent.push_back(buf.to_vec());
+        self.sent_fds
+            .push_back(fds.iter().map(AsRawFd::as_raw_fd).collect());
+        Ok(())
+    }
+
+    fn recv_msg(&mut self) -> Result<Option<&[u8]>, Self::RecvError> {
+        if self.closed {
+            return Err(InMemoryIoClosed);
+        }
+        match self.responses.pop_front() {
+            Some(res) => {
+                self.recv_buf = res;
+                Ok(Some(&self.recv_buf))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::client::BrokerClient;
+    use crate::test_fixtures::{config, random_psk};
+    use crate::{SerializedBrokerConfig, WireGuardBroker};
+
+    use super::*;
+
+    #[test]
+    fn set_psk_encoding() {
+        let (peer_id, psk) = random_psk();
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        client
+            .set_psk(SerializedBrokerConfig {
+                slot: 3,
+                ..config(b"wg0", &peer_id, &psk)
+            })
+            .unwrap();
+
+        let mut expected = vec![msgs::MsgType::SetPsk as u8, 3, 0, 0];
+        expected.extend_from_slice(&peer_id.0.value);
+        expected.extend_from_slice(psk.secret());
+        expected.push(3);
+        expected.extend_from_slice(b"wg0");
+        expected.resize(expected.len() + 255 - 3, 0);
+        assert_eq!(expected.len(), msgs::REQUEST_MSG_BUFFER_SIZE);
+
+        let io = client.io_mut();
+        assert_eq!(io.pop_sent().unwrap(), expected);
+        assert!(io.pop_sent().is_none());
+    }
+
+    #[test]
+    fn canned_responses() {
+        let mut client = BrokerClient::new(InMemoryIo::new());
+        assert_eq!(client.poll_response(), Ok(None));
+
+        let io = client.io_mut();
+        io.push_set_psk_response(msgs::SetPskResponseReturnCode::NoSuchPeer);
+        io.push_response([msgs::MsgType::SetPsk as u8, 0, 0, 0, 0]);
+        assert_eq!(
+            client.poll_response(),
+            Ok(Some(Err(msgs::SetPskError::NoSuchPeer)))
+        );
+        assert_eq!(client.poll_response(), Ok(Some(Ok(()))));
+        assert_eq!(client.poll_response(), Ok(None));
+
+        client.io_mut().close();
+        assert!(client.poll_response().is_err());
+    }
+
+    #[test]
+    fn records_fds() {
+        use std::os::fd::AsFd;
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let mut io = InMemoryIo::new();
+        assert!(io.supports_fds());
+        io.send_msg(b"plain").unwrap();
+        io.send_msg_with_fds(b"fds", &[file.as_fd()]).unwrap();
+
+        assert_eq!(io.sent_fds(), Some(&[][..]));
+        assert_eq!(io.pop_sent().unwrap(), b"plain");
+        assert_eq!(io.sent_fds(), Some(&[file.as_raw_fd()][..]));
+        assert_eq!(io.pop_sent().unwrap(), b"fds");
+        assert_eq!(io.sent_fds(), None);
+    }
+}
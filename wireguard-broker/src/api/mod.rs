@@ -1,4 +1,11 @@
 pub mod client;
 pub mod config;
+pub mod connection;
+pub mod framing;
+#[cfg(any(test, feature = "testing"))]
+pub mod in_memory;
 pub mod msgs;
+pub mod owned;
+#[cfg(any(test, feature = "testing"))]
+pub mod recording;
 pub mod server;
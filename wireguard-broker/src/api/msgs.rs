@@ -3,16 +3,29 @@ use std::str::{from_utf8, Utf8Error};
 
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+use crate::{PEER_ID_LEN, WG_KEY_LEN};
+
 pub const ENVELOPE_OVERHEAD: usize = 1 + 3;
-pub const REQUEST_MSG_BUFFER_SIZE: usize = ENVELOPE_OVERHEAD + 32 + 32 + 1 + 255;
+pub const REQUEST_MSG_BUFFER_SIZE: usize = ENVELOPE_OVERHEAD + PEER_ID_LEN + WG_KEY_LEN + 1 + 255;
 pub const RESPONSE_MSG_BUFFER_SIZE: usize = ENVELOPE_OVERHEAD + 1;
 
+/// Maximum number of peers listed in a single [MsgType::ListPsks] response
+pub const MAX_LISTED_PEERS: usize = 64;
+/// Size of the largest response, a [MsgType::ListPsks] response listing
+/// [MAX_LISTED_PEERS] peers
+pub const MAX_RESPONSE_MSG_SIZE: usize =
+    ENVELOPE_OVERHEAD + std::mem::size_of::<ListPsksResponse>() + MAX_LISTED_PEERS * PEER_ID_LEN;
+
 #[repr(packed)]
 #[derive(AsBytes, FromBytes, FromZeroes)]
 pub struct Envelope<M: AsBytes + FromBytes> {
     /// [MsgType] of this message
     pub msg_type: u8,
     /// Reserved for future use
+    ///
+    /// The first byte of a [MsgType::SetPsk] request holds the PSK slot; see
+    /// [Envelope::slot]. Using a reserved byte keeps the request size unchanged, so
+    /// clients and brokers that predate slots still understand each other.
     pub reserved: [u8; 3],
     /// The actual Paylod
     pub payload: M,
@@ -21,16 +34,44 @@ pub struct Envelope<M: AsBytes + FromBytes> {
 #[repr(packed)]
 #[derive(AsBytes, FromBytes, FromZeroes)]
 pub struct SetPskRequest {
-    pub peer_id: [u8; 32],
-    pub psk: [u8; 32],
+    pub peer_id: [u8; PEER_ID_LEN],
+    /// Always exactly [WG_KEY_LEN] bytes; requests of any other size are invalid messages
+    pub psk: [u8; WG_KEY_LEN],
     pub iface_size: u8, // TODO: We should have variable length strings in lenses
     pub iface_buf: [u8; 255],
 }
 
+fn iface_bin(iface_size: u8, iface_buf: &[u8; 255]) -> &[u8] {
+    &iface_buf[..iface_size as usize]
+}
+
+fn set_iface_bin(iface_size: &mut u8, iface_buf: &mut [u8; 255], iface: &[u8]) -> Option<()> {
+    (iface.len() < 256).then_some(())?; // Assert iface.len() < 256
+
+    *iface_size = iface.len() as u8;
+
+    *iface_buf = [0; 255];
+    (&mut iface_buf[..iface.len()]).copy_from_slice(iface);
+
+    Some(())
+}
+
+impl Envelope<SetPskRequest> {
+    /// PSK slot of the peer; see [crate::SerializedBrokerConfig::slot]
+    ///
+    /// Requests from clients without slot support leave this at [crate::DEFAULT_PSK_SLOT].
+    pub fn slot(&self) -> u8 {
+        self.reserved[0]
+    }
+
+    pub fn set_slot(&mut self, slot: u8) {
+        self.reserved[0] = slot;
+    }
+}
+
 impl SetPskRequest {
     pub fn iface_bin(&self) -> &[u8] {
-        let len = self.iface_size as usize;
-        &self.iface_buf[..len]
+        iface_bin(self.iface_size, &self.iface_buf)
     }
 
     pub fn iface(&self) -> Result<&str, Utf8Error> {
@@ -38,14 +79,53 @@ impl SetPskRequest {
     }
 
     pub fn set_iface_bin(&mut self, iface: &[u8]) -> Option<()> {
-        (iface.len() < 256).then_some(())?; // Assert iface.len() < 256
+        set_iface_bin(&mut self.iface_size, &mut self.iface_buf, iface)
+    }
+
+    pub fn set_iface(&mut self, iface: &str) -> Option<()> {
+        self.set_iface_bin(iface.as_bytes())
+    }
+}
+
+#[repr(packed)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+pub struct SetPskResponse {
+    pub return_code: u8,
+}
 
-        self.iface_size = iface.len() as u8;
+/// Payload of [MsgType::Goodbye] requests and their acknowledgements
+///
+/// A client sends a goodbye message when it is done; the server acknowledges it
+/// with a goodbye message of its own once all previous requests were processed.
+/// Afterwards, both sides close the connection.
+#[repr(packed)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+pub struct Goodbye {}
 
-        self.iface_buf = [0; 255];
-        (&mut self.iface_buf[..iface.len()]).copy_from_slice(iface);
+/// Payload of [MsgType::ListPsks] requests
+///
+/// Asks for the peers of an interface that had a PSK set through the broker, starting at
+/// the `offset`th peer in ascending order of their ids.
+#[repr(packed)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+pub struct ListPsksRequest {
+    pub iface_size: u8,
+    pub iface_buf: [u8; 255],
+    /// Number of peers to skip, little endian
+    pub offset: [u8; 4],
+}
 
-        Some(())
+impl ListPsksRequest {
+    pub fn iface_bin(&self) -> &[u8] {
+        iface_bin(self.iface_size, &self.iface_buf)
+    }
+
+    pub fn iface(&self) -> Result<&str, Utf8Error> {
+        from_utf8(self.iface_bin())
+    }
+
+    pub fn set_iface_bin(&mut self, iface: &[u8]) -> Option<()> {
+        set_iface_bin(&mut self.iface_size, &mut self.iface_buf, iface)
     }
 
     pub fn set_iface(&mut self, iface: &str) -> Option<()> {
@@ -53,10 +133,16 @@ impl SetPskRequest {
     }
 }
 
+/// Fixed part of [MsgType::ListPsks] responses
+///
+/// Followed by the ids of up to [MAX_LISTED_PEERS] peers, [PEER_ID_LEN] bytes each; the
+/// number of peers is given by the size of the message. Fewer than `total - offset` peers
+/// means the client has to ask again for the rest.
 #[repr(packed)]
 #[derive(AsBytes, FromBytes, FromZeroes)]
-pub struct SetPskResponse {
-    pub return_code: u8,
+pub struct ListPsksResponse {
+    /// Number of peers of the interface with a PSK, little endian
+    pub total: [u8; 4],
 }
 
 #[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
@@ -67,17 +153,21 @@ pub enum SetPskError {
     NoSuchInterface,
     #[error("The indicated peer does not exist on the wireguard interface")]
     NoSuchPeer,
+    #[error("The broker rejected the request because too many requests were made")]
+    RateLimited,
 }
 
 pub type SetPskResult = Result<(), SetPskError>;
 
 #[repr(u8)]
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SetPskResponseReturnCode {
     Success = 0x00,
     InternalError = 0x01,
     NoSuchInterface = 0x02,
     NoSuchPeer = 0x03,
+    RateLimited = 0x04,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -93,6 +183,7 @@ impl TryFrom<u8> for SetPskResponseReturnCode {
             0x01 => Ok(InternalError),
             0x02 => Ok(NoSuchInterface),
             0x03 => Ok(NoSuchPeer),
+            0x04 => Ok(RateLimited),
             _ => Err(InvalidSetPskResponseError),
         }
     }
@@ -107,6 +198,7 @@ impl From<SetPskResponseReturnCode> for SetPskResult {
             C::InternalError => Err(E::InternalError),
             C::NoSuchInterface => Err(E::NoSuchInterface),
             C::NoSuchPeer => Err(E::NoSuchPeer),
+            C::RateLimited => Err(E::RateLimited),
         }
     }
 }
@@ -120,6 +212,7 @@ impl From<SetPskResult> for SetPskResponseReturnCode {
             Err(E::InternalError) => C::InternalError,
             Err(E::NoSuchInterface) => C::NoSuchInterface,
             Err(E::NoSuchPeer) => C::NoSuchPeer,
+            Err(E::RateLimited) => C::RateLimited,
         }
     }
 }
@@ -128,6 +221,8 @@ impl From<SetPskResult> for SetPskResponseReturnCode {
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub enum MsgType {
     SetPsk = 0x01,
+    Goodbye = 0x02,
+    ListPsks = 0x03,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -139,7 +234,170 @@ impl TryFrom<u8> for MsgType {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0x01 => Ok(MsgType::SetPsk),
+            0x02 => Ok(MsgType::Goodbye),
+            0x03 => Ok(MsgType::ListPsks),
             _ => Err(InvalidMessageTypeError),
         }
     }
 }
+
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum ParseMessageError {
+    #[error("Message has size {actual}, expected {expected}")]
+    InvalidSize { expected: usize, actual: usize },
+    #[error("Message has type {actual:#04x}, expected {expected:?}")]
+    InvalidType { expected: MsgType, actual: u8 },
+    #[error("Message has unknown type {0:#04x}")]
+    UnknownType(u8),
+    #[error("Response has unknown return code {0:#04x}")]
+    InvalidReturnCode(u8),
+    #[error("Interface name is not valid UTF-8")]
+    InvalidInterface,
+    #[error("Peer list of {0} bytes is not a valid list of peer ids")]
+    InvalidPeerList(usize),
+}
+
+/// Parse a message of a known type, copying its payload out of the buffer
+///
+/// Use [zerocopy::Ref] with [Envelope] to access the message in place instead.
+/// Parse a message of type `expected`, keeping its envelope
+pub fn parse_envelope<M: AsBytes + FromBytes>(
+    buf: &[u8],
+    expected: MsgType,
+) -> Result<Envelope<M>, ParseMessageError> {
+    let env = Envelope::<M>::read_from(buf).ok_or(ParseMessageError::InvalidSize {
+        expected: std::mem::size_of::<Envelope<M>>(),
+        actual: buf.len(),
+    })?;
+    if env.msg_type != expected as u8 {
+        return Err(ParseMessageError::InvalidType {
+            expected,
+            actual: env.msg_type,
+        });
+    }
+    Ok(env)
+}
+
+fn parse_payload<M: AsBytes + FromBytes>(
+    buf: &[u8],
+    expected: MsgType,
+) -> Result<M, ParseMessageError> {
+    parse_envelope(buf, expected).map(|env: Envelope<M>| env.payload)
+}
+
+impl TryFrom<&[u8]> for SetPskRequest {
+    type Error = ParseMessageError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_payload(buf, MsgType::SetPsk)
+    }
+}
+
+impl TryFrom<&[u8]> for SetPskResponse {
+    type Error = ParseMessageError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_payload(buf, MsgType::SetPsk)
+    }
+}
+
+impl TryFrom<&[u8]> for ListPsksRequest {
+    type Error = ParseMessageError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_payload(buf, MsgType::ListPsks)
+    }
+}
+
+impl TryFrom<&[u8]> for Goodbye {
+    type Error = ParseMessageError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        parse_payload(buf, MsgType::Goodbye)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn envelope<M: AsBytes + FromBytes>(msg_type: MsgType, payload: M) -> Vec<u8> {
+        Envelope {
+            msg_type: msg_type as u8,
+            reserved: [0; 3],
+            payload,
+        }
+        .as_bytes()
+        .to_vec()
+    }
+
+    #[test]
+    fn parse_set_psk_request() {
+        let mut req = SetPskRequest::new_zeroed();
+        req.peer_id = [0x11; PEER_ID_LEN];
+        req.psk = [0x22; WG_KEY_LEN];
+        req.set_iface("wg0").unwrap();
+        let buf = envelope(MsgType::SetPsk, req);
+        assert_eq!(buf.len(), REQUEST_MSG_BUFFER_SIZE);
+
+        let req = SetPskRequest::try_from(&buf[..]).unwrap();
+        assert_eq!(req.peer_id, [0x11; PEER_ID_LEN]);
+        assert_eq!(req.psk, [0x22; WG_KEY_LEN]);
+        assert_eq!(req.iface(), Ok("wg0"));
+    }
+
+    #[test]
+    fn set_psk_slot_in_envelope() {
+        // Brokers and clients that predate slots send and expect requests of this size
+        assert_eq!(REQUEST_MSG_BUFFER_SIZE, 324);
+
+        let mut env = Envelope {
+            msg_type: MsgType::SetPsk as u8,
+            reserved: [0; 3],
+            payload: SetPskRequest::new_zeroed(),
+        };
+        assert_eq!(env.slot(), crate::DEFAULT_PSK_SLOT);
+        env.set_slot(7);
+        let buf = env.as_bytes().to_vec();
+        assert_eq!(buf.len(), REQUEST_MSG_BUFFER_SIZE);
+        let env = parse_envelope::<SetPskRequest>(&buf, MsgType::SetPsk).unwrap();
+        assert_eq!(env.slot(), 7);
+    }
+
+    #[test]
+    fn parse_response_and_goodbye() {
+        let buf = envelope(MsgType::SetPsk, SetPskResponse { return_code: 0x03 });
+        let res = SetPskResponse::try_from(&buf[..]).unwrap();
+        assert_eq!(
+            SetPskResponseReturnCode::try_from(res.return_code),
+            Ok(SetPskResponseReturnCode::NoSuchPeer)
+        );
+
+        let buf = envelope(MsgType::Goodbye, Goodbye {});
+        assert_eq!(buf.len(), ENVELOPE_OVERHEAD);
+        assert!(Goodbye::try_from(&buf[..]).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_wrong_type_or_size() {
+        let buf = envelope(MsgType::Goodbye, SetPskResponse { return_code: 0 });
+        assert_eq!(
+            SetPskResponse::try_from(&buf[..]).err(),
+            Some(ParseMessageError::InvalidType {
+                expected: MsgType::SetPsk,
+                actual: MsgType::Goodbye as u8,
+            })
+        );
+
+        let buf = envelope(MsgType::SetPsk, SetPskRequest::new_zeroed());
+        assert_eq!(
+            SetPskRequest::try_from(&buf[..buf.len() - 1]).err(),
+            Some(ParseMessageError::InvalidSize {
+                expected: REQUEST_MSG_BUFFER_SIZE,
+                actual: REQUEST_MSG_BUFFER_SIZE - 1,
+            })
+        );
+        assert!(SetPskResponse::try_from(&buf[..]).is_err());
+        assert!(Goodbye::try_from(&[][..]).is_err());
+    }
+}
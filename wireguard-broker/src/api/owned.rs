@@ -0,0 +1,400 @@
+//! Owned representations of broker API messages
+//!
+//! The binary messages in [msgs] are the wire format; the types here decode and encode
+//! them for tooling and tests. With the `serde` feature, they also serialize to
+//! self-describing formats such as JSON. Pre-shared keys are redacted in serialized form;
+//! deserializing a request yields an all-zero PSK.
+
+use std::str::from_utf8;
+
+use rosenpass_secret_memory::Secret;
+use zerocopy::{AsBytes, FromZeroes};
+
+use crate::api::msgs::{
+    self, Envelope, Goodbye, ListPsksResponse, MsgType, ParseMessageError, SetPskResponse,
+    SetPskResponseReturnCode,
+};
+use crate::{PeerId, PEER_ID_LEN, WG_KEY_LEN};
+
+/// A request to the broker
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Request {
+    SetPsk(SetPsk),
+    Goodbye,
+    ListPsks(ListPsks),
+}
+
+/// Payload of [Request::SetPsk]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetPsk {
+    pub interface: String,
+    pub peer_id: PeerId,
+    #[cfg_attr(feature = "serde", serde(with = "redacted"))]
+    pub psk: Secret<WG_KEY_LEN>,
+    pub slot: u8,
+}
+
+/// Payload of [Request::ListPsks]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListPsks {
+    pub interface: String,
+    pub offset: u32,
+}
+
+/// A response from the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Response {
+    SetPsk(SetPskResponseReturnCode),
+    Goodbye,
+    ListPsks(PskList),
+}
+
+/// Payload of [Response::ListPsks]: one page of the peers with a PSK installed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PskList {
+    /// Number of peers of the interface with a PSK, including those not on this page
+    pub total: u32,
+    /// At most [msgs::MAX_LISTED_PEERS] peers, in ascending order
+    pub peers: Vec<PeerId>,
+}
+
+impl PskList {
+    /// Decode a [MsgType::ListPsks] response in wire format
+    pub fn from_wire(buf: &[u8]) -> Result<Self, ParseMessageError> {
+        let (env, peers) = zerocopy::Ref::<&[u8], Envelope<ListPsksResponse>>::new_from_prefix(buf)
+            .ok_or(ParseMessageError::InvalidSize {
+                expected: std::mem::size_of::<Envelope<ListPsksResponse>>(),
+                actual: buf.len(),
+            })?;
+        if env.msg_type != MsgType::ListPsks as u8 {
+            return Err(ParseMessageError::InvalidType {
+                expected: MsgType::ListPsks,
+                actual: env.msg_type,
+            });
+        }
+        if peers.len() % PEER_ID_LEN != 0 || peers.len() / PEER_ID_LEN > msgs::MAX_LISTED_PEERS {
+            return Err(ParseMessageError::InvalidPeerList(peers.len()));
+        }
+        Ok(Self {
+            total: u32::from_le_bytes(env.payload.total),
+            peers: peers
+                .chunks_exact(PEER_ID_LEN)
+                .map(|id| PeerId::new(id.try_into().unwrap()))
+                .collect(),
+        })
+    }
+
+    /// Encode the response in wire format
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut buf = envelope(
+            MsgType::ListPsks,
+            ListPsksResponse {
+                total: self.total.to_le_bytes(),
+            },
+        );
+        for peer_id in &self.peers {
+            buf.extend_from_slice(peer_id.as_ref());
+        }
+        buf
+    }
+}
+
+fn msg_type(buf: &[u8]) -> Result<MsgType, ParseMessageError> {
+    let typ = *buf.first().ok_or(ParseMessageError::InvalidSize {
+        expected: msgs::ENVELOPE_OVERHEAD,
+        actual: 0,
+    })?;
+    MsgType::try_from(typ).map_err(|_| ParseMessageError::UnknownType(typ))
+}
+
+fn envelope<M: AsBytes + zerocopy::FromBytes>(msg_type: MsgType, payload: M) -> Vec<u8> {
+    Envelope {
+        msg_type: msg_type as u8,
+        reserved: [0; 3],
+        payload,
+    }
+    .as_bytes()
+    .to_vec()
+}
+
+impl Request {
+    /// Decode a request in wire format
+    pub fn from_wire(buf: &[u8]) -> Result<Self, ParseMessageError> {
+        match msg_type(buf)? {
+            MsgType::SetPsk => {
+                let env = msgs::parse_envelope::<msgs::SetPskRequest>(buf, MsgType::SetPsk)?;
+                let req = &env.payload;
+                let interface = from_utf8(req.iface_bin())
+                    .map_err(|_| ParseMessageError::InvalidInterface)?
+                    .to_owned();
+                Ok(Request::SetPsk(SetPsk {
+                    interface,
+                    peer_id: PeerId::new(req.peer_id),
+                    psk: Secret::from_slice(&req.psk),
+                    slot: env.slot(),
+                }))
+            }
+            MsgType::Goodbye => Goodbye::try_from(buf).map(|_| Request::Goodbye),
+            MsgType::ListPsks => {
+                let req = msgs::ListPsksRequest::try_from(buf)?;
+                let interface = from_utf8(req.iface_bin())
+                    .map_err(|_| ParseMessageError::InvalidInterface)?
+                    .to_owned();
+                Ok(Request::ListPsks(ListPsks {
+                    interface,
+                    offset: u32::from_le_bytes(req.offset),
+                }))
+            }
+        }
+    }
+
+    /// Encode the request in wire format
+    ///
+    /// Returns [None] if the interface name does not fit into a request.
+    pub fn to_wire(&self) -> Option<Vec<u8>> {
+        match self {
+            Request::SetPsk(set_psk) => {
+                let mut req = msgs::SetPskRequest::new_zeroed();
+                req.peer_id.copy_from_slice(set_psk.peer_id.as_ref());
+                req.psk.copy_from_slice(set_psk.psk.secret());
+                req.set_iface(&set_psk.interface)?;
+                let mut env = Envelope {
+                    msg_type: MsgType::SetPsk as u8,
+                    reserved: [0; 3],
+                    payload: req,
+                };
+                env.set_slot(set_psk.slot);
+                Some(env.as_bytes().to_vec())
+            }
+            Request::Goodbye => Some(envelope(MsgType::Goodbye, Goodbye {})),
+            Request::ListPsks(list) => {
+                let mut req = msgs::ListPsksRequest::new_zeroed();
+                req.set_iface(&list.interface)?;
+                req.offset = list.offset.to_le_bytes();
+                Some(envelope(MsgType::ListPsks, req))
+            }
+        }
+    }
+}
+
+impl Response {
+    /// Decode a response in wire format
+    pub fn from_wire(buf: &[u8]) -> Result<Self, ParseMessageError> {
+        match msg_type(buf)? {
+            MsgType::SetPsk => {
+                let res = SetPskResponse::try_from(buf)?;
+                let code = SetPskResponseReturnCode::try_from(res.return_code)
+                    .map_err(|_| ParseMessageError::InvalidReturnCode(res.return_code))?;
+                Ok(Response::SetPsk(code))
+            }
+            MsgType::Goodbye => Goodbye::try_from(buf).map(|_| Response::Goodbye),
+            MsgType::ListPsks => PskList::from_wire(buf).map(Response::ListPsks),
+        }
+    }
+
+    /// Encode the response in wire format
+    pub fn to_wire(&self) -> Vec<u8> {
+        match self {
+            Response::SetPsk(code) => envelope(
+                MsgType::SetPsk,
+                SetPskResponse {
+                    return_code: *code as u8,
+                },
+            ),
+            Response::Goodbye => envelope(MsgType::Goodbye, Goodbye {}),
+            Response::ListPsks(list) => list.to_wire(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod redacted {
+    use rosenpass_secret_memory::Secret;
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::Serializer;
+
+    const REDACTED: &str = "[redacted]";
+
+    pub fn serialize<const N: usize, S: Serializer>(
+        _secret: &Secret<N>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+
+    pub fn deserialize<'de, const N: usize, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Secret<N>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s != REDACTED {
+            return Err(D::Error::custom(format!(
+                "Secrets must be serialized as \"{REDACTED}\""
+            )));
+        }
+        Ok(Secret::zero())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::WG_PEER_LEN;
+
+    fn set_psk() -> SetPsk {
+        SetPsk {
+            interface: "wg0".to_owned(),
+            peer_id: PeerId::new([0x11; WG_PEER_LEN]),
+            psk: Secret::from_slice(&[0x22; WG_KEY_LEN]),
+            slot: 3,
+        }
+    }
+
+    fn assert_set_psk_eq(a: &SetPsk, b: &SetPsk) {
+        assert_eq!(a.interface, b.interface);
+        assert_eq!(a.peer_id, b.peer_id);
+        assert_eq!(a.psk.secret(), b.psk.secret());
+        assert_eq!(a.slot, b.slot);
+    }
+
+    #[test]
+    fn request_wire_roundtrip() {
+        let buf = Request::SetPsk(set_psk()).to_wire().unwrap();
+        assert_eq!(buf.len(), msgs::REQUEST_MSG_BUFFER_SIZE);
+        match Request::from_wire(&buf).unwrap() {
+            Request::SetPsk(req) => assert_set_psk_eq(&req, &set_psk()),
+            req => panic!("Unexpected request {req:?}"),
+        }
+
+        let buf = Request::Goodbye.to_wire().unwrap();
+        assert!(matches!(Request::from_wire(&buf), Ok(Request::Goodbye)));
+
+        let list = ListPsks {
+            interface: "wg0".to_owned(),
+            offset: 0x0102,
+        };
+        let buf = Request::ListPsks(list.clone()).to_wire().unwrap();
+        assert!(matches!(Request::from_wire(&buf), Ok(Request::ListPsks(l)) if l == list));
+
+        let mut req = set_psk();
+        req.interface = "x".repeat(256);
+        assert!(Request::SetPsk(req).to_wire().is_none());
+    }
+
+    #[test]
+    fn response_wire_roundtrip() {
+        for res in [
+            Response::SetPsk(SetPskResponseReturnCode::Success),
+            Response::SetPsk(SetPskResponseReturnCode::RateLimited),
+            Response::Goodbye,
+            Response::ListPsks(PskList {
+                total: 0,
+                peers: vec![],
+            }),
+            Response::ListPsks(PskList {
+                total: 100,
+                peers: vec![PeerId::new([0x11; WG_PEER_LEN]); msgs::MAX_LISTED_PEERS],
+            }),
+        ] {
+            assert_eq!(Response::from_wire(&res.to_wire()), Ok(res));
+        }
+
+        let list = PskList {
+            total: 1,
+            peers: vec![PeerId::new([0x11; WG_PEER_LEN])],
+        };
+        let mut buf = list.to_wire();
+        assert_eq!(buf.len(), msgs::ENVELOPE_OVERHEAD + 4 + WG_PEER_LEN);
+        buf.pop();
+        assert_eq!(
+            PskList::from_wire(&buf),
+            Err(ParseMessageError::InvalidPeerList(WG_PEER_LEN - 1))
+        );
+    }
+
+    #[test]
+    fn from_wire_rejects_invalid_messages() {
+        assert_eq!(
+            Request::from_wire(&[0x7f, 0, 0, 0]).err(),
+            Some(ParseMessageError::UnknownType(0x7f))
+        );
+        assert!(Response::from_wire(&[]).is_err());
+        assert_eq!(
+            Response::from_wire(&[MsgType::SetPsk as u8, 0, 0, 0, 0x7f]),
+            Err(ParseMessageError::InvalidReturnCode(0x7f))
+        );
+
+        let mut buf = Request::SetPsk(set_psk()).to_wire().unwrap();
+        buf[msgs::ENVELOPE_OVERHEAD + 2 * 32 + 1] = 0xff;
+        assert_eq!(
+            Request::from_wire(&buf).err(),
+            Some(ParseMessageError::InvalidInterface)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn request_json_redacts_psk() {
+        let json = serde_json::to_value(Request::SetPsk(set_psk())).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "set_psk": {
+                    "interface": "wg0",
+                    "peer_id": "11".repeat(WG_PEER_LEN),
+                    "psk": "[redacted]",
+                    "slot": 3,
+                }
+            })
+        );
+
+        let req: Request = serde_json::from_value(json).unwrap();
+        let mut expected = set_psk();
+        expected.psk = Secret::zero();
+        match req {
+            Request::SetPsk(req) => assert_set_psk_eq(&req, &expected),
+            req => panic!("Unexpected request {req:?}"),
+        }
+
+        let json = serde_json::to_string(&Request::Goodbye).unwrap();
+        assert_eq!(json, "\"goodbye\"");
+        let req: Request = serde_json::from_str(&json).unwrap();
+        assert!(matches!(req, Request::Goodbye));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn request_json_rejects_plaintext_psk() {
+        let json = serde_json::json!({
+            "set_psk": {
+                "interface": "wg0",
+                "peer_id": "11".repeat(WG_PEER_LEN),
+                "psk": "22".repeat(WG_KEY_LEN),
+                "slot": 0,
+            }
+        });
+        assert!(serde_json::from_value::<Request>(json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn response_json_roundtrip() {
+        for (res, json) in [
+            (
+                Response::SetPsk(SetPskResponseReturnCode::NoSuchPeer),
+                r#"{"set_psk":"NoSuchPeer"}"#,
+            ),
+            (Response::Goodbye, r#""goodbye""#),
+        ] {
+            assert_eq!(serde_json::to_string(&res).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Response>(json).unwrap(), res);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! A [BrokerClientIo] adapter recording every message, for golden tests of the wire format
+
+use std::os::fd::{BorrowedFd, OwnedFd};
+
+use crate::api::client::BrokerClientIo;
+
+/// A message passed through a [RecordingIo]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscriptEntry {
+    /// Passed to [BrokerClientIo::send_msg] or [BrokerClientIo::send_msg_with_fds]
+    Sent(Vec<u8>),
+    /// Returned by [BrokerClientIo::recv_msg]
+    Received(Vec<u8>),
+}
+
+/// Delegates to an inner [BrokerClientIo], recording all messages in a transcript
+///
+/// Messages are recorded in the order they pass through, exactly as the inner io sees them;
+/// sends that fail are not recorded. Comparing the transcript against a committed expected
+/// transcript catches unintended changes to the encoding of broker messages.
+#[derive(Debug, Default)]
+pub struct RecordingIo<Io> {
+    inner: Io,
+    transcript: Vec<TranscriptEntry>,
+}
+
+impl<Io: BrokerClientIo> RecordingIo<Io> {
+    pub fn new(inner: Io) -> Self {
+        Self {
+            inner,
+            transcript: Vec::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &Io {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Io {
+        &mut self.inner
+    }
+
+    /// The messages sent and received so far, oldest first
+    pub fn transcript(&self) -> &[TranscriptEntry] {
+        &self.transcript
+    }
+
+    /// Take the transcript recorded so far, starting a new one
+    pub fn take_transcript(&mut self) -> Vec<TranscriptEntry> {
+        std::mem::take(&mut self.transcript)
+    }
+
+    pub fn into_inner(self) -> Io {
+        self.inner
+    }
+}
+
+impl<Io: BrokerClientIo> BrokerClientIo for RecordingIo<Io> {
+    type SendError = Io::SendError;
+    type RecvError = Io::RecvError;
+
+    fn send_msg(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
+        self.inner.send_msg(buf)?;
+        self.transcript.push(TranscriptEntry::Sent(buf.to_vec()));
+        Ok(())
+    }
+
+    fn recv_msg(&mut self) -> Result<Option<&[u8]>, Self::RecvError> {
+        let msg = self.inner.recv_msg()?;
+        if let Some(msg) = msg {
+            self.transcript
+                .push(TranscriptEntry::Received(msg.to_vec()));
+        }
+        Ok(msg)
+    }
+
+    fn supports_fds(&self) -> bool {
+        self.inner.supports_fds()
+    }
+
+    fn send_msg_with_fds(
+        &mut self,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Result<(), Self::SendError> {
+        self.inner.send_msg_with_fds(buf, fds)?;
+        self.transcript.push(TranscriptEntry::Sent(buf.to_vec()));
+        Ok(())
+    }
+
+    fn take_fds(&mut self) -> Vec<OwnedFd> {
+        self.inner.take_fds()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rosenpass_secret_memory::{Public, Secret};
+
+    use crate::api::client::BrokerClient;
+    use crate::api::in_memory::InMemoryIo;
+    use crate::api::msgs::SetPskResponseReturnCode;
+    use crate::{PeerId, SerializedBrokerConfig, WireGuardBroker};
+
+    use super::TranscriptEntry::{Received, Sent};
+    use super::*;
+
+    /// Pins the encoding of a set_psk request and its response
+    ///
+    /// If this fails, the wire format changed; that breaks brokers and clients built from
+    /// different versions, so only update the expected transcript deliberately.
+    #[test]
+    fn set_psk_transcript() {
+        let mut client = BrokerClient::new(RecordingIo::new(InMemoryIo::new()));
+        client
+            .set_psk(SerializedBrokerConfig {
+                interface: b"wg0",
+                peer_id: &PeerId::from(Public::new([0x11; 32])),
+                psk: &Secret::from_slice(&[0x22; 32]),
+                additional_params: &[],
+                slot: 7,
+            })
+            .unwrap();
+        client
+            .io_mut()
+            .inner_mut()
+            .push_set_psk_response(SetPskResponseReturnCode::NoSuchPeer);
+        assert!(client.poll_response().unwrap().is_some());
+
+        let request = [
+            &[0x01, 0x07, 0x00, 0x00][..], // message type, slot, padding
+            &[0x11; 32],                   // peer id
+            &[0x22; 32],                   // psk
+            &[0x03],                       // interface name length
+            b"wg0",
+            &[0x00; 252],
+        ]
+        .concat();
+        let response = vec![0x01, 0x00, 0x00, 0x00, 0x03];
+        assert_eq!(
+            client.io().transcript(),
+            &[Sent(request), Received(response)]
+        );
+
+        client.io_mut().take_transcript();
+        assert!(client.io().transcript().is_empty());
+    }
+}
@@ -1,10 +1,13 @@
 use std::borrow::BorrowMut;
+use std::collections::{BTreeMap, BTreeSet};
 use std::result::Result;
 
-use rosenpass_secret_memory::{Public, Secret};
+use rosenpass_secret_memory::Secret;
 
-use crate::api::msgs::{self, Envelope, SetPskRequest, SetPskResponse};
-use crate::WireGuardBroker;
+use crate::api::msgs::{
+    self, Envelope, Goodbye, ListPsksRequest, ListPsksResponse, SetPskRequest, SetPskResponse,
+};
+use crate::{PeerId, WireGuardBroker};
 
 use super::config::{NetworkBrokerConfigBuilder, NetworkBrokerConfigErr};
 
@@ -31,6 +34,8 @@ where
     msgs::SetPskError: From<Err>,
 {
     inner: Inner,
+    // Peers whose PSK was set successfully and not disabled since, by interface
+    installed: BTreeMap<String, BTreeSet<PeerId>>,
 }
 
 impl<Err, Inner> BrokerServer<Err, Inner>
@@ -39,38 +44,116 @@ where
     msgs::SetPskError: From<Err>,
 {
     pub fn new(inner: Inner) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            installed: BTreeMap::new(),
+        }
     }
 
+    /// The peers of `interface` whose PSK was set through this server, in ascending order
+    ///
+    /// This is what [msgs::MsgType::ListPsks] requests are answered with. It reflects the
+    /// requests the inner broker succeeded with, not the state of WireGuard itself. Setting
+    /// the all-zero PSK disables the PSK in WireGuard, so it removes the peer from the list.
+    pub fn installed_psks(&self, interface: &str) -> impl Iterator<Item = &PeerId> {
+        self.installed.get(interface).into_iter().flatten()
+    }
+
+    /// Handle the request in `req`, writing the response to `res`
+    ///
+    /// Returns the size of the response.
     pub fn handle_message(
         &mut self,
         req: &[u8],
-        res: &mut [u8; msgs::RESPONSE_MSG_BUFFER_SIZE],
+        res: &mut [u8; msgs::MAX_RESPONSE_MSG_SIZE],
     ) -> Result<usize, BrokerServerError> {
         use BrokerServerError::*;
 
         let typ = req.get(0).ok_or(InvalidMessage)?;
-        let typ = msgs::MsgType::try_from(*typ)?;
-        let msgs::MsgType::SetPsk = typ; // Assert type
+        match msgs::MsgType::try_from(*typ)? {
+            msgs::MsgType::SetPsk => self.handle_set_psk_msg(req, res),
+            msgs::MsgType::Goodbye => self.handle_goodbye_msg(req, res),
+            msgs::MsgType::ListPsks => self.handle_list_psks_msg(req, res),
+        }
+    }
 
+    fn handle_set_psk_msg(
+        &mut self,
+        req: &[u8],
+        res: &mut [u8; msgs::MAX_RESPONSE_MSG_SIZE],
+    ) -> Result<usize, BrokerServerError> {
         let req = zerocopy::Ref::<&[u8], Envelope<SetPskRequest>>::new(req)
             .ok_or(BrokerServerError::InvalidMessage)?;
-        let mut res = zerocopy::Ref::<&mut [u8], Envelope<SetPskResponse>>::new(res)
+        let (mut res, _) =
+            zerocopy::Ref::<&mut [u8], Envelope<SetPskResponse>>::new_from_prefix(&mut res[..])
+                .ok_or(BrokerServerError::InvalidMessage)?;
+
+        res.msg_type = msgs::MsgType::SetPsk as u8;
+        self.handle_set_psk(&req.payload, req.slot(), &mut res.payload)?;
+        Ok(res.bytes().len())
+    }
+
+    /// Acknowledge a goodbye message
+    ///
+    /// Requests are processed in order, so all previous requests have been handled
+    /// by the time the acknowledgement is sent.
+    fn handle_goodbye_msg(
+        &mut self,
+        req: &[u8],
+        res: &mut [u8; msgs::MAX_RESPONSE_MSG_SIZE],
+    ) -> Result<usize, BrokerServerError> {
+        zerocopy::Ref::<&[u8], Envelope<Goodbye>>::new(req)
             .ok_or(BrokerServerError::InvalidMessage)?;
+        let (mut res, _) =
+            zerocopy::Ref::<&mut [u8], Envelope<Goodbye>>::new_from_prefix(&mut res[..])
+                .ok_or(BrokerServerError::InvalidMessage)?;
 
-        res.payload.return_code = msgs::MsgType::SetPsk as u8;
-        self.handle_set_psk(&req.payload, &mut res.payload)?;
+        res.msg_type = msgs::MsgType::Goodbye as u8;
         Ok(res.bytes().len())
     }
 
+    /// List a page of the peers with a PSK installed; see [msgs::ListPsksResponse]
+    fn handle_list_psks_msg(
+        &mut self,
+        req: &[u8],
+        res: &mut [u8; msgs::MAX_RESPONSE_MSG_SIZE],
+    ) -> Result<usize, BrokerServerError> {
+        let req = zerocopy::Ref::<&[u8], Envelope<ListPsksRequest>>::new(req)
+            .ok_or(BrokerServerError::InvalidMessage)?;
+        let interface = req
+            .payload
+            .iface()
+            .map_err(|_e| BrokerServerError::InvalidMessage)?;
+        let offset = u32::from_le_bytes(req.payload.offset) as usize;
+
+        let (mut header, peers) =
+            zerocopy::Ref::<&mut [u8], Envelope<ListPsksResponse>>::new_from_prefix(&mut res[..])
+                .ok_or(BrokerServerError::InvalidMessage)?;
+        header.msg_type = msgs::MsgType::ListPsks as u8;
+        let total = self.installed_psks(interface).count();
+        header.payload.total = (total as u32).to_le_bytes();
+
+        let page = self
+            .installed_psks(interface)
+            .skip(offset)
+            .take(msgs::MAX_LISTED_PEERS);
+        let mut len = header.bytes().len();
+        for (dst, peer_id) in peers.chunks_exact_mut(crate::PEER_ID_LEN).zip(page) {
+            dst.copy_from_slice(peer_id.as_ref());
+            len += crate::PEER_ID_LEN;
+        }
+        Ok(len)
+    }
+
     fn handle_set_psk(
         &mut self,
         req: &SetPskRequest,
+        slot: u8,
         res: &mut SetPskResponse,
     ) -> Result<(), BrokerServerError> {
         // Using unwrap here since lenses can not return fixed-size arrays
         // TODO: Slices should give access to fixed size arrays
-        let peer_id = Public::from_slice(&req.peer_id);
+        let peer_id = PeerId::from_slice(&req.peer_id);
         let psk = Secret::from_slice(&req.psk);
 
         let interface = req
@@ -81,13 +164,218 @@ where
             .peer_id(&peer_id)
             .psk(&psk)
             .iface(interface)
+            .slot(slot)
             .build()
             .unwrap();
         let r: Result<(), Err> = self.inner.borrow_mut().set_psk(config.into());
         let r: msgs::SetPskResult = r.map_err(|e| e.into());
+        if r.is_ok() {
+            self.record_installed(interface, peer_id, req.psk != [0u8; crate::WG_KEY_LEN]);
+        }
         let r: msgs::SetPskResponseReturnCode = r.into();
         res.return_code = r as u8;
 
         Ok(())
     }
+
+    fn record_installed(&mut self, interface: &str, peer_id: PeerId, installed: bool) {
+        if installed {
+            self.installed
+                .entry(interface.to_owned())
+                .or_default()
+                .insert(peer_id);
+        } else if let Some(peers) = self.installed.get_mut(interface) {
+            peers.remove(&peer_id);
+            if peers.is_empty() {
+                self.installed.remove(interface);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_fixtures::MockBroker;
+
+    use super::*;
+
+    type Server = BrokerServer<msgs::SetPskError, MockBroker<msgs::SetPskError>>;
+
+    fn set_psk_slot(server: &mut Server, slot: u8) -> u8 {
+        set_psk_for(server, "wg0", PeerId::new([0; 32]), slot)
+    }
+
+    fn set_psk_for(server: &mut Server, iface: &str, peer_id: PeerId, slot: u8) -> u8 {
+        send_set_psk(server, iface, peer_id, slot, [0x22; crate::WG_KEY_LEN])
+    }
+
+    /// Set the all-zero PSK, which disables the PSK of the peer
+    fn disable_psk(server: &mut Server, iface: &str, peer_id: PeerId) -> u8 {
+        send_set_psk(server, iface, peer_id, 0, [0; crate::WG_KEY_LEN])
+    }
+
+    fn send_set_psk(
+        server: &mut Server,
+        iface: &str,
+        peer_id: PeerId,
+        slot: u8,
+        psk: [u8; crate::WG_KEY_LEN],
+    ) -> u8 {
+        let mut req = [0u8; msgs::REQUEST_MSG_BUFFER_SIZE];
+        let mut req_env =
+            zerocopy::Ref::<&mut [u8], Envelope<SetPskRequest>>::new(&mut req[..]).unwrap();
+        req_env.msg_type = msgs::MsgType::SetPsk as u8;
+        req_env.payload.peer_id.copy_from_slice(peer_id.as_ref());
+        req_env.payload.psk = psk;
+        req_env.payload.set_iface(iface).unwrap();
+        req_env.set_slot(slot);
+
+        let mut res = [0u8; msgs::MAX_RESPONSE_MSG_SIZE];
+        let len = server.handle_message(&req, &mut res).unwrap();
+        assert_eq!(res[0], msgs::MsgType::SetPsk as u8);
+        let res = zerocopy::Ref::<&[u8], Envelope<SetPskResponse>>::new(&res[..len]).unwrap();
+        res.payload.return_code
+    }
+
+    /// Returns the total number of peers and the listed page
+    fn list_psks(server: &mut Server, iface: &str, offset: u32) -> (u32, Vec<PeerId>) {
+        let mut req = [0u8; std::mem::size_of::<Envelope<ListPsksRequest>>()];
+        let mut req_env =
+            zerocopy::Ref::<&mut [u8], Envelope<ListPsksRequest>>::new(&mut req[..]).unwrap();
+        req_env.msg_type = msgs::MsgType::ListPsks as u8;
+        req_env.payload.set_iface(iface).unwrap();
+        req_env.payload.offset = offset.to_le_bytes();
+
+        let mut res = [0u8; msgs::MAX_RESPONSE_MSG_SIZE];
+        let len = server.handle_message(&req, &mut res).unwrap();
+        let (res, peers) =
+            zerocopy::Ref::<&[u8], Envelope<ListPsksResponse>>::new_from_prefix(&res[..len])
+                .unwrap();
+        assert_eq!(res.msg_type, msgs::MsgType::ListPsks as u8);
+        let peers = peers
+            .chunks_exact(crate::PEER_ID_LEN)
+            .map(PeerId::from_slice)
+            .collect();
+        (u32::from_le_bytes(res.payload.total), peers)
+    }
+
+    fn peer(n: u8) -> PeerId {
+        PeerId::new([n; 32])
+    }
+
+    #[test]
+    fn set_psk_slot_forwarded() {
+        let mut server = Server::new(MockBroker::default());
+        set_psk_slot(&mut server, 0);
+        set_psk_slot(&mut server, 3);
+        let slots: Vec<u8> = server.inner.calls().iter().map(|c| c.slot).collect();
+        assert_eq!(slots, [0, 3]);
+    }
+
+    /// The PSK field has a fixed size, so a PSK of any other length changes the size of
+    /// the whole request; such requests must be rejected instead of truncated or padded
+    #[test]
+    fn set_psk_strict_psk_len() {
+        const PSK_OFFSET: usize = msgs::ENVELOPE_OVERHEAD + crate::PEER_ID_LEN;
+
+        let mut req = vec![0u8; msgs::REQUEST_MSG_BUFFER_SIZE];
+        let mut req_env =
+            zerocopy::Ref::<&mut [u8], Envelope<SetPskRequest>>::new(&mut req[..]).unwrap();
+        req_env.msg_type = msgs::MsgType::SetPsk as u8;
+        req_env.payload.psk = [0x22; crate::WG_KEY_LEN];
+        req_env.payload.set_iface("wg0").unwrap();
+
+        let mut short = req.clone();
+        short.remove(PSK_OFFSET);
+        let mut long = req.clone();
+        long.insert(PSK_OFFSET, 0x22);
+
+        let mut server = Server::new(MockBroker::default());
+        let mut res = [0u8; msgs::MAX_RESPONSE_MSG_SIZE];
+        for req in [short, long] {
+            assert_eq!(
+                server.handle_message(&req, &mut res),
+                Err(BrokerServerError::InvalidMessage)
+            );
+        }
+        assert_eq!(server.inner.calls().len(), 0);
+
+        let len = server.handle_message(&req, &mut res).unwrap();
+        let res = zerocopy::Ref::<&[u8], Envelope<SetPskResponse>>::new(&res[..len]).unwrap();
+        assert_eq!(
+            res.payload.return_code,
+            msgs::SetPskResponseReturnCode::Success as u8
+        );
+        assert_eq!(server.inner.calls().len(), 1);
+    }
+
+    #[test]
+    fn list_psks_by_interface() {
+        let mut server = Server::new(MockBroker::default());
+        assert_eq!(list_psks(&mut server, "wg0", 0), (0, vec![]));
+
+        set_psk_for(&mut server, "wg0", peer(2), 0);
+        set_psk_for(&mut server, "wg1", peer(3), 0);
+        set_psk_for(&mut server, "wg0", peer(1), 0);
+        // Replacing a PSK does not list the peer twice
+        set_psk_for(&mut server, "wg0", peer(2), 1);
+
+        assert_eq!(
+            list_psks(&mut server, "wg0", 0),
+            (2, vec![peer(1), peer(2)])
+        );
+        assert_eq!(list_psks(&mut server, "wg1", 0), (1, vec![peer(3)]));
+        assert_eq!(list_psks(&mut server, "wg2", 0), (0, vec![]));
+    }
+
+    #[test]
+    fn list_psks_paginated() {
+        let mut server = Server::new(MockBroker::default());
+        let count = msgs::MAX_LISTED_PEERS + 3;
+        let all: Vec<PeerId> = (0..count as u8).map(peer).collect();
+        for peer_id in all.iter() {
+            set_psk_for(&mut server, "wg0", *peer_id, 0);
+        }
+
+        let (total, first) = list_psks(&mut server, "wg0", 0);
+        assert_eq!(total as usize, count);
+        assert_eq!(first, all[..msgs::MAX_LISTED_PEERS]);
+
+        let (_, rest) = list_psks(&mut server, "wg0", first.len() as u32);
+        assert_eq!(rest, all[msgs::MAX_LISTED_PEERS..]);
+
+        let (total, past_end) = list_psks(&mut server, "wg0", count as u32 + 1);
+        assert_eq!((total as usize, past_end), (count, vec![]));
+    }
+
+    #[test]
+    fn list_psks_skips_disabled_psks() {
+        let mut server = Server::new(MockBroker::default());
+        set_psk_for(&mut server, "wg0", peer(1), 0);
+        set_psk_for(&mut server, "wg0", peer(2), 0);
+        assert_eq!(
+            disable_psk(&mut server, "wg0", peer(1)),
+            msgs::SetPskResponseReturnCode::Success as u8
+        );
+        assert_eq!(list_psks(&mut server, "wg0", 0), (1, vec![peer(2)]));
+
+        disable_psk(&mut server, "wg0", peer(2));
+        assert_eq!(list_psks(&mut server, "wg0", 0), (0, vec![]));
+        assert!(server.installed.is_empty());
+
+        // Disabling the PSK of a peer that has none is harmless
+        disable_psk(&mut server, "wg1", peer(3));
+        assert_eq!(list_psks(&mut server, "wg1", 0), (0, vec![]));
+    }
+
+    #[test]
+    fn list_psks_skips_failed_set_psk() {
+        let mut server = Server::new(MockBroker::failing(msgs::SetPskError::NoSuchPeer));
+        assert_eq!(
+            set_psk_for(&mut server, "wg0", peer(1), 0),
+            msgs::SetPskResponseReturnCode::NoSuchPeer as u8
+        );
+        assert_eq!(server.inner.calls().len(), 1);
+        assert_eq!(list_psks(&mut server, "wg0", 0), (0, vec![]));
+    }
 }
@@ -39,7 +39,7 @@ fn main() -> Result<(), BrokerAppError> {
         stdin.read_exact(req_buf)?;
 
         // Process the message
-        let mut res_buf = [0u8; msgs::RESPONSE_MSG_BUFFER_SIZE];
+        let mut res_buf = [0u8; msgs::MAX_RESPONSE_MSG_SIZE];
         let res = match broker.handle_message(req_buf, &mut res_buf) {
             Ok(len) => &res_buf[..len],
             Err(e) => {
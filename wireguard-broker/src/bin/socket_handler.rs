@@ -1,15 +1,19 @@
 use std::process::Stdio;
+use std::time::Duration;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::process::Command;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc;
 use tokio::task;
 
 use anyhow::{bail, ensure, Result};
 use clap::{ArgGroup, Parser};
 
 use rosenpass_util::fd::claim_fd;
+use rosenpass_wireguard_broker::api::connection::{
+    serve_connection, BrokerRequest, BrokerResponse, RateLimiter, DEFAULT_MAX_QUEUED_REQUESTS,
+};
 use rosenpass_wireguard_broker::api::msgs;
 
 #[derive(Parser, Debug)]
@@ -36,6 +40,17 @@ struct Args {
     #[arg(long)]
     stream_fd: Option<i32>,
 
+    /// Maximum number of requests per connection awaiting their response; once reached,
+    /// no more requests are read from the connection until a response was sent
+    #[arg(long, default_value_t = DEFAULT_MAX_QUEUED_REQUESTS)]
+    max_queued_requests: usize,
+
+    /// Maximum number of PSKs each connection may set per second, in bursts of up to this
+    /// many; requests above the limit are answered with a rate-limited error. Unlimited
+    /// by default.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_psks_per_second: Option<u32>,
+
     /// The underlying broker, accepting commands through stdin and sending results through stdout.
     #[arg(
         last = true,
@@ -45,15 +60,6 @@ struct Args {
     command: Vec<String>,
 }
 
-struct BrokerRequest {
-    reply_to: oneshot::Sender<BrokerResponse>,
-    request: Vec<u8>,
-}
-
-struct BrokerResponse {
-    response: Vec<u8>,
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -61,6 +67,9 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     let (proc_tx, proc_rx) = mpsc::channel(100);
+    let rate_limiter = args
+        .max_psks_per_second
+        .map(|n| RateLimiter::new(n, Duration::from_secs(1) / n));
 
     // Start the inner broker handler
     task::spawn(async move {
@@ -73,15 +82,17 @@ async fn main() -> Result<()> {
     // Listen for incoming requests
     if let Some(path) = args.listen_path {
         let sock = UnixListener::bind(path)?;
-        listen_for_clients(proc_tx, sock).await
+        listen_for_clients(proc_tx, sock, args.max_queued_requests, rate_limiter).await
     } else if let Some(fd) = args.listen_fd {
         let sock = std::os::unix::net::UnixListener::from(claim_fd(fd)?);
         sock.set_nonblocking(true)?;
-        listen_for_clients(proc_tx, UnixListener::from_std(sock)?).await
+        let sock = UnixListener::from_std(sock)?;
+        listen_for_clients(proc_tx, sock, args.max_queued_requests, rate_limiter).await
     } else if let Some(fd) = args.stream_fd {
         let stream = std::os::unix::net::UnixStream::from(claim_fd(fd)?);
         stream.set_nonblocking(true)?;
-        on_accept(proc_tx, UnixStream::from_std(stream)?).await
+        let stream = UnixStream::from_std(stream)?;
+        serve_connection(proc_tx, stream, args.max_queued_requests, rate_limiter).await
     } else {
         unreachable!();
     }
@@ -115,7 +126,7 @@ async fn direct_broker_process(
         // Parse the response length
         let len = u64::from_le_bytes(len) as usize;
         ensure!(
-            len <= msgs::RESPONSE_MSG_BUFFER_SIZE,
+            len <= msgs::MAX_RESPONSE_MSG_SIZE,
             "Oversized buffer ({len}) in broker stdout."
         );
 
@@ -131,12 +142,19 @@ async fn direct_broker_process(
     }
 }
 
-async fn listen_for_clients(queue: mpsc::Sender<BrokerRequest>, sock: UnixListener) -> Result<()> {
+async fn listen_for_clients(
+    queue: mpsc::Sender<BrokerRequest>,
+    sock: UnixListener,
+    max_queued: usize,
+    rate_limiter: Option<RateLimiter>,
+) -> Result<()> {
     loop {
         let (stream, _addr) = sock.accept().await?;
         let queue = queue.clone();
+        // Every connection starts out with a full bucket of its own
+        let rate_limiter = rate_limiter.clone();
         task::spawn(async move {
-            if let Err(e) = on_accept(queue, stream).await {
+            if let Err(e) = serve_connection(queue, stream, max_queued, rate_limiter).await {
                 log::error!("Error during connection processing: {e}");
             }
         });
@@ -144,48 +162,3 @@ async fn listen_for_clients(queue: mpsc::Sender<BrokerRequest>, sock: UnixListen
 
     // NOTE: If loop can ever terminate we need to join the spawned tasks
 }
-
-async fn on_accept(queue: mpsc::Sender<BrokerRequest>, mut stream: UnixStream) -> Result<()> {
-    let mut req_buf = Vec::new();
-
-    loop {
-        stream.readable().await?;
-
-        // Read the message length
-        let mut len = [0u8; 8];
-        stream.read_exact(&mut len).await?;
-
-        // Parse the message length
-        let len = u64::from_le_bytes(len) as usize;
-        ensure!(
-            len <= msgs::REQUEST_MSG_BUFFER_SIZE,
-            "Oversized buffer ({len}) in unix socket input."
-        );
-
-        // Read the message itself
-        req_buf.resize(len as usize, 0);
-        stream.read_exact(&mut req_buf[..len]).await?;
-
-        // Handle the message
-        let (reply_tx, reply_rx) = oneshot::channel();
-        queue
-            .send(BrokerRequest {
-                reply_to: reply_tx,
-                request: req_buf,
-            })
-            .await?;
-
-        // Wait for the reply
-        let BrokerResponse { response } = reply_rx.await.unwrap();
-
-        // Write reply back to unix socket
-        stream
-            .write_all(&(response.len() as u64).to_le_bytes())
-            .await?;
-        stream.write_all(&response[..]).await?;
-        stream.flush().await?;
-
-        // Reuse the same memory for the next message
-        req_buf = response;
-    }
-}
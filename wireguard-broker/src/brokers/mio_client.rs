@@ -1,22 +1,129 @@
-use anyhow::{bail, ensure};
+use allocator_api2::alloc::{Allocator, Layout};
+use anyhow::{bail, ensure, Context};
 use mio::Interest;
+use rosenpass_secret_memory::alloc::{MemlockPolicy, SecretAllocator};
+use rustix::io::Errno;
+use rustix::net::{
+    recvmsg, sendmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
 use std::collections::VecDeque;
-use std::io::{ErrorKind, Read, Write};
+use std::fmt;
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
 
-use crate::{SerializedBrokerConfig, WireGuardBroker, WireguardBrokerMio};
+use crate::{PeerId, SerializedBrokerConfig, WireGuardBroker, WireguardBrokerMio};
 
-use crate::api::client::{
-    BrokerClient, BrokerClientIo, BrokerClientPollResponseError, BrokerClientSetPskError,
+use crate::api::client::{BrokerClient, BrokerClientIo, BrokerClientSetPskError, BrokerResponse};
+use crate::api::framing::{len_prefix, MessageWriter, FRAMED_REQUEST_SIZE, LEN_SIZE};
+use crate::api::msgs::{
+    self, Envelope, SetPskRequest, MAX_RESPONSE_MSG_SIZE, REQUEST_MSG_BUFFER_SIZE,
 };
-use crate::api::msgs::{self, RESPONSE_MSG_BUFFER_SIZE};
 
+/// Client for a PSK broker on the other end of a unix socket, driven by mio
+///
+/// The client is [Send], so it can be moved to the thread running the event loop. It is
+/// also [Sync]: the socket and the buffers holding secrets are only touched through
+/// `&mut self`, so shared references give no access to them. To use one client from
+/// several threads, wrap it in a [std::sync::Mutex].
 #[derive(Debug)]
 pub struct MioBrokerClient {
     inner: BrokerClient<MioBrokerClientIo>,
+    pending: VecDeque<RequestContext>,
+}
+
+/// Interface and peer of a request awaiting its response, included in log messages
+#[derive(Debug, Clone)]
+struct RequestContext {
+    interface: String,
+    peer_id: PeerId,
+}
+
+impl RequestContext {
+    fn new(config: &SerializedBrokerConfig<'_>) -> Self {
+        Self {
+            interface: String::from_utf8_lossy(config.interface).into_owned(),
+            peer_id: *config.peer_id,
+        }
+    }
+
+    /// Context of an encoded `set_psk` request, or `None` if `msg` is not one
+    fn from_request(msg: &[u8]) -> Option<Self> {
+        let req = zerocopy::Ref::<&[u8], Envelope<SetPskRequest>>::new(msg)?;
+        (req.msg_type == msgs::MsgType::SetPsk as u8).then(|| Self {
+            interface: String::from_utf8_lossy(req.payload.iface_bin()).into_owned(),
+            peer_id: PeerId::from_slice(&req.payload.peer_id),
+        })
+    }
+}
+
+impl fmt::Display for RequestContext {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "interface {}, peer {}", self.interface, self.peer_id)
+    }
 }
 
-const LEN_SIZE: usize = 8;
-const RECV_BUF_SIZE: usize = RESPONSE_MSG_BUFFER_SIZE;
+// The receive buffer holds either the length prefix or the message
+const RECV_BUF_SIZE: usize = if LEN_SIZE > MAX_RESPONSE_MSG_SIZE {
+    LEN_SIZE
+} else {
+    MAX_RESPONSE_MSG_SIZE
+};
+
+/// Time [MioBrokerClient::close] waits for the broker to acknowledge the shutdown
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time [MioBrokerClient::set_psk_blocking] waits for the broker's response
+pub const SET_PSK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why [MioBrokerClient::set_psk_blocking] gave up on the connection to the broker
+///
+/// Returned inside the [anyhow::Error]; use [anyhow::Error::downcast_ref] to tell a hung
+/// broker or half-open connection from a broker that went away.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerConnectionError {
+    /// The request was written to the socket, but no response arrived within
+    /// [SET_PSK_TIMEOUT]
+    #[error("Broker accepted the request but did not respond in time")]
+    ConnectionStalled,
+    /// The broker closed the connection or it was reset
+    #[error("Broker closed the connection")]
+    ConnectionReset,
+}
+
+impl BrokerConnectionError {
+    /// Tag errors caused by the broker closing the connection with [Self::ConnectionReset]
+    fn tag_reset(err: anyhow::Error) -> anyhow::Error {
+        let closed = err.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+            )
+        });
+        match closed {
+            true => err.context(Self::ConnectionReset),
+            false => err,
+        }
+    }
+}
+
+/// Time a dropped [MioBrokerClient] spends sending requests still in its send buffer
+pub const DROP_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of file descriptors accepted along with a single message
+pub const MAX_RECV_FDS: usize = 8;
+
+/// Environment variable holding the path of the default broker socket
+pub const BROKER_SOCKET_ENV: &str = "ROSENPASS_BROKER_SOCK";
+
+/// Prefix of broker socket paths naming a socket in the abstract namespace; see
+/// [MioBrokerClient::connect]
+pub const ABSTRACT_SOCKET_PREFIX: &[u8] = b"@";
 
 #[derive(Debug)]
 struct MioBrokerClientIo {
@@ -25,6 +132,12 @@ struct MioBrokerClientIo {
     recv_state: RxState,
     expected_state: RxState,
     recv_buf: [u8; RECV_BUF_SIZE],
+    recv_fds: Vec<OwnedFd>,
+    // Reused for every message that needs framing, so no secret memory is allocated later
+    framing: MessageWriter<FRAMED_REQUEST_SIZE>,
+    strict_nonblocking: bool,
+    // The socket's non-blocking connect may still be in progress
+    connecting: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -42,26 +155,380 @@ impl MioBrokerClient {
             recv_state: RxState::RxSize(0),
             recv_buf: [0u8; RECV_BUF_SIZE],
             expected_state: RxState::RxSize(LEN_SIZE),
+            recv_fds: Vec::new(),
+            framing: MessageWriter::new(),
+            strict_nonblocking: false,
+            connecting: false,
         };
         let inner = BrokerClient::new(io);
-        Self { inner }
+        Self {
+            inner,
+            pending: VecDeque::new(),
+        }
     }
 
-    fn poll(&mut self) -> anyhow::Result<Option<msgs::SetPskResult>> {
-        self.inner.io_mut().flush()?;
+    /// Like [Self::new], for a socket whose non-blocking connect may still be in progress
+    ///
+    /// Until the connection is established, requests are queued rather than sent and no
+    /// responses are received. Every flush, including the one in
+    /// [WireguardBrokerMio::process_poll], checks whether the connection has completed;
+    /// register the client for writable events to be woken up when it does. A failed
+    /// connection attempt is returned by the first such check.
+    pub fn new_connecting(socket: mio::net::UnixStream) -> Self {
+        let mut client = Self::new(socket);
+        client.inner.io_mut().connecting = true;
+        client
+    }
 
-        // This sucks
-        match self.inner.poll_response() {
-            Ok(res) => {
+    /// Whether the connection to the broker is still being established; see
+    /// [Self::new_connecting]
+    pub fn is_connecting(&self) -> bool {
+        self.inner.io().connecting
+    }
+
+    /// Like [Self::new], but fail right away if locked secret memory is not available
+    ///
+    /// PSKs are kept in [Secret](rosenpass_secret_memory::Secret)s; by default, secret
+    /// memory that can not be locked into RAM silently falls back to unlocked memory, see
+    /// [MemlockPolicy]. This constructor checks up front that secret memory can be allocated
+    /// and locked, regardless of the process-wide policy.
+    ///
+    /// The client frames requests in a single secret memory buffer, which is allocated along
+    /// with the client and reused for every request, so using the client allocates no
+    /// further secret memory. Data the socket does not accept right away is queued in
+    /// ordinary memory until it is sent; closing or dropping the client wipes all buffers.
+    pub fn new_checked(socket: mio::net::UnixStream) -> anyhow::Result<Self> {
+        let alloc = SecretAllocator::with_memlock_policy(MemlockPolicy::Require);
+        Self::new_checked_in(socket, &alloc)
+    }
+
+    fn new_checked_in<A: Allocator>(
+        socket: mio::net::UnixStream,
+        alloc: &A,
+    ) -> anyhow::Result<Self> {
+        let layout = Layout::new::<[u8; FRAMED_REQUEST_SIZE]>();
+        let mem = alloc.allocate(layout).map_err(|_| {
+            anyhow::anyhow!("Could not allocate locked secret memory for the broker client")
+        })?;
+        unsafe { alloc.deallocate(mem.cast(), layout) };
+
+        Ok(Self::new(socket))
+    }
+
+    /// Connect to the broker listening on the unix socket at `path`
+    ///
+    /// A path starting with [ABSTRACT_SOCKET_PREFIX] names a socket in the abstract
+    /// namespace, which has no presence in the file system. The abstract namespace is
+    /// only available on Linux; elsewhere, connecting to such a path fails.
+    pub fn connect<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let socket = match path
+            .as_os_str()
+            .as_bytes()
+            .strip_prefix(ABSTRACT_SOCKET_PREFIX)
+        {
+            Some(name) => connect_abstract(name),
+            None => mio::net::UnixStream::connect(path),
+        };
+        let socket =
+            socket.with_context(|| format!("Could not connect to broker socket {path:?}"))?;
+        Ok(Self::new(socket))
+    }
+
+    /// Connect to the broker socket named by the [BROKER_SOCKET_ENV] environment variable
+    pub fn connect_from_env() -> anyhow::Result<Self> {
+        let path = std::env::var_os(BROKER_SOCKET_ENV).with_context(|| {
+            format!(
+                "Environment variable {BROKER_SOCKET_ENV} with the broker socket path is not set"
+            )
+        })?;
+        Self::connect(path)
+    }
+
+    /// Refuse new requests while previous ones are still waiting to be sent
+    ///
+    /// By default, data the socket can not take is queued in an unbounded buffer.
+    /// In strict mode, `set_psk` instead fails with an [std::io::Error] of kind
+    /// [ErrorKind::WouldBlock] until the queued data has been flushed, so the client
+    /// never accumulates a backlog. The socket itself always stays in non-blocking mode.
+    pub fn strict_nonblocking(mut self, enabled: bool) -> Self {
+        self.inner.io_mut().strict_nonblocking = enabled;
+        self
+    }
+
+    /// Log the message framing at trace level; see [BrokerClient::set_trace_framing]
+    pub fn set_trace_framing(&mut self, enabled: bool) {
+        self.inner.set_trace_framing(enabled);
+    }
+
+    /// Number of requests awaiting a response; see [BrokerClient::in_flight]
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight()
+    }
+
+    /// Queue an encoded `set_psk` request without sending it
+    ///
+    /// The message is framed and appended to the send buffer; [Self::flush] then sends all
+    /// queued messages together, saving write calls when reconfiguring many peers at once.
+    /// Queued requests count towards [Self::in_flight] and their results are handled like
+    /// those of [WireGuardBroker::set_psk]. Other messages are rejected, since their
+    /// responses could not be told apart from the results of `set_psk` requests.
+    pub fn queue_message(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        let ctx = RequestContext::from_request(msg)
+            .context("Only encoded set_psk requests can be queued")?;
+        self.inner.io_mut().queue_message(msg)?;
+        self.inner.expect_response();
+        self.pending.push_back(ctx);
+        Ok(())
+    }
+
+    /// Send as much of the queued data as the socket accepts right now
+    ///
+    /// Data that can not be sent stays queued and is sent by later calls, including
+    /// [WireguardBrokerMio::process_poll].
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.io_mut().flush()
+    }
+
+    /// Iterate over the results of all responses that can be received right now
+    ///
+    /// Broker errors are logged, just like in [WireguardBrokerMio::process_poll]. The iterator
+    /// ends after the first communication error.
+    pub fn drain_responses(
+        &mut self,
+    ) -> impl Iterator<Item = anyhow::Result<msgs::SetPskResult>> + '_ {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let res = self.poll().transpose();
+            failed = matches!(res, Some(Err(_)));
+            res
+        })
+    }
+
+    /// Set a PSK and wait for the broker's result
+    ///
+    /// For callers without an event loop of their own: the socket is registered with a
+    /// private [mio::Poll] until the response arrives or [SET_PSK_TIMEOUT] passes, so the
+    /// client must not be registered elsewhere during the call. No other requests may be
+    /// awaiting their response. A broker error is returned as [msgs::SetPskError], a lost
+    /// or unresponsive connection as [BrokerConnectionError].
+    pub fn set_psk_blocking(&mut self, config: SerializedBrokerConfig<'_>) -> anyhow::Result<()> {
+        self.set_psk_blocking_within(config, SET_PSK_TIMEOUT)
+    }
+
+    fn set_psk_blocking_within(
+        &mut self,
+        config: SerializedBrokerConfig<'_>,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            self.in_flight() == 0,
+            "Requests are still awaiting their response"
+        );
+        let ctx = RequestContext::new(&config);
+        self.set_psk(config)?;
+
+        let res = self
+            .wait_registered(timeout, Self::poll)
+            .with_context(|| format!("No result for PSK request ({ctx})"));
+        if res.is_err() {
+            // Should the response still arrive, it must not be taken for that of a later request
+            self.cancel_pending();
+        }
+        Ok(res??)
+    }
+
+    /// The peers of `interface` the broker installed a PSK for, in ascending order
+    ///
+    /// Requests pages of the list until it is complete, waiting up to [SET_PSK_TIMEOUT] for
+    /// each; see [BrokerClient::send_list_psks]. Like [Self::set_psk_blocking], this needs
+    /// a client that is idle and not registered elsewhere.
+    pub fn list_installed_psks(&mut self, interface: &str) -> anyhow::Result<Vec<PeerId>> {
+        use BrokerClientSetPskError::*;
+
+        ensure!(
+            self.in_flight() == 0,
+            "Requests are still awaiting their response"
+        );
+        let mut peers = Vec::new();
+        loop {
+            match self.inner.send_list_psks(interface, peers.len() as u32) {
+                Ok(()) => {}
+                Err(IoError(e)) => return Err(e.context("Could not send PSK list request")),
+                Err(e) => bail!("Could not list PSKs of interface {interface:?}: {e:?}"),
+            }
+
+            let list = self.wait_registered(SET_PSK_TIMEOUT, |client| {
+                client.poll()?;
+                Ok(client.inner.take_psk_list())
+            });
+            let list = match list {
+                Ok(list) => list,
+                Err(e) => {
+                    self.cancel_pending();
+                    return Err(e.context(format!("No PSK list for interface {interface:?}")));
+                }
+            };
+
+            // Peers may be removed while listing, so an empty page ends the list as well
+            let done =
+                list.peers.is_empty() || peers.len() + list.peers.len() >= list.total as usize;
+            peers.extend(list.peers);
+            if done {
+                return Ok(peers);
+            }
+        }
+    }
+
+    /// [Self::wait_for], registered with a private [mio::Poll]
+    fn wait_registered<T>(
+        &mut self,
+        timeout: Duration,
+        ready: impl FnMut(&mut Self) -> anyhow::Result<Option<T>>,
+    ) -> anyhow::Result<T> {
+        let mut poll = mio::Poll::new()?;
+        self.register(poll.registry(), mio::Token(0))?;
+        let res = self.wait_for(&mut poll, timeout, ready);
+        let unregistered = self.unregister(poll.registry());
+        let res = res?;
+        unregistered?;
+        Ok(res)
+    }
+
+    /// Wait until `ready` yields a result
+    fn wait_for<T>(
+        &mut self,
+        poll: &mut mio::Poll,
+        timeout: Duration,
+        mut ready: impl FnMut(&mut Self) -> anyhow::Result<Option<T>>,
+    ) -> anyhow::Result<T> {
+        let deadline = Instant::now() + timeout;
+        let mut events = mio::Events::with_capacity(8);
+        loop {
+            if let Some(res) = ready(self).map_err(BrokerConnectionError::tag_reset)? {
                 return Ok(res);
             }
-            Err(BrokerClientPollResponseError::IoError(e)) => {
-                return Err(e);
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                ensure!(
+                    self.inner.io().send_buf.is_empty(),
+                    "Broker did not accept the request in time"
+                );
+                bail!(BrokerConnectionError::ConnectionStalled);
             }
-            Err(BrokerClientPollResponseError::InvalidMessage) => {
-                bail!("Invalid message");
+            match poll.poll(&mut events, Some(timeout)) {
+                Err(e) if e.kind() != ErrorKind::Interrupted => return Err(e.into()),
+                _ => {}
             }
+        }
+    }
+
+    /// Give up on all requests awaiting a response; see [BrokerClient::cancel_pending]
+    pub fn cancel_pending(&mut self) {
+        self.inner.cancel_pending();
+        self.pending.clear();
+    }
+
+    /// Shut down the connection to the broker
+    ///
+    /// Sends a goodbye message and waits up to [CLOSE_TIMEOUT] for the broker to acknowledge it.
+    /// Since the broker processes requests in order, the acknowledgement confirms that all
+    /// previous requests were handled. Results of previous requests arriving in the meantime
+    /// are discarded, just like in [WireguardBrokerMio::process_poll].
+    ///
+    /// Whether or not the handshake succeeds, the send and receive buffers are zeroized
+    /// and the socket is shut down before this function returns. The first error
+    /// encountered is returned.
+    pub fn close(&mut self) -> anyhow::Result<()> {
+        let handshake = self.goodbye();
+
+        self.pending.clear();
+        let io = self.inner.io_mut();
+        io.wipe_buffers();
+        let shutdown = match io.socket.shutdown(Shutdown::Both) {
+            // The broker may already have closed its side
+            Err(e) if e.kind() == ErrorKind::NotConnected => Ok(()),
+            r => r,
         };
+
+        handshake?;
+        Ok(shutdown?)
+    }
+
+    fn goodbye(&mut self) -> anyhow::Result<()> {
+        self.inner.send_goodbye()?;
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.inner.is_closed() {
+            ensure!(
+                Instant::now() < deadline,
+                "Broker did not acknowledge the shutdown in time"
+            );
+            if self.poll()?.is_none() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        self.inner.io_mut().flush()
+    }
+
+    fn poll(&mut self) -> anyhow::Result<Option<msgs::SetPskResult>> {
+        self.inner.io_mut().flush()?;
+
+        match self.inner.poll_broker_response()? {
+            BrokerResponse::NoneReady => Ok(None),
+            BrokerResponse::Ok => {
+                self.pending.pop_front();
+                Ok(Some(Ok(())))
+            }
+            BrokerResponse::BrokerError(e) => {
+                match self.pending.pop_front() {
+                    Some(ctx) => log::warn!("Error from PSK broker ({ctx}): {e:?}"),
+                    None => log::warn!("Error from PSK broker: {e:?}"),
+                }
+                Ok(Some(Err(e)))
+            }
+            BrokerResponse::Invalid => bail!("Invalid message"),
+        }
+    }
+}
+
+/// Requests still in the send buffer are sent before the client goes away, waiting up to
+/// [DROP_FLUSH_TIMEOUT]; a warning is logged either way, since this hints at a missing
+/// call to [MioBrokerClient::flush] or [MioBrokerClient::close]. Responses are not awaited.
+/// In [strict non-blocking](MioBrokerClient::strict_nonblocking) mode, dropping never
+/// blocks: whatever the socket does not take right away is discarded.
+///
+/// All buffers are wiped in any case.
+impl Drop for MioBrokerClient {
+    fn drop(&mut self) {
+        let io = self.inner.io_mut();
+        let unsent = io.send_buf.len();
+        if unsent > 0 {
+            let flushed = match io.strict_nonblocking {
+                true => io.flush(),
+                false => io.flush_blocking(DROP_FLUSH_TIMEOUT),
+            };
+            let left = io.send_buf.len();
+            match flushed {
+                Ok(()) if left == 0 => log::warn!(
+                    "PSK broker client dropped with {unsent} unsent bytes; sent them, but \
+                     their results are lost. Close the client to wait for them."
+                ),
+                Ok(()) => log::error!(
+                    "PSK broker client dropped with {unsent} unsent bytes; {left} of them \
+                     could not be sent without blocking and PSKs may be missing"
+                ),
+                Err(e) => log::error!(
+                    "PSK broker client dropped with {unsent} unsent bytes; {left} of them \
+                     could not be sent and PSKs may be missing: {e:?}"
+                ),
+            }
+        }
+        io.wipe_buffers();
     }
 }
 
@@ -70,10 +537,14 @@ impl WireGuardBroker for MioBrokerClient {
 
     fn set_psk<'a>(&mut self, config: SerializedBrokerConfig<'a>) -> anyhow::Result<()> {
         use BrokerClientSetPskError::*;
+        let ctx = RequestContext::new(&config);
         let e = self.inner.set_psk(config);
         match e {
-            Ok(()) => Ok(()),
-            Err(IoError(e)) => Err(e),
+            Ok(()) => {
+                self.pending.push_back(ctx);
+                Ok(())
+            }
+            Err(IoError(e)) => Err(e.context(format!("Could not send PSK request ({ctx})"))),
             Err(IfaceOutOfBounds) => bail!("Interface name size is out of bounds."),
             Err(MsgError) => bail!("Error with encoding/decoding message."),
             Err(BrokerError(e)) => bail!("Broker error: {:?}", e),
@@ -97,8 +568,16 @@ impl WireguardBrokerMio for MioBrokerClient {
         Ok(())
     }
 
+    /// Handle all responses that can be received right now
+    ///
+    /// A broker error only fails its own request: it is logged along with the interface
+    /// and peer, and the remaining responses are still processed. Only communication
+    /// errors are returned.
     fn process_poll(&mut self) -> Result<(), Self::MioError> {
-        self.poll()?;
+        for res in self.drain_responses() {
+            // Broker errors have already been logged
+            let _ = res?;
+        }
         Ok(())
     }
 
@@ -114,14 +593,66 @@ impl BrokerClientIo for MioBrokerClientIo {
 
     fn send_msg(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
         self.flush()?;
-        self.send_or_buffer(&(buf.len() as u64).to_le_bytes())?;
-        self.send_or_buffer(&buf)?;
+        if self.connecting {
+            return self.queue_message(buf);
+        }
+        if self.strict_nonblocking && !self.send_buf.is_empty() {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock).into());
+        }
+        if self.send_buf.is_empty() {
+            self.send_framed(buf)?;
+        } else {
+            // Queued behind previous messages
+            self.framing.clear();
+            self.framing.write_all(buf)?;
+            self.send_buf.extend(self.framing.finish().iter());
+            self.framing.clear();
+        }
+        self.flush()?;
+
+        Ok(())
+    }
+
+    fn supports_fds(&self) -> bool {
+        true
+    }
+
+    fn send_msg_with_fds(
+        &mut self,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Result<(), Self::SendError> {
+        // The descriptors are attached to the first byte sent, so the message can not be
+        // queued behind previous ones
+        self.flush()?;
+        if self.connecting || !self.send_buf.is_empty() {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock).into());
+        }
+        self.framing.clear();
+        self.framing.write_all(buf)?;
+        let msg = self.framing.finish();
+
+        let off = raw_send_with_fds(&self.socket, msg, fds);
+        if let Ok(off) = off {
+            self.send_buf.extend(msg[off..].iter());
+        }
+        self.framing.clear();
+        if off? == 0 {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock).into());
+        }
         self.flush()?;
 
         Ok(())
     }
 
+    fn take_fds(&mut self) -> Vec<OwnedFd> {
+        std::mem::take(&mut self.recv_fds)
+    }
+
     fn recv_msg(&mut self) -> Result<Option<&[u8]>, Self::RecvError> {
+        if self.connecting {
+            return Ok(None);
+        }
         loop {
             match (self.recv_state, self.expected_state) {
                 //Stale Buffer state or recieved everything
@@ -135,7 +666,7 @@ impl BrokerClientIo for MioBrokerClientIo {
                             let len: usize = u64::from_le_bytes(*len) as usize;
 
                             ensure!(
-                                len <= msgs::RESPONSE_MSG_BUFFER_SIZE,
+                                len <= msgs::MAX_RESPONSE_MSG_SIZE,
                                 "Oversized buffer ({len}) in psk buffer response."
                             );
 
@@ -156,15 +687,23 @@ impl BrokerClientIo for MioBrokerClientIo {
                 | (RxState::RxBuffer(x), RxState::RxBuffer(y))
                     if x < y =>
                 {
-                    let bytes = raw_recv(&self.socket, &mut self.recv_buf[x..y])?;
+                    // Descriptors of the previous message were not taken
+                    if let RxState::RxSize(0) = self.recv_state {
+                        self.recv_fds.clear();
+                    }
+
+                    let bytes =
+                        raw_recv(&self.socket, &mut self.recv_buf[x..y], &mut self.recv_fds)?;
 
-                    if x + bytes == y {
-                        return Ok(Some(&self.recv_buf[0..y]));
+                    //Nothing to read right now; continue with the next poll
+                    if bytes == 0 {
+                        return Ok(None);
                     }
-                    //We didn't recieve everything so let's assume something went wrong
-                    self.recv_state = RxState::RxSize(0);
-                    self.expected_state = RxState::RxSize(LEN_SIZE);
-                    bail!("Invalid state");
+                    self.recv_state = match self.recv_state {
+                        RxState::RxSize(_) => RxState::RxSize(x + bytes),
+                        RxState::RxBuffer(_) => RxState::RxBuffer(x + bytes),
+                    };
+                    continue;
                 }
                 _ => {
                     //Reset states
@@ -178,63 +717,210 @@ impl BrokerClientIo for MioBrokerClientIo {
 }
 
 impl MioBrokerClientIo {
+    /// Zeroize and discard all buffered data, including unused capacity
+    fn wipe_buffers(&mut self) {
+        self.send_buf.resize(self.send_buf.capacity(), 0);
+        let (fst, snd) = self.send_buf.as_mut_slices();
+        fst.zeroize();
+        snd.zeroize();
+        self.send_buf.clear();
+
+        self.recv_buf.zeroize();
+        self.framing.clear();
+        self.recv_fds.clear();
+        self.recv_state = RxState::RxSize(0);
+        self.expected_state = RxState::RxSize(LEN_SIZE);
+    }
+
+    /// Append a framed message to the send buffer without writing to the socket
+    ///
+    /// Several messages can be queued and then sent together by [Self::flush], in as
+    /// few write calls as the socket allows.
+    fn queue_message(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        if msg.len() > REQUEST_MSG_BUFFER_SIZE {
+            return Err(std::io::Error::from(ErrorKind::WriteZero).into());
+        }
+        self.send_buf.extend(len_prefix(msg).iter());
+        self.send_buf.extend(msg.iter());
+        Ok(())
+    }
+
+    /// Check whether a connection in progress has been established
+    ///
+    /// Returns an error if connecting failed.
+    fn check_connected(&mut self) -> anyhow::Result<bool> {
+        if !self.connecting {
+            return Ok(true);
+        }
+        if let Some(e) = self.socket.take_error()? {
+            return Err(anyhow::Error::new(e).context("Could not connect to the broker"));
+        }
+        match self.socket.peer_addr() {
+            Ok(_) => {
+                self.connecting = false;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write as much of the send buffer as the socket accepts
+    ///
+    /// Nothing is written while the connection is still being established.
     fn flush(&mut self) -> anyhow::Result<()> {
+        if !self.check_connected()? {
+            return Ok(());
+        }
         let (fst, snd) = self.send_buf.as_slices();
 
-        let (written, res) = match raw_send(&self.socket, fst) {
-            Ok(w1) if w1 >= fst.len() => match raw_send(&self.socket, snd) {
-                Ok(w2) => (w1 + w2, Ok(())),
-                Err(e) => (w1, Err(e)),
-            },
-            Ok(w1) => (w1, Ok(())),
-            Err(e) => (0, Err(e)),
-        };
-
+        let written = raw_send_vectored(&self.socket, fst, snd)?;
         self.send_buf.drain(..written);
 
         (&self.socket).try_io(|| (&self.socket).flush())?;
 
-        res
+        Ok(())
     }
 
-    fn send_or_buffer(&mut self, buf: &[u8]) -> anyhow::Result<()> {
-        let mut off = 0;
+    /// Keep flushing until the send buffer is empty or `timeout` passes
+    fn flush_blocking(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.flush()?;
+            if self.send_buf.is_empty() {
+                return Ok(());
+            }
+            ensure!(
+                Instant::now() < deadline,
+                "Socket did not accept the data in time"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
 
-        if self.send_buf.is_empty() {
-            off += raw_send(&self.socket, buf)?;
+    /// Send the length prefix and `msg` in a single vectored write
+    ///
+    /// Fast path for an empty send buffer; unlike [MessageWriter], this does not copy the
+    /// message. Whatever the socket does not accept is queued.
+    fn send_framed(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        if msg.len() > REQUEST_MSG_BUFFER_SIZE {
+            return Err(std::io::Error::from(ErrorKind::WriteZero).into());
         }
+        let prefix = len_prefix(msg);
 
-        self.send_buf.extend((&buf[off..]).iter());
+        let off = raw_send_vectored(&self.socket, &prefix, msg)?;
+
+        if off < LEN_SIZE {
+            self.send_buf.extend(prefix[off..].iter());
+            self.send_buf.extend(msg.iter());
+        } else {
+            self.send_buf.extend(msg[off - LEN_SIZE..].iter());
+        }
 
         Ok(())
     }
 }
 
-fn raw_send(mut socket: &mio::net::UnixStream, data: &[u8]) -> anyhow::Result<usize> {
+/// Connect to the socket with the given name in the abstract namespace
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &[u8]) -> std::io::Result<mio::net::UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixStream};
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    let socket = UnixStream::connect_addr(&addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(mio::net::UnixStream::from_std(socket))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_name: &[u8]) -> std::io::Result<mio::net::UnixStream> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "Abstract unix socket addresses are only supported on Linux",
+    ))
+}
+
+fn raw_send_vectored(
+    mut socket: &mio::net::UnixStream,
+    fst: &[u8],
+    snd: &[u8],
+) -> anyhow::Result<usize> {
     let mut off = 0;
+    let total = fst.len() + snd.len();
 
-    socket.try_io(|| {
-        loop {
-            if off == data.len() {
+    socket.try_io(|| loop {
+        if off == total {
+            return Ok(());
+        }
+        let res = match fst.get(off..) {
+            Some(rest) if !rest.is_empty() => {
+                socket.write_vectored(&[IoSlice::new(rest), IoSlice::new(snd)])
+            }
+            _ => socket.write(&snd[off - fst.len()..]),
+        };
+        match res {
+            Ok(n) => {
+                off += n;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                // pass – retry
+            }
+            Err(e) if off > 0 || e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    })?;
+
+    Ok(off)
+}
+
+/// Send as much of `data` as possible in a single call, passing `fds` along with it
+///
+/// Returns zero if the socket can not take any data right now; the descriptors were
+/// not sent in that case.
+fn raw_send_with_fds(
+    socket: &mio::net::UnixStream,
+    data: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> anyhow::Result<usize> {
+    let mut space = vec![0u8; rustix::cmsg_space!(ScmRights(fds.len()))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() {
+        ensure!(
+            control.push(SendAncillaryMessage::ScmRights(fds)),
+            "Could not attach file descriptors to the message"
+        );
+    }
+
+    // Safety: The socket outlives the borrowed file descriptor
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+    let mut off = 0;
+
+    socket.try_io(|| loop {
+        match sendmsg(fd, &[IoSlice::new(data)], &mut control, SendFlags::empty()) {
+            Ok(n) => {
+                off = n;
                 return Ok(());
             }
-            match socket.write(&data[off..]) {
-                Ok(n) => {
-                    off += n;
-                }
-                Err(e) if e.kind() == ErrorKind::Interrupted => {
-                    // pass – retry
-                }
-                Err(e) if off > 0 || e.kind() == ErrorKind::WouldBlock => return Ok(()),
-                Err(e) => return Err(e),
+            Err(Errno::INTR) => {
+                // pass – retry
             }
+            Err(Errno::WOULDBLOCK) => return Ok(()),
+            Err(e) => return Err(e.into()),
         }
     })?;
 
-    return Ok(off);
+    Ok(off)
 }
 
-fn raw_recv(mut socket: &mio::net::UnixStream, out: &mut [u8]) -> anyhow::Result<usize> {
+/// Receive into `out`, collecting any file descriptors passed along with the data in `fds`
+fn raw_recv(
+    socket: &mio::net::UnixStream,
+    out: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> anyhow::Result<usize> {
+    // Safety: The socket outlives the borrowed file descriptor
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
     let mut off = 0;
 
     socket.try_io(|| {
@@ -242,7 +928,22 @@ fn raw_recv(mut socket: &mio::net::UnixStream, out: &mut [u8]) -> anyhow::Result
             if off == out.len() {
                 return Ok(());
             }
-            match socket.read(&mut out[off..]) {
+            let mut space = [0u8; rustix::cmsg_space!(ScmRights(MAX_RECV_FDS))];
+            let mut control = RecvAncillaryBuffer::new(&mut space);
+            let res = recvmsg(
+                fd,
+                &mut [IoSliceMut::new(&mut out[off..])],
+                &mut control,
+                RecvFlags::CMSG_CLOEXEC,
+            )
+            .map_err(std::io::Error::from);
+            for msg in control.drain() {
+                if let RecvAncillaryMessage::ScmRights(received) = msg {
+                    fds.extend(received);
+                }
+            }
+            match res.map(|r| r.bytes) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
                 Ok(n) => {
                     off += n;
                 }
@@ -257,3 +958,741 @@ fn raw_recv(mut socket: &mio::net::UnixStream, out: &mut [u8]) -> anyhow::Result
 
     return Ok(off);
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+    use std::ptr::NonNull;
+
+    use allocator_api2::alloc::AllocError;
+
+    use crate::api::server::BrokerServer;
+    use crate::brokers::pool::BrokerPool;
+    use crate::brokers::psk_slots::SlottedBroker;
+    use rosenpass_secret_memory::Secret;
+
+    use crate::test_fixtures::{config, random_psk, MockBroker};
+    use crate::test_logger;
+
+    use super::*;
+
+    fn set_psk(client: &mut MioBrokerClient) -> anyhow::Result<()> {
+        let (peer_id, psk) = random_psk();
+        client.set_psk(config(b"wg0", &peer_id, &psk))
+    }
+
+    #[test]
+    fn strict_nonblocking_backpressure() {
+        let (client_socket, mut server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket).strict_nonblocking(true);
+
+        // Fill the socket buffer until the client pushes back instead of queuing
+        let mut sent = 0;
+        let err = loop {
+            match set_psk(&mut client) {
+                Ok(()) => sent += 1,
+                Err(e) => break e,
+            }
+            assert!(sent < 100_000, "Client never applied backpressure");
+        };
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(!client.inner.io().send_buf.is_empty());
+
+        // Drain the socket; afterwards, requests are accepted again
+        let mut buf = [0u8; 4096];
+        while !client.inner.io().send_buf.is_empty() {
+            while server_socket.read(&mut buf).is_ok() {}
+            client.inner.io_mut().flush().unwrap();
+        }
+        set_psk(&mut client).unwrap();
+    }
+
+    #[test]
+    fn new_checked() {
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        assert!(MioBrokerClient::new_checked(client_socket).is_ok());
+    }
+
+    #[test]
+    fn new_checked_fails_without_secret_memory() {
+        struct Unavailable;
+
+        unsafe impl Allocator for Unavailable {
+            fn allocate(&self, _layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                Err(AllocError)
+            }
+
+            unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+                unreachable!()
+            }
+        }
+
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        let err = MioBrokerClient::new_checked_in(client_socket, &Unavailable).unwrap_err();
+        assert!(err.to_string().contains("locked secret memory"));
+    }
+
+    #[test]
+    fn lenient_nonblocking_queues() {
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+        while client.inner.io().send_buf.is_empty() {
+            set_psk(&mut client).unwrap();
+        }
+        set_psk(&mut client).unwrap();
+    }
+
+    #[test]
+    fn framing_fast_path_matches_message_writer() {
+        let (client_socket, mut server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+
+        // Keep sending until messages are queued, so both the vectored fast path (including
+        // partial writes) and the MessageWriter path are exercised
+        let mut expected = Vec::new();
+        let mut msg = [0u8; REQUEST_MSG_BUFFER_SIZE];
+        let mut i = 0u8;
+        while client.inner.io().send_buf.is_empty() || i < 10 {
+            msg.fill(i);
+            let msg = &msg[..(i as usize % REQUEST_MSG_BUFFER_SIZE) + 1];
+            client.inner.io_mut().send_msg(msg).unwrap();
+
+            let mut framed = MessageWriter::<FRAMED_REQUEST_SIZE>::new();
+            framed.write_all(msg).unwrap();
+            expected.extend_from_slice(framed.finish());
+            i = i.wrapping_add(1);
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while received.len() < expected.len() {
+            match server_socket.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    client.inner.io_mut().flush().unwrap()
+                }
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn framing_rejects_oversized_message() {
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+        let err = client
+            .inner
+            .io_mut()
+            .send_msg(&[0u8; REQUEST_MSG_BUFFER_SIZE + 1])
+            .unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn client_types_are_send_and_sync() {
+        assert_send::<MioBrokerClient>();
+        assert_sync::<MioBrokerClient>();
+        assert_send::<BrokerClient<MioBrokerClientIo>>();
+        assert_sync::<BrokerClient<MioBrokerClientIo>>();
+        assert_send::<BrokerPool<MioBrokerClient>>();
+        assert_sync::<BrokerPool<MioBrokerClient>>();
+        assert_send::<SlottedBroker<MioBrokerClient>>();
+        assert_sync::<SlottedBroker<MioBrokerClient>>();
+    }
+
+    /// An encoded `set_psk` request for `interface`
+    fn encoded_set_psk(interface: &[u8]) -> Vec<u8> {
+        let mut req = vec![0u8; REQUEST_MSG_BUFFER_SIZE];
+        let mut env =
+            zerocopy::Ref::<&mut [u8], Envelope<SetPskRequest>>::new(&mut req[..]).unwrap();
+        env.msg_type = msgs::MsgType::SetPsk as u8;
+        env.payload.set_iface_bin(interface).unwrap();
+        req
+    }
+
+    #[test]
+    fn queue_messages_then_flush() {
+        let (sender_socket, receiver_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut sender = MioBrokerClient::new(sender_socket);
+        let mut receiver = MioBrokerClient::new(receiver_socket);
+
+        let msgs = [b"wg0", b"wg1", b"wg2"].map(|iface| encoded_set_psk(iface));
+        for msg in msgs.iter() {
+            sender.queue_message(msg).unwrap();
+        }
+        assert_eq!(sender.in_flight(), 3);
+        assert!(!sender.inner.io().send_buf.is_empty());
+        sender.flush().unwrap();
+        assert!(sender.inner.io().send_buf.is_empty());
+
+        let io = receiver.inner.io_mut();
+        for msg in msgs.iter() {
+            assert_eq!(io.recv_msg().unwrap().unwrap(), msg);
+        }
+        assert!(io.recv_msg().unwrap().is_none());
+
+        // Only set_psk requests can be queued
+        let mut goodbye = [0u8; msgs::ENVELOPE_OVERHEAD];
+        goodbye[0] = msgs::MsgType::Goodbye as u8;
+        for msg in [&goodbye[..], &[0u8; REQUEST_MSG_BUFFER_SIZE + 1], b"ping"] {
+            assert!(sender.queue_message(msg).is_err());
+        }
+        assert_eq!(sender.in_flight(), 3);
+        assert!(sender.inner.io().send_buf.is_empty());
+    }
+
+    #[test]
+    fn queued_requests_are_tracked() {
+        test_logger::install();
+
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let broker = MockBroker::failing_on(b"wg-queued", msgs::SetPskError::NoSuchInterface);
+        let server = serve(socket, broker, 3);
+        client
+            .queue_message(&encoded_set_psk(b"wg-queued"))
+            .unwrap();
+        set_psk(&mut client).unwrap();
+        assert_eq!(client.in_flight(), 2);
+
+        // The response to the queued request must not be taken for that of a blocking one
+        assert!(set_psk_blocking(&mut client).is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut results = Vec::new();
+        while results.len() < 2 && Instant::now() < deadline {
+            match client.poll().unwrap() {
+                Some(res) => results.push(res),
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(results, [Err(msgs::SetPskError::NoSuchInterface), Ok(())]);
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.pending.is_empty());
+
+        // The broker error is logged with the context of the queued request
+        assert!(test_logger::captured()
+            .iter()
+            .any(|m| m.contains("interface wg-queued") && m.contains("NoSuchInterface")));
+
+        set_psk_blocking(&mut client).unwrap();
+        server.join().unwrap();
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn drop_flushes_pending_data() {
+        test_logger::install();
+
+        let (sender_socket, receiver_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut sender = MioBrokerClient::new(sender_socket);
+        let mut receiver = MioBrokerClient::new(receiver_socket);
+        let msg = encoded_set_psk(b"wg-dropped");
+        sender.queue_message(&msg).unwrap();
+        drop(sender);
+
+        let io = receiver.inner.io_mut();
+        assert_eq!(io.recv_msg().unwrap().unwrap(), msg);
+        let expected = format!("dropped with {FRAMED_REQUEST_SIZE} unsent bytes; sent them");
+        let log = test_logger::captured();
+        assert!(log.iter().any(|l| l.contains(&expected)));
+    }
+
+    #[test]
+    fn drop_warns_about_lost_data() {
+        test_logger::install();
+
+        let (sender_socket, receiver_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut sender = MioBrokerClient::new(sender_socket);
+        drop(receiver_socket);
+        sender.queue_message(&encoded_set_psk(b"wg-lost")).unwrap();
+        drop(sender);
+
+        let expected = format!(
+            "dropped with {FRAMED_REQUEST_SIZE} unsent bytes; {FRAMED_REQUEST_SIZE} of them \
+            could not be sent"
+        );
+        let log = test_logger::captured();
+        assert!(log.iter().any(|l| l.contains(&expected)));
+    }
+
+    #[test]
+    fn strict_nonblocking_drop_does_not_block() {
+        test_logger::install();
+
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket).strict_nonblocking(true);
+        while set_psk(&mut client).is_ok() {}
+        // Nobody reads from the socket, so blocking would run into the timeout
+        let start = Instant::now();
+        drop(client);
+        assert!(start.elapsed() < DROP_FLUSH_TIMEOUT);
+
+        let log = test_logger::captured();
+        assert!(log
+            .iter()
+            .any(|l| l.contains("could not be sent without blocking")));
+    }
+
+    #[test]
+    fn pass_fds() {
+        let (sender_socket, receiver_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut sender = MioBrokerClient::new(sender_socket);
+        let mut receiver = MioBrokerClient::new(receiver_socket);
+
+        let (mut local, remote) = UnixStream::pair().unwrap();
+        sender
+            .inner
+            .io_mut()
+            .send_msg_with_fds(b"sock", &[remote.as_fd()])
+            .unwrap();
+        drop(remote);
+
+        let io = receiver.inner.io_mut();
+        assert_eq!(io.recv_msg().unwrap().unwrap(), b"sock");
+        let mut fds = io.take_fds();
+        assert_eq!(fds.len(), 1);
+        assert!(io.take_fds().is_empty());
+
+        // The received descriptor refers to the same socket
+        let mut passed = UnixStream::from(fds.pop().unwrap());
+        passed.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn broker_error_logged_with_context() {
+        test_logger::install();
+
+        let (client_socket, mut socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let (peer_id, psk) = random_psk();
+        client
+            .set_psk(config(b"wg-log-context", &peer_id, &psk))
+            .unwrap();
+
+        let mut server = BrokerServer::new(MockBroker::failing(msgs::SetPskError::NoSuchPeer));
+        let mut len = [0u8; LEN_SIZE];
+        socket.read_exact(&mut len).unwrap();
+        let mut req = vec![0u8; u64::from_le_bytes(len) as usize];
+        socket.read_exact(&mut req).unwrap();
+        let mut res = [0u8; MAX_RESPONSE_MSG_SIZE];
+        let len = server.handle_message(&req, &mut res).unwrap();
+        socket.write_all(&(len as u64).to_le_bytes()).unwrap();
+        socket.write_all(&res[..len]).unwrap();
+
+        assert_eq!(
+            client.poll().unwrap(),
+            Some(Err(msgs::SetPskError::NoSuchPeer))
+        );
+        assert!(client.pending.is_empty());
+
+        let psk_hex: String = psk.secret().iter().map(|b| format!("{b:02x}")).collect();
+        let log = test_logger::captured();
+        let msg = log.iter().find(|m| m.contains("wg-log-context")).unwrap();
+        assert!(msg.contains("Error from PSK broker"));
+        assert!(msg.contains(&peer_id.to_string()));
+        assert!(!msg.contains(&psk_hex));
+    }
+
+    #[test]
+    fn broker_error_isolated_to_its_interface() {
+        test_logger::install();
+
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        // Keep the connection open after the server is done
+        let _socket = socket.try_clone().unwrap();
+        let broker = MockBroker::failing_on(b"wg-isolated-0", msgs::SetPskError::NoSuchInterface);
+        let server = serve(socket, broker, 2);
+        let (peer_id, psk) = random_psk();
+        for interface in ["wg-isolated-0", "wg-isolated-1"] {
+            client
+                .set_psk(config(interface.as_bytes(), &peer_id, &psk))
+                .unwrap();
+        }
+        server.join().unwrap();
+
+        // Both responses are handled in the same poll cycle, despite the first one failing
+        client.process_poll().unwrap();
+        assert_eq!(client.in_flight(), 0);
+        assert!(client.pending.is_empty());
+
+        let log = test_logger::captured();
+        assert!(log
+            .iter()
+            .any(|m| m.contains("wg-isolated-0") && m.contains("NoSuchInterface")));
+        assert!(!log.iter().any(|m| m.contains("wg-isolated-1")));
+    }
+
+    /// Answer `requests` requests with `broker` on another thread
+    fn serve<B>(mut socket: UnixStream, broker: B, requests: usize) -> std::thread::JoinHandle<()>
+    where
+        B: WireGuardBroker<Error = msgs::SetPskError> + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            let mut server = BrokerServer::new(broker);
+            for _ in 0..requests {
+                let mut len = [0u8; LEN_SIZE];
+                socket.read_exact(&mut len).unwrap();
+                let mut req = vec![0u8; u64::from_le_bytes(len) as usize];
+                socket.read_exact(&mut req).unwrap();
+
+                // Let the client wait for the response
+                std::thread::sleep(Duration::from_millis(10));
+
+                let mut res = [0u8; MAX_RESPONSE_MSG_SIZE];
+                let len = server.handle_message(&req, &mut res).unwrap();
+                socket.write_all(&(len as u64).to_le_bytes()).unwrap();
+                socket.write_all(&res[..len]).unwrap();
+            }
+        })
+    }
+
+    fn set_psk_blocking(client: &mut MioBrokerClient) -> anyhow::Result<()> {
+        let (peer_id, psk) = random_psk();
+        client.set_psk_blocking(config(b"wg0", &peer_id, &psk))
+    }
+
+    #[test]
+    fn set_psk_blocking_success() {
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let broker = MockBroker::default();
+        let server = serve(socket, broker.clone(), 2);
+        set_psk_blocking(&mut client).unwrap();
+        set_psk_blocking(&mut client).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(broker.calls().len(), 2);
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn set_psk_blocking_broker_error() {
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let server = serve(
+            socket,
+            MockBroker::failing(msgs::SetPskError::NoSuchPeer),
+            1,
+        );
+        let err = set_psk_blocking(&mut client).unwrap_err();
+        server.join().unwrap();
+
+        assert_eq!(
+            err.downcast::<msgs::SetPskError>().unwrap(),
+            msgs::SetPskError::NoSuchPeer
+        );
+    }
+
+    /// Send a request and wait for its response, like [MioBrokerClient::set_psk_blocking]
+    /// with a shorter timeout
+    fn wait_briefly(client: &mut MioBrokerClient) -> anyhow::Error {
+        set_psk(client).unwrap();
+        let mut poll = mio::Poll::new().unwrap();
+        client.register(poll.registry(), mio::Token(0)).unwrap();
+        let err = client
+            .wait_for(&mut poll, Duration::from_millis(50), MioBrokerClient::poll)
+            .unwrap_err();
+        client.unregister(poll.registry()).unwrap();
+        err
+    }
+
+    #[test]
+    fn stalled_connection() {
+        let (client_socket, mut socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        // The request is sent, but the broker never answers
+        let err = wait_briefly(&mut client);
+        let mut req = [0u8; FRAMED_REQUEST_SIZE];
+        socket.read_exact(&mut req).unwrap();
+        assert_eq!(
+            err.downcast_ref::<BrokerConnectionError>(),
+            Some(&BrokerConnectionError::ConnectionStalled)
+        );
+    }
+
+    /// A broker that reads one request, then goes away
+    fn serve_and_vanish(mut socket: UnixStream) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut req = [0u8; FRAMED_REQUEST_SIZE];
+            socket.read_exact(&mut req).unwrap();
+        })
+    }
+
+    #[test]
+    fn reset_connection() {
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let server = serve_and_vanish(socket);
+        let err = wait_briefly(&mut client);
+        server.join().unwrap();
+        assert_eq!(
+            err.downcast_ref::<BrokerConnectionError>(),
+            Some(&BrokerConnectionError::ConnectionReset)
+        );
+
+        // Through set_psk_blocking, the error is still recognizable
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        let server = serve_and_vanish(socket);
+        let err = set_psk_blocking(&mut client).unwrap_err();
+        server.join().unwrap();
+        assert_eq!(
+            err.downcast_ref::<BrokerConnectionError>(),
+            Some(&BrokerConnectionError::ConnectionReset)
+        );
+    }
+
+    #[test]
+    fn set_psk_blocking_after_timeout() {
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        // The response to the first request is late and reports an error
+        let broker = MockBroker::failing_on(b"wg-late", msgs::SetPskError::NoSuchInterface);
+        let server = serve(socket, broker, 2);
+        let (peer_id, psk) = random_psk();
+        let err = client
+            .set_psk_blocking_within(config(b"wg-late", &peer_id, &psk), Duration::ZERO)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BrokerConnectionError>(),
+            Some(&BrokerConnectionError::ConnectionStalled)
+        );
+        assert_eq!(client.in_flight(), 0);
+
+        // The late response is skipped instead of being returned for the next request
+        set_psk_blocking(&mut client).unwrap();
+        server.join().unwrap();
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn list_installed_psks() {
+        let (client_socket, socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new(mio::net::UnixStream::from_std(client_socket));
+
+        // Keep the connection open after the server is done
+        let _socket = socket.try_clone().unwrap();
+
+        // More peers than fit on a page, one of them disabled again
+        let count = msgs::MAX_LISTED_PEERS + 2;
+        let server = serve(socket, MockBroker::default(), count + 3);
+        let peers: Vec<PeerId> = (0..count as u8).map(|n| PeerId::new([n; 32])).collect();
+        for peer_id in peers.iter() {
+            let psk = Secret::random();
+            client
+                .set_psk_blocking(config(b"wg0", peer_id, &psk))
+                .unwrap();
+        }
+        let zero = Secret::zero();
+        client
+            .set_psk_blocking(config(b"wg0", &peers[0], &zero))
+            .unwrap();
+
+        assert_eq!(client.list_installed_psks("wg0").unwrap(), peers[1..]);
+        server.join().unwrap();
+        assert_eq!(client.in_flight(), 0);
+
+        // Only an idle client can list
+        set_psk(&mut client).unwrap();
+        assert!(client.list_installed_psks("wg0").is_err());
+    }
+
+    #[test]
+    fn set_psk_blocking_requires_idle_client() {
+        let (client_socket, _socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+        set_psk(&mut client).unwrap();
+        assert!(set_psk_blocking(&mut client).is_err());
+    }
+
+    #[test]
+    fn close_handshake() {
+        const REQUESTS: usize = 10;
+
+        let (client_socket, mut socket) = UnixStream::pair().unwrap();
+        client_socket.set_nonblocking(true).unwrap();
+        let client_socket = mio::net::UnixStream::from_std(client_socket);
+        let server = std::thread::spawn(move || {
+            let broker = MockBroker::<msgs::SetPskError>::default();
+            let mut server = BrokerServer::new(broker.clone());
+            loop {
+                let mut len = [0u8; LEN_SIZE];
+                socket.read_exact(&mut len).unwrap();
+                let mut req = vec![0u8; u64::from_le_bytes(len) as usize];
+                socket.read_exact(&mut req).unwrap();
+
+                let mut res = [0u8; MAX_RESPONSE_MSG_SIZE];
+                let len = server.handle_message(&req, &mut res).unwrap();
+                // Everything before the goodbye must have been processed when acknowledging
+                let calls_at_ack = broker.calls().len();
+                socket.write_all(&(len as u64).to_le_bytes()).unwrap();
+                socket.write_all(&res[..len]).unwrap();
+                if res[0] == msgs::MsgType::Goodbye as u8 {
+                    return calls_at_ack;
+                }
+            }
+        });
+
+        let mut client = MioBrokerClient::new(client_socket);
+        for _ in 0..REQUESTS {
+            set_psk(&mut client).unwrap();
+        }
+        client.close().unwrap();
+        assert!(client.inner.is_closed());
+        assert_eq!(server.join().unwrap(), REQUESTS);
+    }
+
+    #[test]
+    fn close_wipes_buffers_and_shuts_down() {
+        let (client_socket, mut server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+
+        // Queue requests that the socket can not take and leave a partial response
+        while client.inner.io().send_buf.is_empty() {
+            set_psk(&mut client).unwrap();
+        }
+        client.inner.io_mut().recv_buf.fill(0xaa);
+
+        // Nobody acknowledges the goodbye, but the buffers are still wiped
+        assert!(client.close().is_err());
+        let io = client.inner.io();
+        assert!(io.send_buf.is_empty());
+        assert_eq!(io.recv_buf, [0u8; RECV_BUF_SIZE]);
+
+        // The socket is closed: after draining the queued requests, the peer reads EOF
+        let mut buf = [0u8; 4096];
+        while server_socket.read(&mut buf).unwrap() != 0 {}
+    }
+
+    #[test]
+    fn close_times_out_without_ack() {
+        let (client_socket, _server_socket) = mio::net::UnixStream::pair().unwrap();
+        let mut client = MioBrokerClient::new(client_socket);
+        assert!(client.close().is_err());
+        assert!(!client.inner.is_closed());
+    }
+
+    #[test]
+    fn connect_in_progress() {
+        use rustix::net::{connect_unix, socket, AddressFamily, SocketAddrUnix, SocketType};
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!(
+            "rosenpass-broker-connecting-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let socket =
+            UnixStream::from(socket(AddressFamily::UNIX, SocketType::STREAM, None).unwrap());
+        socket.set_nonblocking(true).unwrap();
+        let mut client = MioBrokerClient::new_connecting(mio::net::UnixStream::from_std(
+            socket.try_clone().unwrap(),
+        ));
+
+        // Requests are queued until the connection is established
+        set_psk(&mut client).unwrap();
+        client.process_poll().unwrap();
+        assert!(client.is_connecting());
+        assert!(!client.inner.io().send_buf.is_empty());
+
+        connect_unix(&socket, &SocketAddrUnix::new(&path).unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.process_poll().unwrap();
+        assert!(!client.is_connecting());
+        assert!(client.inner.io().send_buf.is_empty());
+
+        let mut len = [0u8; LEN_SIZE];
+        server.read_exact(&mut len).unwrap();
+        assert_eq!(u64::from_le_bytes(len) as usize, REQUEST_MSG_BUFFER_SIZE);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connect_abstract_namespace() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+
+        let name = format!("rosenpass-broker-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = UnixListener::bind_addr(&addr).unwrap();
+
+        let mut client = MioBrokerClient::connect(format!("@{name}")).unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+        set_psk(&mut client).unwrap();
+
+        let mut len = [0u8; LEN_SIZE];
+        socket.read_exact(&mut len).unwrap();
+        assert_eq!(u64::from_le_bytes(len) as usize, REQUEST_MSG_BUFFER_SIZE);
+
+        // Nothing is created in the file system
+        assert!(!Path::new(&format!("@{name}")).exists());
+        assert!(MioBrokerClient::connect("@rosenpass-broker-test-missing").is_err());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn connect_abstract_namespace_unsupported() {
+        let err = MioBrokerClient::connect("@rosenpass-broker-test").unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    // Environment variables are process-global, so all cases live in one test
+    #[test]
+    fn connect_from_env() {
+        let dir =
+            std::env::temp_dir().join(format!("rosenpass-broker-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broker.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        std::env::remove_var(BROKER_SOCKET_ENV);
+        let err = MioBrokerClient::connect_from_env().unwrap_err();
+        assert!(err.to_string().contains(BROKER_SOCKET_ENV));
+
+        std::env::set_var(BROKER_SOCKET_ENV, dir.join("missing.sock"));
+        let err = MioBrokerClient::connect_from_env().unwrap_err();
+        assert!(err.to_string().contains("missing.sock"));
+
+        std::env::set_var(BROKER_SOCKET_ENV, &path);
+        assert!(MioBrokerClient::connect_from_env().is_ok());
+
+        std::env::remove_var(BROKER_SOCKET_ENV);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
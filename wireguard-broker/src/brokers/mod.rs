@@ -4,3 +4,5 @@ pub mod mio_client;
 pub mod netlink;
 
 pub mod native_unix;
+pub mod pool;
+pub mod psk_slots;
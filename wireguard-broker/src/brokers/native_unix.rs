@@ -4,13 +4,15 @@ use std::thread;
 
 use derive_builder::Builder;
 use log::{debug, error};
-use postcard::{from_bytes, to_allocvec};
-use rosenpass_secret_memory::{Public, Secret};
+use rosenpass_secret_memory::Secret;
 use rosenpass_util::b64::b64_decode;
 use rosenpass_util::{b64::B64Display, file::StoreValueB64Writer};
 
-use crate::{SerializedBrokerConfig, WireGuardBroker, WireguardBrokerCfg, WireguardBrokerMio};
-use crate::{WG_KEY_LEN, WG_PEER_LEN};
+use crate::{
+    AdditionalParams, PeerId, SerializedBrokerConfig, WireGuardBroker, WireguardBrokerCfg,
+    WireguardBrokerMio,
+};
+use crate::{DEFAULT_PSK_SLOT, WG_KEY_LEN, WG_PEER_LEN};
 
 const MAX_B64_KEY_SIZE: usize = WG_KEY_LEN * 5 / 3;
 const MAX_B64_PEER_ID_SIZE: usize = WG_PEER_LEN * 5 / 3;
@@ -106,7 +108,7 @@ impl WireguardBrokerMio for NativeUnixBroker {
 #[builder(pattern = "mutable")]
 pub struct NativeUnixBrokerConfigBase {
     pub interface: String,
-    pub peer_id: Public<WG_PEER_LEN>,
+    pub peer_id: PeerId,
     #[builder(private)]
     pub extra_params: Vec<u8>,
 }
@@ -116,20 +118,23 @@ impl NativeUnixBrokerConfigBaseBuilder {
         &mut self,
         peer_id: &str,
     ) -> Result<&mut Self, NativeUnixBrokerConfigBaseBuilderError> {
-        let mut peer_id_b64 = Public::<WG_PEER_LEN>::zero();
-        b64_decode(peer_id.as_bytes(), &mut peer_id_b64.value).map_err(|_e| {
+        let mut peer_id_b64 = [0u8; WG_PEER_LEN];
+        b64_decode(peer_id.as_bytes(), &mut peer_id_b64).map_err(|_e| {
             NativeUnixBrokerConfigBaseBuilderError::ValidationError(
                 "Failed to parse peer id b64".to_string(),
             )
         })?;
-        Ok(self.peer_id(peer_id_b64))
+        Ok(self.peer_id(PeerId::new(peer_id_b64)))
     }
 
     pub fn extra_params_ser(
         &mut self,
-        extra_params: &Vec<String>,
+        extra_params: &[String],
     ) -> Result<&mut Self, NativeUnixBrokerConfigBuilderError> {
-        let params = to_allocvec(extra_params).map_err(|_e| {
+        let params = AdditionalParams {
+            extra_args: extra_params.to_vec(),
+        };
+        let params = params.encode().map_err(|_e| {
             NativeUnixBrokerConfigBuilderError::ValidationError(
                 "Failed to parse extra params".to_string(),
             )
@@ -145,6 +150,7 @@ impl WireguardBrokerCfg for NativeUnixBrokerConfigBase {
             peer_id: &self.peer_id,
             psk,
             additional_params: &self.extra_params,
+            slot: DEFAULT_PSK_SLOT,
         }
     }
 }
@@ -153,7 +159,7 @@ impl WireguardBrokerCfg for NativeUnixBrokerConfigBase {
 #[builder(pattern = "mutable")]
 pub struct NativeUnixBrokerConfig<'a> {
     pub interface: &'a str,
-    pub peer_id: &'a Public<WG_PEER_LEN>,
+    pub peer_id: &'a PeerId,
     pub psk: &'a Secret<WG_KEY_LEN>,
     pub extra_params: Vec<String>,
 }
@@ -165,8 +171,7 @@ impl<'a> TryFrom<SerializedBrokerConfig<'a>> for NativeUnixBrokerConfig<'a> {
         let iface = std::str::from_utf8(value.interface)
             .map_err(|_| anyhow::Error::msg("Interface UTF8 decoding error"))?;
 
-        let extra_params: Vec<String> =
-            from_bytes(value.additional_params).map_err(anyhow::Error::new)?;
+        let extra_params = AdditionalParams::decode(value.additional_params)?.extra_args;
         Ok(Self {
             interface: iface,
             peer_id: value.peer_id,
@@ -1,5 +1,6 @@
 use std::fmt::Debug;
 
+use rosenpass_secret_memory::Public;
 use wireguard_uapi::linux as wg;
 
 use crate::api::config::NetworkBrokerConfig;
@@ -85,12 +86,11 @@ impl WireGuardBroker for NetlinkWireGuardBroker {
             .sock
             .get_device(wg::DeviceInterface::from_name(config.iface))?;
 
-        if state
-            .peers
-            .iter()
-            .find(|p| &p.public_key == &config.peer_id.value)
-            .is_none()
-        {
+        // Visit every peer so the comparison time does not reveal the index of the match
+        let peer_found = state.peers.iter().fold(false, |found, p| {
+            found | config.peer_id.ct_eq(&Public::new(p.public_key))
+        });
+        if !peer_found {
             return Err(SetPskError::NoSuchPeer);
         }
 
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use crate::{SerializedBrokerConfig, WireGuardBroker, WireguardBrokerMio};
+
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum BrokerPoolError<E> {
+    #[error("No broker for interface {}", String::from_utf8_lossy(.0))]
+    NoSuchInterface(Vec<u8>),
+    #[error("No broker registered with token {0:?}")]
+    NoSuchToken(mio::Token),
+    #[error(transparent)]
+    BrokerError(E),
+}
+
+/// Multiple brokers, one per WireGuard interface
+///
+/// [WireGuardBroker::set_psk] is routed to the broker responsible for the interface named
+/// in the config. All brokers are registered with the same mio registry under consecutive
+/// tokens; [Self::process_poll] dispatches mio events to the broker owning the token.
+#[derive(Debug)]
+pub struct BrokerPool<B> {
+    brokers: Vec<B>,
+    interfaces: HashMap<Vec<u8>, usize>,
+    tokens: HashMap<mio::Token, usize>,
+}
+
+impl<B> BrokerPool<B> {
+    pub fn new() -> Self {
+        Self {
+            brokers: Vec::new(),
+            interfaces: HashMap::new(),
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Use `broker` for the given interface, returning the broker previously used for it
+    ///
+    /// Brokers added or replaced after [Self::register] are only registered by the next call to it.
+    pub fn insert(&mut self, interface: impl Into<Vec<u8>>, broker: B) -> Option<B> {
+        let interface = interface.into();
+        match self.interfaces.get(&interface) {
+            Some(&idx) => Some(std::mem::replace(&mut self.brokers[idx], broker)),
+            None => {
+                self.interfaces.insert(interface, self.brokers.len());
+                self.brokers.push(broker);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, interface: &[u8]) -> Option<&B> {
+        let idx = *self.interfaces.get(interface)?;
+        Some(&self.brokers[idx])
+    }
+
+    pub fn get_mut(&mut self, interface: &[u8]) -> Option<&mut B> {
+        let idx = *self.interfaces.get(interface)?;
+        Some(&mut self.brokers[idx])
+    }
+
+    /// The token the broker for `interface` was registered with
+    pub fn token(&self, interface: &[u8]) -> Option<mio::Token> {
+        let idx = *self.interfaces.get(interface)?;
+        self.tokens
+            .iter()
+            .find_map(|(&token, &i)| (i == idx).then_some(token))
+    }
+
+    pub fn len(&self) -> usize {
+        self.brokers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.brokers.is_empty()
+    }
+}
+
+impl<B> Default for BrokerPool<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: WireguardBrokerMio> BrokerPool<B> {
+    /// Register all brokers, using the tokens `first_token`, `first_token + 1`, and so on
+    pub fn register(
+        &mut self,
+        registry: &mio::Registry,
+        first_token: mio::Token,
+    ) -> Result<(), B::MioError> {
+        self.tokens.clear();
+        for (idx, broker) in self.brokers.iter_mut().enumerate() {
+            let token = mio::Token(first_token.0 + idx);
+            broker.register(registry, token)?;
+            self.tokens.insert(token, idx);
+        }
+        Ok(())
+    }
+
+    /// Run after a mio::poll operation yielded an event for `token`
+    pub fn process_poll(&mut self, token: mio::Token) -> Result<(), BrokerPoolError<B::MioError>> {
+        let idx = *self
+            .tokens
+            .get(&token)
+            .ok_or(BrokerPoolError::NoSuchToken(token))?;
+        self.brokers[idx]
+            .process_poll()
+            .map_err(BrokerPoolError::BrokerError)
+    }
+
+    pub fn unregister(&mut self, registry: &mio::Registry) -> Result<(), B::MioError> {
+        for (_, idx) in self.tokens.drain() {
+            self.brokers[idx].unregister(registry)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: WireGuardBroker> WireGuardBroker for BrokerPool<B> {
+    type Error = BrokerPoolError<B::Error>;
+
+    fn set_psk(&mut self, config: SerializedBrokerConfig<'_>) -> Result<(), Self::Error> {
+        let broker = self
+            .get_mut(config.interface)
+            .ok_or_else(|| BrokerPoolError::NoSuchInterface(config.interface.to_vec()))?;
+        broker.set_psk(config).map_err(BrokerPoolError::BrokerError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rosenpass_secret_memory::Secret;
+
+    use crate::test_fixtures::{config, MockBroker, PskCall};
+    use crate::{PeerId, DEFAULT_PSK_SLOT, WG_KEY_LEN, WG_PEER_LEN};
+
+    use super::*;
+
+    fn set_psk(
+        pool: &mut BrokerPool<MockBroker>,
+        interface: &[u8],
+        peer_id: &PeerId,
+        psk: &Secret<WG_KEY_LEN>,
+    ) -> Result<(), BrokerPoolError<()>> {
+        pool.set_psk(config(interface, peer_id, psk))
+    }
+
+    #[test]
+    fn set_psk_routing() {
+        let mut pool = BrokerPool::new();
+        assert!(pool.insert("wg0", MockBroker::default()).is_none());
+        assert!(pool.insert("wg1", MockBroker::default()).is_none());
+        assert_eq!(pool.len(), 2);
+
+        let (peer0, psk0) = (PeerId::new([0; WG_PEER_LEN]), Secret::random());
+        let (peer1, psk1) = (PeerId::new([1; WG_PEER_LEN]), Secret::random());
+        set_psk(&mut pool, b"wg0", &peer0, &psk0).unwrap();
+        set_psk(&mut pool, b"wg1", &peer1, &psk1).unwrap();
+
+        assert_eq!(
+            pool.get(b"wg0").unwrap().calls(),
+            [PskCall {
+                interface: b"wg0".to_vec(),
+                peer_id: peer0,
+                psk: *psk0.secret(),
+                slot: DEFAULT_PSK_SLOT,
+            }]
+        );
+        assert_eq!(
+            pool.get(b"wg1").unwrap().calls(),
+            [PskCall {
+                interface: b"wg1".to_vec(),
+                peer_id: peer1,
+                psk: *psk1.secret(),
+                slot: DEFAULT_PSK_SLOT,
+            }]
+        );
+
+        assert_eq!(
+            set_psk(&mut pool, b"wg2", &peer0, &psk0),
+            Err(BrokerPoolError::NoSuchInterface(b"wg2".to_vec()))
+        );
+    }
+
+    #[test]
+    fn poll_routing() {
+        let mut pool = BrokerPool::<MockBroker>::new();
+        pool.insert("wg0", MockBroker::default());
+        pool.insert("wg1", MockBroker::default());
+
+        let poll = mio::Poll::new().unwrap();
+        pool.register(poll.registry(), mio::Token(10)).unwrap();
+        assert_eq!(pool.token(b"wg0"), Some(mio::Token(10)));
+        assert_eq!(pool.token(b"wg1"), Some(mio::Token(11)));
+        assert_eq!(pool.get(b"wg1").unwrap().token, Some(mio::Token(11)));
+
+        pool.process_poll(mio::Token(11)).unwrap();
+        assert_eq!(pool.get(b"wg0").unwrap().polls, 0);
+        assert_eq!(pool.get(b"wg1").unwrap().polls, 1);
+        assert_eq!(
+            pool.process_poll(mio::Token(12)),
+            Err(BrokerPoolError::NoSuchToken(mio::Token(12)))
+        );
+
+        pool.unregister(poll.registry()).unwrap();
+        assert_eq!(pool.get(b"wg0").unwrap().token, None);
+        assert_eq!(pool.token(b"wg0"), None);
+    }
+
+    #[test]
+    fn insert_replaces_broker() {
+        let mut pool = BrokerPool::<MockBroker>::new();
+        pool.insert("wg0", MockBroker::default());
+        let mut replaced = MockBroker::default();
+        replaced.polls = 7;
+        assert_eq!(pool.insert("wg0", replaced).unwrap().polls, 0);
+        assert_eq!(pool.get(b"wg0").unwrap().polls, 7);
+        assert_eq!(pool.len(), 1);
+    }
+}
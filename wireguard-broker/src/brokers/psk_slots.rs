@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use rosenpass_secret_memory::Secret;
+
+use crate::{PeerId, SerializedBrokerConfig, WireGuardBroker, WG_KEY_LEN};
+
+/// A PSK installed for a slot, along with the parameters it was set with
+#[derive(Debug)]
+struct SlotEntry {
+    slot: u8,
+    psk: Secret<WG_KEY_LEN>,
+    additional_params: Vec<u8>,
+}
+
+/// Broker tracking multiple PSKs per peer, one per [slot](SerializedBrokerConfig::slot)
+///
+/// WireGuard supports only one PSK per peer, so the most recently set PSK is the one
+/// installed by the inner broker. Setting the PSK of an occupied slot replaces it.
+/// Removing the slot of the installed PSK through [Self::remove_slot] reinstalls the most
+/// recently set of the remaining PSKs; once no PSK is left, the all-zero PSK is installed,
+/// which disables the PSK in WireGuard.
+#[derive(Debug)]
+pub struct SlottedBroker<B> {
+    inner: B,
+    // Ordered by installation, the installed PSK is the last one
+    slots: HashMap<(Vec<u8>, PeerId), Vec<SlotEntry>>,
+}
+
+impl<B: WireGuardBroker> SlottedBroker<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            slots: HashMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// The occupied slots of a peer, from the least to the most recently set
+    pub fn slots(&self, interface: &[u8], peer_id: &PeerId) -> Vec<u8> {
+        self.slots
+            .get(&(interface.to_vec(), *peer_id))
+            .map(|entries| entries.iter().map(|e| e.slot).collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the PSK set for `slot`, returning whether there was one
+    ///
+    /// If the inner broker fails to install the replacement PSK, the slot is kept.
+    pub fn remove_slot(
+        &mut self,
+        interface: &[u8],
+        peer_id: &PeerId,
+        slot: u8,
+    ) -> Result<bool, B::Error> {
+        let key = (interface.to_vec(), *peer_id);
+        let Some(entries) = self.slots.get_mut(&key) else {
+            return Ok(false);
+        };
+        let Some(pos) = entries.iter().position(|e| e.slot == slot) else {
+            return Ok(false);
+        };
+
+        if pos == entries.len() - 1 {
+            // The installed PSK is removed; only forget it once the inner broker replaced it
+            let zero = Secret::zero();
+            let config = match pos.checked_sub(1).map(|prev| &entries[prev]) {
+                Some(e) => SerializedBrokerConfig {
+                    interface,
+                    peer_id,
+                    psk: &e.psk,
+                    additional_params: &e.additional_params,
+                    slot: e.slot,
+                },
+                None => SerializedBrokerConfig {
+                    interface,
+                    peer_id,
+                    psk: &zero,
+                    additional_params: &entries[pos].additional_params,
+                    slot,
+                },
+            };
+            self.inner.set_psk(config)?;
+        }
+        entries.remove(pos);
+        if entries.is_empty() {
+            self.slots.remove(&key);
+        }
+
+        Ok(true)
+    }
+}
+
+impl<B: WireGuardBroker> WireGuardBroker for SlottedBroker<B> {
+    type Error = B::Error;
+
+    fn set_psk(&mut self, config: SerializedBrokerConfig<'_>) -> Result<(), Self::Error> {
+        let key = (config.interface.to_vec(), *config.peer_id);
+        let entry = SlotEntry {
+            slot: config.slot,
+            psk: config.psk.clone(),
+            additional_params: config.additional_params.to_vec(),
+        };
+        self.inner.set_psk(config)?;
+
+        let entries = self.slots.entry(key).or_default();
+        entries.retain(|e| e.slot != entry.slot);
+        entries.push(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_fixtures::{config, MockBroker};
+    use crate::WG_PEER_LEN;
+
+    fn set_psk(broker: &mut SlottedBroker<MockBroker>, peer_id: &PeerId, slot: u8, psk: u8) {
+        let psk = Secret::from_slice(&[psk; WG_KEY_LEN]);
+        let config = SerializedBrokerConfig {
+            slot,
+            ..config(b"wg0", peer_id, &psk)
+        };
+        broker.set_psk(config).unwrap();
+    }
+
+    #[test]
+    fn psk_slots() {
+        let peer_id = PeerId::new([1; WG_PEER_LEN]);
+        let other_peer = PeerId::new([2; WG_PEER_LEN]);
+        // The slot and PSK of the last request for the peer
+        let installed = |b: &SlottedBroker<MockBroker>| {
+            let calls = b.inner().calls();
+            let call = calls.iter().rev().find(|c| c.peer_id == peer_id).unwrap();
+            (call.slot, call.psk)
+        };
+
+        let mut broker = SlottedBroker::new(MockBroker::default());
+        set_psk(&mut broker, &peer_id, 0, 0xa0);
+        set_psk(&mut broker, &peer_id, 1, 0xb1);
+        set_psk(&mut broker, &other_peer, 0, 0xc0);
+        assert_eq!(broker.slots(b"wg0", &peer_id), vec![0, 1]);
+        assert_eq!(installed(&broker), (1, [0xb1; WG_KEY_LEN]));
+
+        // Setting an occupied slot replaces its PSK
+        set_psk(&mut broker, &peer_id, 0, 0xa1);
+        assert_eq!(broker.slots(b"wg0", &peer_id), vec![1, 0]);
+        assert_eq!(installed(&broker), (0, [0xa1; WG_KEY_LEN]));
+
+        // Removing an inactive slot keeps the installed PSK
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Ok(true));
+        assert_eq!(broker.slots(b"wg0", &peer_id), vec![0]);
+        assert_eq!(installed(&broker), (0, [0xa1; WG_KEY_LEN]));
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Ok(false));
+
+        // Removing the installed PSK falls back to the remaining one
+        set_psk(&mut broker, &peer_id, 1, 0xb2);
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Ok(true));
+        assert_eq!(installed(&broker), (0, [0xa1; WG_KEY_LEN]));
+
+        // Removing the last PSK disables it
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 0), Ok(true));
+        assert!(broker.slots(b"wg0", &peer_id).is_empty());
+        assert_eq!(installed(&broker), (0, [0; WG_KEY_LEN]));
+
+        assert_eq!(broker.slots(b"wg0", &other_peer), vec![0]);
+        assert_eq!(broker.remove_slot(b"wg1", &other_peer, 0), Ok(false));
+    }
+
+    #[test]
+    fn remove_slot_keeps_slot_on_error() {
+        let peer_id = PeerId::new([1; WG_PEER_LEN]);
+        let mut broker = SlottedBroker::new(MockBroker::default());
+        set_psk(&mut broker, &peer_id, 0, 0xa0);
+        set_psk(&mut broker, &peer_id, 1, 0xb1);
+
+        // The installed PSK can not be replaced, so it is not forgotten
+        broker.inner = MockBroker::failing(());
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Err(()));
+        assert_eq!(broker.slots(b"wg0", &peer_id), vec![0, 1]);
+        // Reinstalling the PSK of slot 0 was attempted
+        assert_eq!(broker.inner().calls()[0].psk, [0xa0; WG_KEY_LEN]);
+
+        // Removing an inactive slot does not involve the inner broker
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 0), Ok(true));
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Err(()));
+        assert_eq!(broker.slots(b"wg0", &peer_id), vec![1]);
+
+        broker.inner = MockBroker::default();
+        assert_eq!(broker.remove_slot(b"wg0", &peer_id, 1), Ok(true));
+        assert!(broker.slots(b"wg0", &peer_id).is_empty());
+        assert_eq!(broker.inner().calls()[0].psk, [0; WG_KEY_LEN]);
+    }
+}
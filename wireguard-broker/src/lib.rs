@@ -1,23 +1,186 @@
+use derive_builder::Builder;
 use rosenpass_secret_memory::{Public, Secret};
-use std::{fmt::Debug, result::Result};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::{fmt, fmt::Debug, result::Result};
 
 pub const WG_KEY_LEN: usize = 32;
 pub const WG_PEER_LEN: usize = 32;
+
+/// Length of a [PeerId] in bytes
+pub const PEER_ID_LEN: usize = WG_PEER_LEN;
+
+/// The public key wrapped by a [PeerId]
+pub type PeerIdBytes = Public<PEER_ID_LEN>;
+
+/// PSK slot used by callers that install only one PSK per peer
+pub const DEFAULT_PSK_SLOT: u8 = 0;
 pub trait WireGuardBroker: Debug {
     type Error;
     fn set_psk(&mut self, config: SerializedBrokerConfig<'_>) -> Result<(), Self::Error>;
+
+    /// Set the PSK of a peer in the [DEFAULT_PSK_SLOT], taking ownership of the PSK
+    ///
+    /// The PSK is zeroized before this returns, whether setting it succeeded or not, so
+    /// the caller does not have to wipe it:
+    ///
+    /// ```compile_fail
+    /// # use rosenpass_secret_memory::Secret;
+    /// # use rosenpass_wireguard_broker::{PeerId, WireGuardBroker};
+    /// fn install(broker: &mut impl WireGuardBroker, peer_id: &PeerId) {
+    ///     let psk = Secret::random();
+    ///     let _ = broker.set_psk_owned(b"wg0", peer_id, psk);
+    ///     psk.secret(); // The PSK was moved into the broker call
+    /// }
+    /// ```
+    fn set_psk_owned(
+        &mut self,
+        interface: &[u8],
+        peer_id: &PeerId,
+        psk: Secret<WG_KEY_LEN>,
+    ) -> Result<(), Self::Error> {
+        // Secret zeroizes its memory when `psk` is dropped at the end of this function
+        self.set_psk(SerializedBrokerConfig {
+            interface,
+            peer_id,
+            psk: &psk,
+            additional_params: &[],
+            slot: DEFAULT_PSK_SLOT,
+        })
+    }
 }
 
 pub trait WireguardBrokerCfg: Debug {
     fn create_config<'a>(&'a self, psk: &'a Secret<WG_KEY_LEN>) -> SerializedBrokerConfig<'a>;
 }
 
+/// Identifies a WireGuard peer by its public key
+///
+/// Displayed and parsed as lowercase hexadecimal, like [Public].
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct PeerId(pub PeerIdBytes);
+
+impl PeerId {
+    pub fn new(value: [u8; WG_PEER_LEN]) -> Self {
+        Self(Public::new(value))
+    }
+
+    pub fn from_slice(value: &[u8]) -> Self {
+        Self(Public::from_slice(value))
+    }
+}
+
+impl From<Public<WG_PEER_LEN>> for PeerId {
+    fn from(value: Public<WG_PEER_LEN>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<PeerId> for Public<WG_PEER_LEN> {
+    fn from(value: PeerId) -> Self {
+        value.0
+    }
+}
+
+impl Deref for PeerId {
+    type Target = Public<WG_PEER_LEN>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for PeerId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0.value
+    }
+}
+
+impl Debug for PeerId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.0, fmt)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, fmt)
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        s.parse().map(Self)
+    }
+}
+
+/// Serialized as its hexadecimal [Display](fmt::Display) representation
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PeerId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug)]
 pub struct SerializedBrokerConfig<'a> {
     pub interface: &'a [u8],
-    pub peer_id: &'a Public<WG_PEER_LEN>,
+    pub peer_id: &'a PeerId,
     pub psk: &'a Secret<WG_KEY_LEN>,
     pub additional_params: &'a [u8],
+    /// Distinguishes multiple PSKs of the same peer, e.g. during key rotation
+    ///
+    /// Setting a PSK replaces the PSK previously set for the same slot. Brokers talking
+    /// to WireGuard directly install the PSK regardless of the slot, since WireGuard
+    /// supports only one PSK per peer; see [brokers::psk_slots::SlottedBroker] for tracking slots.
+    pub slot: u8,
+}
+
+/// Owned counterpart of [SerializedBrokerConfig]
+///
+/// Use [BrokerConfigBuilder] to assemble a config without juggling the lifetimes of the
+/// borrowed fields, then pass [Self::serialized] to [WireGuardBroker::set_psk].
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "mutable")]
+pub struct BrokerConfig {
+    #[builder(setter(into))]
+    pub iface: String,
+    #[builder(setter(into))]
+    pub peer_id: PeerId,
+    pub psk: Secret<WG_KEY_LEN>,
+    #[builder(default)]
+    pub additional_params: Vec<u8>,
+    #[builder(default)]
+    pub slot: u8,
+}
+
+impl BrokerConfig {
+    pub fn serialized(&self) -> SerializedBrokerConfig<'_> {
+        self.into()
+    }
+}
+
+impl<'a> From<&'a BrokerConfig> for SerializedBrokerConfig<'a> {
+    fn from(value: &'a BrokerConfig) -> Self {
+        SerializedBrokerConfig {
+            interface: value.iface.as_bytes(),
+            peer_id: &value.peer_id,
+            psk: &value.psk,
+            additional_params: &value.additional_params,
+            slot: value.slot,
+        }
+    }
 }
 
 pub trait WireguardBrokerMio: WireGuardBroker {
@@ -34,7 +197,117 @@ pub trait WireguardBrokerMio: WireGuardBroker {
     fn unregister(&mut self, registry: &mio::Registry) -> Result<(), Self::MioError>;
 }
 
+mod additional_params;
+pub use crate::additional_params::AdditionalParams;
+
 #[cfg(feature = "enable_broker_api")]
 pub mod api;
 
 pub mod brokers;
+
+#[cfg(test)]
+mod test_fixtures;
+#[cfg(test)]
+mod test_logger;
+
+#[cfg(test)]
+mod test {
+    use crate::test_fixtures::{config, MockBroker, PskCall};
+
+    use super::*;
+
+    #[test]
+    fn peer_id_conversions() {
+        let public = Public::new([0xab; WG_PEER_LEN]);
+        let peer_id = PeerId::from(public);
+        assert_eq!(peer_id, PeerId::new([0xab; WG_PEER_LEN]));
+        assert_eq!(peer_id, PeerId::from_slice(&[0xab; WG_PEER_LEN]));
+        assert_eq!(Public::from(peer_id), public);
+        assert_eq!(peer_id.value, public.value);
+    }
+
+    #[test]
+    fn peer_id_len() {
+        assert_eq!(std::mem::size_of::<PeerId>(), PEER_ID_LEN);
+        assert_eq!(std::mem::size_of::<PeerIdBytes>(), PEER_ID_LEN);
+
+        let peer_id = PeerId::new([3; PEER_ID_LEN]);
+        let psk = Secret::zero();
+        let config = config(b"wg0", &peer_id, &psk);
+        let bytes: &PeerIdBytes = &config.peer_id.0;
+        assert_eq!(bytes.value.len(), PEER_ID_LEN);
+    }
+
+    #[test]
+    fn peer_id_hex() {
+        let peer_id = PeerId::new(std::array::from_fn(|i| i as u8));
+        let hex = peer_id.to_string();
+        assert_eq!(hex.len(), 2 * WG_PEER_LEN);
+        assert!(hex.starts_with("000102"));
+        assert_eq!(hex.parse::<PeerId>().unwrap(), peer_id);
+        assert!("0001".parse::<PeerId>().is_err());
+    }
+
+    #[test]
+    fn broker_config_builder() {
+        let err = BrokerConfigBuilder::default()
+            .iface("wg0")
+            .psk(Secret::random())
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            BrokerConfigBuilderError::UninitializedField("peer_id")
+        ));
+
+        let err = BrokerConfigBuilder::default()
+            .peer_id(Public::zero())
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            BrokerConfigBuilderError::UninitializedField(_)
+        ));
+
+        let psk = Secret::random();
+        let config = BrokerConfigBuilder::default()
+            .iface("wg0")
+            .peer_id(Public::new([7; WG_PEER_LEN]))
+            .psk(psk.clone())
+            .build()
+            .unwrap();
+        let serialized = config.serialized();
+        assert_eq!(serialized.interface, b"wg0");
+        assert_eq!(serialized.peer_id, &PeerId::new([7; WG_PEER_LEN]));
+        assert_eq!(serialized.psk.secret(), psk.secret());
+        assert!(serialized.additional_params.is_empty());
+        assert_eq!(serialized.slot, DEFAULT_PSK_SLOT);
+    }
+
+    #[test]
+    fn set_psk_owned() {
+        let mut broker = MockBroker::<()>::default();
+        let peer_id = PeerId::new([2; WG_PEER_LEN]);
+        let psk = Secret::random();
+        let expected = *psk.secret();
+
+        broker.set_psk_owned(b"wg0", &peer_id, psk).unwrap();
+        assert_eq!(
+            broker.calls(),
+            [PskCall {
+                interface: b"wg0".to_vec(),
+                peer_id,
+                psk: expected,
+                slot: DEFAULT_PSK_SLOT,
+            }]
+        );
+    }
+
+    #[test]
+    fn peer_id_in_broker_config() {
+        let peer_id = PeerId::new([1; WG_PEER_LEN]);
+        let psk = Secret::zero();
+        let config = config(b"wg0", &peer_id, &psk);
+        assert_eq!(config.peer_id, &peer_id);
+    }
+}
@@ -0,0 +1,128 @@
+//! Configurations and a mock broker, shared by the tests of this crate
+
+use std::sync::{Arc, Mutex};
+
+use rosenpass_secret_memory::{Public, Secret};
+
+use crate::{
+    PeerId, SerializedBrokerConfig, WireGuardBroker, WireguardBrokerMio, DEFAULT_PSK_SLOT,
+    WG_KEY_LEN,
+};
+
+/// A random peer id and PSK
+pub fn random_psk() -> (PeerId, Secret<WG_KEY_LEN>) {
+    (PeerId::from(Public::random()), Secret::random())
+}
+
+/// Configuration setting `psk` for `peer_id` in the default slot
+pub fn config<'a>(
+    interface: &'a [u8],
+    peer_id: &'a PeerId,
+    psk: &'a Secret<WG_KEY_LEN>,
+) -> SerializedBrokerConfig<'a> {
+    SerializedBrokerConfig {
+        interface,
+        peer_id,
+        psk,
+        additional_params: &[],
+        slot: DEFAULT_PSK_SLOT,
+    }
+}
+
+/// A request received by [MockBroker]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PskCall {
+    pub interface: Vec<u8>,
+    pub peer_id: PeerId,
+    pub psk: [u8; WG_KEY_LEN],
+    pub slot: u8,
+}
+
+/// Broker recording every request it receives, including the failing ones
+///
+/// Clones share the recorded requests, so a test can keep a clone of a broker moved to
+/// another thread. When polled through [WireguardBrokerMio], the broker counts the polls and
+/// remembers its token.
+#[derive(Debug, Clone)]
+pub struct MockBroker<E = ()> {
+    calls: Arc<Mutex<Vec<PskCall>>>,
+    // Interface to fail on, or `None` to fail on all of them
+    failure: Option<(Option<Vec<u8>>, E)>,
+    pub token: Option<mio::Token>,
+    pub polls: usize,
+}
+
+impl<E> Default for MockBroker<E> {
+    fn default() -> Self {
+        Self {
+            calls: Arc::default(),
+            failure: None,
+            token: None,
+            polls: 0,
+        }
+    }
+}
+
+impl<E> MockBroker<E> {
+    /// Broker failing every request with `error`
+    pub fn failing(error: E) -> Self {
+        Self {
+            failure: Some((None, error)),
+            ..Self::default()
+        }
+    }
+
+    /// Broker failing the requests for `interface` with `error`
+    pub fn failing_on(interface: &[u8], error: E) -> Self {
+        Self {
+            failure: Some((Some(interface.to_vec()), error)),
+            ..Self::default()
+        }
+    }
+
+    /// The requests received so far, in order
+    pub fn calls(&self) -> Vec<PskCall> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl<E: Clone + std::fmt::Debug> WireGuardBroker for MockBroker<E> {
+    type Error = E;
+
+    fn set_psk(&mut self, config: SerializedBrokerConfig<'_>) -> Result<(), Self::Error> {
+        self.calls.lock().unwrap().push(PskCall {
+            interface: config.interface.to_vec(),
+            peer_id: *config.peer_id,
+            psk: *config.psk.secret(),
+            slot: config.slot,
+        });
+        match &self.failure {
+            Some((None, error)) => Err(error.clone()),
+            Some((Some(interface), error)) if interface == config.interface => Err(error.clone()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: Clone + std::fmt::Debug> WireguardBrokerMio for MockBroker<E> {
+    type MioError = ();
+
+    fn register(
+        &mut self,
+        _registry: &mio::Registry,
+        token: mio::Token,
+    ) -> Result<(), Self::MioError> {
+        self.token = Some(token);
+        Ok(())
+    }
+
+    fn process_poll(&mut self) -> Result<(), Self::MioError> {
+        self.polls += 1;
+        Ok(())
+    }
+
+    fn unregister(&mut self, _registry: &mio::Registry) -> Result<(), Self::MioError> {
+        self.token = None;
+        Ok(())
+    }
+}
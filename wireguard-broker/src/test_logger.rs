@@ -0,0 +1,32 @@
+//! Logger capturing all log messages, shared by the tests of this crate
+
+use std::sync::{Mutex, MutexGuard};
+
+static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOG.lock().unwrap().push(format!("{}", record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+
+/// Install the capturing logger; can be called by every test that inspects the log
+pub fn install() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Trace);
+}
+
+/// The messages logged so far, by all tests
+pub fn captured() -> MutexGuard<'static, Vec<String>> {
+    LOG.lock().unwrap()
+}
@@ -3,22 +3,23 @@
 mod integration_tests {
 
     use rand::Rng;
+    use rosenpass_secret_memory::testing::assert_secret_eq;
     use rosenpass_secret_memory::{Public, Secret};
     use rosenpass_wireguard_broker::api::msgs::{
-        SetPskError, REQUEST_MSG_BUFFER_SIZE, RESPONSE_MSG_BUFFER_SIZE,
+        SetPskError, MAX_RESPONSE_MSG_SIZE, REQUEST_MSG_BUFFER_SIZE,
     };
     use rosenpass_wireguard_broker::api::server::{BrokerServer, BrokerServerError};
     use rosenpass_wireguard_broker::brokers::mio_client::MioBrokerClient;
-    use rosenpass_wireguard_broker::WG_KEY_LEN;
-    use rosenpass_wireguard_broker::WG_PEER_LEN;
+    use rosenpass_wireguard_broker::PeerId;
     use rosenpass_wireguard_broker::{SerializedBrokerConfig, WireGuardBroker};
+    use rosenpass_wireguard_broker::{DEFAULT_PSK_SLOT, WG_KEY_LEN};
     use std::io::Read;
     use std::sync::{Arc, Mutex};
 
     #[derive(Default, Debug)]
     struct MockServerBrokerInner {
         psk: Option<Secret<WG_KEY_LEN>>,
-        peer_id: Option<Public<WG_PEER_LEN>>,
+        peer_id: Option<PeerId>,
         interface: Option<String>,
     }
 
@@ -43,7 +44,7 @@ mod integration_tests {
                 if let Ok(ref mut mutex) = lock {
                     **mutex = MockServerBrokerInner {
                         psk: Some(config.psk.clone()),
-                        peer_id: Some(config.peer_id.clone()),
+                        peer_id: Some(*config.peer_id),
                         interface: Some(std::str::from_utf8(config.interface).unwrap().to_string()),
                     };
                     break;
@@ -79,7 +80,7 @@ mod integration_tests {
                 let mut data_buffer = [0; REQUEST_MSG_BUFFER_SIZE];
                 while let Err(_err) = server_socket.read_exact(&mut data_buffer[0..length]) {}
 
-                let mut response = [0; RESPONSE_MSG_BUFFER_SIZE];
+                let mut response = [0; MAX_RESPONSE_MSG_SIZE];
                 server.handle_message(&data_buffer[0..length], &mut response)?;
             }
             Ok::<(), BrokerServerError>(())
@@ -91,13 +92,14 @@ mod integration_tests {
         for _ in 0..TEST_RUNS {
             //Create psk of random 32 bytes
             let psk = Secret::random();
-            let peer_id = Public::random();
+            let peer_id = PeerId::from(Public::random());
             let interface = "test";
             let config = SerializedBrokerConfig {
                 psk: &psk,
                 peer_id: &peer_id,
                 interface: interface.as_bytes(),
                 additional_params: &[],
+                slot: DEFAULT_PSK_SLOT,
             };
             client.set_psk(config).unwrap();
 
@@ -106,18 +108,13 @@ mod integration_tests {
                 rand::thread_rng().gen_range(100..500),
             ));
 
-            let psk = psk.secret().to_owned();
-
             loop {
                 let mut lock = server_broker_inner.try_lock();
 
                 if let Ok(ref mut inner) = lock {
                     // Check if the psk is received by the server
-                    let received_psk = &inner.psk;
-                    assert_eq!(
-                        received_psk.as_ref().map(|psk| psk.secret().to_owned()),
-                        Some(psk)
-                    );
+                    let received_psk = inner.psk.as_ref().expect("No PSK received");
+                    assert_secret_eq(received_psk, &psk);
 
                     let recieved_peer_id = inner.peer_id;
                     assert_eq!(recieved_peer_id, Some(peer_id));